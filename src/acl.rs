@@ -0,0 +1,163 @@
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+use anyhow::{bail, Result};
+
+// a list of clients allowed to do something, entries are single addresses, networks
+// in CIDR notation or `key:<name>` for requests signed with a TSIG key. an empty list
+// allows nobody.
+#[derive(Clone, Default, Debug, PartialEq, Eq)]
+pub struct Acl {
+    entries: Vec<AclEntry>
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AclEntry {
+    Network {
+        addr: IpAddr,
+        prefix: u8
+    },
+    Key(String)
+}
+
+impl Acl {
+    pub fn from(entries: &[String]) -> Result<Self> {
+        let entries = entries.iter().map(|entry| {
+            AclEntry::from(entry)
+        }).collect::<Result<Vec<AclEntry>>>()?;
+
+        Ok(Self {
+            entries
+        })
+    }
+
+    // whether a client is allowed, `key` is the key its request was signed with.
+    pub fn allows(&self, addr: &IpAddr, key: Option<&str>) -> bool {
+        self.entries.iter().any(|entry| entry.matches(addr, key))
+    }
+
+    // the names of the keys the list refers to
+    pub fn keys(&self) -> Vec<&str> {
+        self.entries.iter().filter_map(|entry| match entry {
+            AclEntry::Key(name) => Some(name.as_str()),
+            _ => None
+        }).collect()
+    }
+}
+
+impl AclEntry {
+    pub fn from(entry: &str) -> Result<Self> {
+        if let Some(name) = entry.trim().strip_prefix("key:") {
+            let name = name.trim().trim_end_matches('.').to_lowercase();
+            if name.is_empty() {
+                bail!("missing key name in {}", entry);
+            }
+
+            return Ok(AclEntry::Key(name));
+        }
+
+        let (addr, prefix) = match entry.trim().split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (entry.trim(), None)
+        };
+
+        let addr: IpAddr = match addr.parse() {
+            Ok(addr) => addr,
+            Err(_) => bail!("invalid address {} in access list", addr)
+        };
+
+        let max_prefix = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128
+        };
+
+        let prefix = match prefix {
+            Some(prefix) => match prefix.parse::<u8>() {
+                Ok(prefix) if prefix <= max_prefix => prefix,
+                _ => bail!("invalid prefix length in {}", entry)
+            },
+            None => max_prefix
+        };
+
+        Ok(AclEntry::Network {
+            addr,
+            prefix
+        })
+    }
+
+    pub fn matches(&self, addr: &IpAddr, key: Option<&str>) -> bool {
+        match self {
+            AclEntry::Key(name) => key.is_some_and(|key| key.eq_ignore_ascii_case(name)),
+            AclEntry::Network { addr: network, prefix } => {
+                // v4 clients of a dual stack socket show up as v4-mapped v6 addresses
+                let addr = match addr {
+                    IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(*addr),
+                    IpAddr::V4(_) => *addr
+                };
+
+                match (network, addr) {
+                    (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                        mask(u32::from(*network) as u128, 32, *prefix) == mask(u32::from(addr) as u128, 32, *prefix)
+                    },
+                    (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                        mask(u128::from(*network), 128, *prefix) == mask(u128::from(addr), 128, *prefix)
+                    },
+                    _ => false
+                }
+            }
+        }
+    }
+}
+
+impl Display for AclEntry {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AclEntry::Network { addr, prefix } => write!(f, "{}/{}", addr, prefix),
+            AclEntry::Key(name) => write!(f, "key:{}", name)
+        }
+    }
+}
+
+// keeps the first `prefix` bits of an address that is `bits` long.
+fn mask(addr: u128, bits: u8, prefix: u8) -> u128 {
+    if prefix == 0 {
+        return 0;
+    }
+
+    addr >> (bits - prefix)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn match_addresses() {
+        let acl = Acl::from(&[
+            "10.0.0.0/8".to_string(),
+            "192.168.1.5".to_string(),
+            "2001:db8::/32".to_string()
+        ]).unwrap();
+
+        assert!(acl.allows(&"10.1.2.3".parse().unwrap(), None));
+        assert!(acl.allows(&"192.168.1.5".parse().unwrap(), None));
+        assert!(acl.allows(&"::ffff:10.0.0.1".parse().unwrap(), None));
+        assert!(acl.allows(&"2001:db8:1::1".parse().unwrap(), None));
+        assert!(!acl.allows(&"192.168.1.6".parse().unwrap(), None));
+        assert!(!acl.allows(&"11.0.0.1".parse().unwrap(), None));
+        assert!(!acl.allows(&"2001:db9::1".parse().unwrap(), None));
+
+        assert!(!Acl::default().allows(&"127.0.0.1".parse().unwrap(), None));
+        assert!(Acl::from(&["0.0.0.0/0".to_string()]).unwrap().allows(&"1.2.3.4".parse().unwrap(), None));
+
+        let acl = Acl::from(&["key:xfr-key.".to_string(), "192.168.1.5".to_string()]).unwrap();
+        assert!(acl.allows(&"10.0.0.1".parse().unwrap(), Some("XFR-key")));
+        assert!(acl.allows(&"192.168.1.5".parse().unwrap(), Some("other")));
+        assert!(!acl.allows(&"10.0.0.1".parse().unwrap(), Some("other")));
+        assert!(!acl.allows(&"10.0.0.1".parse().unwrap(), None));
+        assert_eq!(acl.keys(), vec!["xfr-key"]);
+
+        assert!(Acl::from(&["10.0.0.0/33".to_string()]).is_err());
+        assert!(Acl::from(&["key:".to_string()]).is_err());
+        assert!(Acl::from(&["localhost".to_string()]).is_err());
+    }
+}
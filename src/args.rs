@@ -25,4 +25,7 @@ pub(crate) struct Args {
     pub(crate) enable_ipv6: Option<bool>,
     #[arg(long, short = 'c')]
     pub(crate) config_file: Option<String>,
+    // reads every zone file, reports all of their errors and exits
+    #[arg(long, default_value_t = false)]
+    pub(crate) check_zones: bool,
 }
\ No newline at end of file
@@ -1,71 +1,602 @@
-use std::collections::{HashMap};
-use std::ops::{Add};
-use std::sync::{RwLock, Arc};
-use tokio::time::{Duration, Instant};
-use crate::record::Record;
-use crate::record::RecordData::SOA;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tokio::time::{sleep, Duration, Instant};
+use tracing::debug;
+use crate::context::CacheContext;
+use crate::query_class::QueryClass;
+use crate::query_type::QueryType;
+use crate::question::Question;
+use crate::record::{Record, RecordData};
+use crate::result_code::ResultCode;
 
-#[derive(Default)]
+// how many CNAME records an answer from the cache may follow
+const MAX_CNAME_CHAIN: usize = 8;
+
+// the ttl of records served after they've expired (RFC 8767 4)
+const STALE_TTL: u32 = 30;
+
+// records are cached as RRsets, all of the records of a name with the same type and class.
+// each RRset expires on its own, after the smallest ttl of its records. answers saying that
+// there's nothing to find are cached as well (RFC 2308). when the cache is full the entries
+// used the longest time ago make room for new ones.
 pub struct DnsCache {
-    map: RwLock<HashMap<String, DnsCacheItem>>
+    entries: Mutex<Entries>,
+    max_entries: usize,
+    min_ttl: u32,
+    max_ttl: u32,
+    stale_window: Duration,
+    prefetch: bool
+}
+
+#[derive(Default)]
+struct Entries {
+    map: HashMap<CacheKey, DnsCacheItem>,
+    // the keys from the least to the most recently used
+    lru: BTreeMap<u64, CacheKey>,
+    tick: u64
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    pub(crate) name: String,
+    pub(crate) rtype: QueryType,
+    pub(crate) class: QueryClass
+}
+
+impl CacheKey {
+    pub fn new(name: &str, rtype: QueryType, class: QueryClass) -> Self {
+        Self {
+            // names are compared without case
+            name: name.to_ascii_lowercase(),
+            rtype,
+            class
+        }
+    }
+
+    // a name that doesn't exist has no records of any type, it's kept under the * type
+    pub fn nxdomain(name: &str, class: QueryClass) -> Self {
+        Self::new(name, QueryType::ASTERISK, class)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum CacheEntry {
+    Records(Vec<Record>),
+    // the name has no records of the type, with the SOA record of its zone
+    NoData(Record),
+    // the name doesn't exist, with the SOA record of its zone
+    NxDomain(Record)
+}
+
+impl CacheEntry {
+    fn set_ttl(&mut self, ttl: u32) {
+        match self {
+            CacheEntry::Records(records) => records.iter_mut().for_each(|record| record.ttl = ttl),
+            CacheEntry::NoData(soa) | CacheEntry::NxDomain(soa) => soa.ttl = ttl
+        }
+    }
+}
+
+// an answer put together from the cache
+#[derive(Debug)]
+pub struct CachedAnswer {
+    pub(crate) code: ResultCode,
+    pub(crate) answers: Vec<Record>,
+    pub(crate) authorities: Vec<Record>
+}
+
+// how an entry is looked for
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Freshness {
+    Fresh,
+    // an expired entry is good enough, as long as it's within the stale window
+    Stale,
+    // only an entry about to expire that nobody refreshes yet, it's marked as being refreshed
+    Prefetch
 }
 
 impl DnsCache {
-    pub fn new() -> Self {
+    pub fn new(ctx: &CacheContext) -> Self {
         Self {
-            map: RwLock::new(HashMap::new())
-        }
-    }
-    
-    pub fn get(&self, key: &str) -> Option<Vec<Record>> {
-        let mut res: Vec<Record> = Vec::new();
-        
-        match self.map.read().expect("dns cache lock poisoned").get(key) {
-            Some(val) => {
-                res = val.records.iter().filter_map(|record| -> Option<Record> {
-                    let mut ttl = Duration::from_secs(record.ttl as u64);
-                    
-                    if let SOA {expire, ..} = record.data {
-                        ttl = Duration::from_secs(expire as u64);
-                    }
-
-                    if val.timestamp.add(ttl).elapsed() == Duration::ZERO {
-                        return Some(record.clone());
-                    }
-                    
-                    None
-                }).collect();
-            },
-            None => {
+            entries: Mutex::new(Entries::default()),
+            max_entries: ctx.max_entries,
+            min_ttl: ctx.min_ttl.as_secs().min(u32::MAX as u64) as u32,
+            max_ttl: ctx.max_ttl.as_secs().min(u32::MAX as u64) as u32,
+            stale_window: ctx.stale_window,
+            prefetch: ctx.prefetch
+        }
+    }
+
+    // what's known about a name, type and class unless it has expired. the ttls are the
+    // time left until it does, rather than the ones it was cached with.
+    fn find(&self, key: &CacheKey, freshness: Freshness) -> Option<CacheEntry> {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().expect("dns cache lock poisoned");
+
+        let item = entries.map.get(key)?;
+        let (expires, ttl, refreshing) = (item.expires, item.ttl, item.refreshing);
+        if expires + self.stale_window <= now {
+            entries.remove(key);
+
+            return None;
+        }
+
+        let left = expires.saturating_duration_since(now);
+        let left_ttl = match left.is_zero() {
+            false => left.as_secs_f64().ceil() as u32,
+            true if freshness == Freshness::Stale => STALE_TTL,
+            true => return None
+        };
+
+        // the last tenth of the ttl is when an entry is refreshed
+        if freshness == Freshness::Prefetch && (refreshing || left * 10 > Duration::from_secs(ttl as u64)) {
+            return None;
+        }
+
+        let item = entries.touch(key)?;
+        if freshness == Freshness::Prefetch {
+            item.refreshing = true;
+        }
+
+        let mut entry = item.entry.clone();
+        entry.set_ttl(left_ttl);
+
+        Some(entry)
+    }
+
+    // answers a question from the cache, following the CNAME records of the name until the
+    // RRset that was asked for. the answer is only complete when the whole chain is cached.
+    pub fn lookup(&self, question: &Question) -> Option<CachedAnswer> {
+        self.answer(question, Freshness::Fresh)
+    }
+
+    // answers a question with what has expired too, when the upstreams can't be reached (RFC 8767)
+    pub fn lookup_stale(&self, question: &Question) -> Option<CachedAnswer> {
+        self.answer(question, Freshness::Stale)
+    }
+
+    // whether the answer to a question is about to expire and should be refreshed. it's only
+    // true once until the answer is cached again, so a single refresh is made.
+    pub fn prefetch(&self, question: &Question) -> bool {
+        if !self.prefetch || self.lookup(question).is_none() {
+            return false;
+        }
+
+        let key = CacheKey::new(&question.domain, question.qtype, question.qclass.clone());
+        let cname = CacheKey::new(&question.domain, QueryType::CNAME, question.qclass.clone());
+
+        self.find(&key, Freshness::Prefetch).is_some() || self.find(&cname, Freshness::Prefetch).is_some()
+    }
+
+    fn answer(&self, question: &Question, freshness: Freshness) -> Option<CachedAnswer> {
+        if question.qtype == QueryType::ASTERISK {
+            return None;
+        }
+
+        let mut answers = Vec::new();
+        let mut name = question.domain.clone();
+
+        for _ in 0..=MAX_CNAME_CHAIN {
+            if let Some(CacheEntry::NxDomain(soa)) = self.find(&CacheKey::nxdomain(&name, question.qclass.clone()), freshness) {
+                return Some(CachedAnswer::negative(ResultCode::NXDOMAIN, answers, soa));
+            }
+
+            match self.find(&CacheKey::new(&name, question.qtype, question.qclass.clone()), freshness) {
+                Some(CacheEntry::Records(mut records)) => {
+                    answers.append(&mut records);
+
+                    return Some(CachedAnswer {
+                        code: ResultCode::NOERROR,
+                        answers,
+                        authorities: vec![]
+                    });
+                },
+                Some(CacheEntry::NoData(soa)) => return Some(CachedAnswer::negative(ResultCode::NOERROR, answers, soa)),
+                _ => {}
+            }
+
+            let cname = match self.find(&CacheKey::new(&name, QueryType::CNAME, question.qclass.clone()), freshness)? {
+                CacheEntry::Records(records) => records,
+                _ => return None
+            };
+
+            name = match cname.first().map(|record| &record.data) {
+                Some(RecordData::CNAME(target)) => target.clone(),
+                _ => return None
+            };
+
+            answers.extend(cname);
+        }
+
+        None
+    }
+
+    // caches the records grouped in their RRsets, replacing the ones that were cached.
+    // records with a ttl of 0 are only good for the answer they came in. a name that has
+    // records exists, whatever was cached about it not existing is dropped.
+    pub fn insert(&self, records: Vec<Record>) {
+        let mut rrsets: HashMap<CacheKey, Vec<Record>> = HashMap::new();
+        for record in records {
+            if record.ttl == 0 || record.rtype == QueryType::OPT {
+                continue;
+            }
+
+            rrsets.entry(CacheKey::new(&record.domain, record.rtype, record.rclass.clone())).or_default().push(record);
+        }
+
+        for key in rrsets.keys() {
+            self.remove(&CacheKey::nxdomain(&key.name, key.class.clone()));
+        }
+
+        for (key, records) in rrsets {
+            let ttl = records.iter().map(|record| record.ttl).min().unwrap_or_default();
+            self.insert_entry(key, CacheEntry::Records(records), ttl);
+        }
+    }
+
+    // remembers that a name has no records of a type, for as long as the SOA record of its zone allows
+    pub fn insert_nodata(&self, name: &str, rtype: QueryType, class: QueryClass, soa: Record) {
+        self.insert_negative(CacheKey::new(name, rtype, class), soa, CacheEntry::NoData);
+    }
+
+    // remembers that a name doesn't exist, for as long as the SOA record of its zone allows
+    pub fn insert_nxdomain(&self, name: &str, class: QueryClass, soa: Record) {
+        self.insert_negative(CacheKey::nxdomain(name, class), soa, CacheEntry::NxDomain);
+    }
+
+    // a negative answer is cached for the smaller of the ttl and the minimum of the SOA record,
+    // which is sent back with that ttl (RFC 2308 3 and 5)
+    fn insert_negative(&self, key: CacheKey, soa: Record, entry: fn(Record) -> CacheEntry) {
+        let minimum = match soa.data {
+            RecordData::SOA { minimum, .. } => minimum,
+            _ => return
+        };
+
+        let ttl = soa.ttl.min(minimum);
+        if ttl == 0 {
+            return;
+        }
+
+        self.insert_entry(key, entry(soa), ttl);
+    }
+
+    // the ttl is kept between the minimum and the maximum of the cache, 0 stays 0
+    pub fn clamp_ttl(&self, ttl: u32) -> u32 {
+        match ttl {
+            0 => 0,
+            _ => ttl.clamp(self.min_ttl, self.max_ttl)
+        }
+    }
+
+    fn insert_entry(&self, key: CacheKey, mut entry: CacheEntry, ttl: u32) {
+        let ttl = self.clamp_ttl(ttl);
+        entry.set_ttl(ttl);
+
+        let mut entries = self.entries.lock().expect("dns cache lock poisoned");
+        entries.insert(key, DnsCacheItem::new(entry, ttl));
+        entries.shrink(self.max_entries);
+    }
+
+    // every entry that hasn't gone past the stale window, from the least to the most recently
+    // used. when they expire is given as a time of the clock, so it still means something
+    // after a restart.
+    pub fn saved_entries(&self) -> Vec<SavedEntry> {
+        let (now, clock) = (Instant::now(), SystemTime::now());
+        let entries = self.entries.lock().expect("dns cache lock poisoned");
+
+        entries.lru.values().filter_map(|key| {
+            let item = entries.map.get(key)?;
+            if item.expires + self.stale_window <= now {
                 return None;
             }
+
+            let expires = match item.expires.checked_duration_since(now) {
+                Some(left) => clock + left,
+                None => clock - now.duration_since(item.expires)
+            };
+
+            Some(SavedEntry {
+                key: key.clone(),
+                entry: item.entry.clone(),
+                expires,
+                ttl: item.ttl
+            })
+        }).collect()
+    }
+
+    // puts back an entry that was saved, as the most recently used one. it's left out when it's
+    // past the stale window, and it doesn't outlive the maximum ttl of the cache.
+    pub fn restore(&self, saved: SavedEntry) -> bool {
+        let (now, clock) = (Instant::now(), SystemTime::now());
+        let max_ttl = Duration::from_secs(self.max_ttl as u64);
+
+        let expires = match saved.expires.duration_since(clock) {
+            Ok(left) => now + left.min(max_ttl),
+            Err(e) if e.duration() < self.stale_window => match now.checked_sub(e.duration()) {
+                Some(expires) => expires,
+                None => return false
+            },
+            Err(_) => return false
+        };
+
+        let mut item = DnsCacheItem::new(saved.entry, saved.ttl);
+        item.expires = expires;
+
+        let mut entries = self.entries.lock().expect("dns cache lock poisoned");
+        entries.insert(saved.key, item);
+        entries.shrink(self.max_entries);
+
+        true
+    }
+
+    fn remove(&self, key: &CacheKey) {
+        self.entries.lock().expect("dns cache lock poisoned").remove(key);
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().expect("dns cache lock poisoned").map.len()
+    }
+
+    // removes what has expired and is past the stale window, returning how many entries that was
+    pub fn remove_expired(&self) -> usize {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().expect("dns cache lock poisoned");
+
+        let expired: Vec<CacheKey> = entries.map.iter()
+            .filter(|(_, item)| item.expires + self.stale_window <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        for key in &expired {
+            entries.remove(key);
         }
-        
-        if res.is_empty() {
-            self.map.write().expect("dns cache lock poisoned").remove(key);
-            
-            return None;
+
+        expired.len()
+    }
+
+    // removes expired entries every `interval`, the ones that are never asked for again
+    // would stay otherwise
+    pub async fn sweep(self: Arc<Self>, interval: Duration) {
+        loop {
+            sleep(interval).await;
+
+            let removed = self.remove_expired();
+            if removed > 0 {
+                debug!("removed {} expired entries from the cache, {} left", removed, self.len());
+            }
+        }
+    }
+}
+
+impl Entries {
+    fn insert(&mut self, key: CacheKey, mut item: DnsCacheItem) {
+        self.remove(&key);
+
+        self.tick += 1;
+        item.used = self.tick;
+        self.lru.insert(item.used, key.clone());
+        self.map.insert(key, item);
+    }
+
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some(item) = self.map.remove(key) {
+            self.lru.remove(&item.used);
+        }
+    }
+
+    // marks an entry as the most recently used one
+    fn touch(&mut self, key: &CacheKey) -> Option<&mut DnsCacheItem> {
+        self.tick += 1;
+        let tick = self.tick;
+
+        let item = self.map.get_mut(key)?;
+        self.lru.remove(&item.used);
+        self.lru.insert(tick, key.clone());
+        item.used = tick;
+
+        Some(item)
+    }
+
+    // removes the least recently used entries until there are no more than `max`
+    fn shrink(&mut self, max: usize) {
+        while self.map.len() > max {
+            match self.lru.pop_first() {
+                Some((_, key)) => self.map.remove(&key),
+                None => break
+            };
         }
-        
-        Some(res)
     }
-    
-    pub fn set(&self, key: &str, val: DnsCacheItem) {
-        self.map.write().expect("dns cache lock poisoned").insert(key.to_string(), val);
+}
+
+impl CachedAnswer {
+    fn negative(code: ResultCode, answers: Vec<Record>, soa: Record) -> Self {
+        Self {
+            code,
+            answers,
+            authorities: vec![soa]
+        }
     }
 }
 
+// an entry of the cache as it's kept in a snapshot
+#[derive(Clone, Debug)]
+pub struct SavedEntry {
+    pub key: CacheKey,
+    pub entry: CacheEntry,
+    pub expires: SystemTime,
+    pub ttl: u32
+}
+
 #[derive(Debug)]
 pub struct DnsCacheItem {
-    pub(crate) records: Arc<Vec<Record>>,
-    pub(crate) timestamp: Instant
+    pub(crate) entry: CacheEntry,
+    pub(crate) expires: Instant,
+    // the ttl it was cached with
+    pub(crate) ttl: u32,
+    // when the entry was last used, as a position in the order of uses
+    used: u64,
+    // whether it's being prefetched
+    refreshing: bool
 }
 
 impl DnsCacheItem {
-    pub fn new(records: Vec<Record>) -> Self {
+    pub fn new(entry: CacheEntry, ttl: u32) -> Self {
         Self {
-            records: Arc::new(records),
-            timestamp: Instant::now()
+            entry,
+            expires: Instant::now() + Duration::from_secs(ttl as u64),
+            ttl,
+            used: 0,
+            refreshing: false
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn record(domain: &str, rtype: QueryType, ttl: u32, data: RecordData) -> Record {
+        Record {
+            domain: domain.to_string(),
+            rtype,
+            rclass: QueryClass::IN,
+            ttl,
+            data,
+            ..Default::default()
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn rrsets() {
+        let cache = DnsCache::new(&CacheContext::default());
+        cache.insert(vec![
+            record("www.example.com", QueryType::CNAME, 300, RecordData::CNAME("web.example.net".to_string())),
+            record("web.example.net", QueryType::CNAME, 300, RecordData::CNAME("host.example.org".to_string())),
+            record("host.example.org", QueryType::A, 60, RecordData::A(Ipv4Addr::new(10, 0, 0, 1))),
+            record("host.example.org", QueryType::A, 30, RecordData::A(Ipv4Addr::new(10, 0, 0, 2))),
+            record("mail.example.com", QueryType::MX, 0, RecordData::MX { preference: 10, exchange: "mx.example.com".to_string() })
+        ]);
+
+        // the type and the class are part of the key
+        assert!(cache.lookup(&Question::new("host.example.org".to_string(), QueryType::MX)).is_none());
+        assert!(cache.lookup(&Question::new_with_class("host.example.org".to_string(), QueryType::A, QueryClass::CH)).is_none());
+        assert_eq!(cache.lookup(&Question::new("HOST.example.org".to_string(), QueryType::A)).unwrap().answers.len(), 2);
+        assert!(cache.lookup(&Question::new("mail.example.com".to_string(), QueryType::MX)).is_none());
+
+        // answers follow the cached CNAME chain
+        let answer = cache.lookup(&Question::new("www.example.com".to_string(), QueryType::A)).unwrap();
+        let names: Vec<(&str, QueryType)> = answer.answers.iter().map(|record| (record.domain.as_str(), record.rtype)).collect();
+        assert_eq!(names, vec![
+            ("www.example.com", QueryType::CNAME),
+            ("web.example.net", QueryType::CNAME),
+            ("host.example.org", QueryType::A),
+            ("host.example.org", QueryType::A)
+        ]);
+        assert_eq!(cache.lookup(&Question::new("www.example.com".to_string(), QueryType::CNAME)).unwrap().answers.len(), 1);
+        assert!(cache.lookup(&Question::new("www.example.com".to_string(), QueryType::AAAA)).is_none());
+
+        // each RRset expires with the smallest ttl of its records
+        let key = CacheKey::new("host.example.org", QueryType::A, QueryClass::IN);
+        assert!(matches!(cache.find(&key, Freshness::Fresh), Some(CacheEntry::Records(records)) if records.iter().all(|record| record.ttl == 30)));
+    }
+
+    #[test]
+    fn negative() {
+        let cache = DnsCache::new(&CacheContext::default());
+        let soa = record("example.com", QueryType::SOA, 3600, RecordData::SOA {
+            mname: "ns1.example.com".to_string(),
+            rname: "admin.example.com".to_string(),
+            serial: 1,
+            refresh: 7200,
+            retry: 3600,
+            expire: 1209600,
+            minimum: 300
+        });
+
+        cache.insert(vec![record("www.example.com", QueryType::CNAME, 300, RecordData::CNAME("gone.example.com".to_string()))]);
+        cache.insert_nxdomain("gone.example.com", QueryClass::IN, soa.clone());
+        cache.insert_nodata("mail.example.com", QueryType::AAAA, QueryClass::IN, soa.clone());
+
+        // the name doesn't exist for any type, the CNAME leading to it is part of the answer
+        let answer = cache.lookup(&Question::new("www.example.com".to_string(), QueryType::MX)).unwrap();
+        assert_eq!(answer.code, ResultCode::NXDOMAIN);
+        assert_eq!(answer.answers.len(), 1);
+        assert!(matches!(&answer.authorities[..], [soa] if soa.rtype == QueryType::SOA && soa.ttl == 300));
+
+        let answer = cache.lookup(&Question::new("mail.example.com".to_string(), QueryType::AAAA)).unwrap();
+        assert_eq!(answer.code, ResultCode::NOERROR);
+        assert!(answer.answers.is_empty() && answer.authorities.len() == 1);
+        assert!(cache.lookup(&Question::new("mail.example.com".to_string(), QueryType::A)).is_none());
+
+        // records learned later for a name that didn't exist replace the negative answer
+        cache.insert(vec![record("gone.example.com", QueryType::A, 300, RecordData::A(Ipv4Addr::new(10, 0, 0, 1)))]);
+        let answer = cache.lookup(&Question::new("www.example.com".to_string(), QueryType::A)).unwrap();
+        assert_eq!(answer.code, ResultCode::NOERROR);
+        assert_eq!(answer.answers.len(), 2);
+
+        // a SOA record with a ttl of 0 isn't cached
+        let mut uncacheable = soa;
+        uncacheable.ttl = 0;
+        cache.insert_nxdomain("other.example.com", QueryClass::IN, uncacheable);
+        assert!(cache.lookup(&Question::new("other.example.com".to_string(), QueryType::A)).is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn limits() {
+        let cache = DnsCache::new(&CacheContext {
+            max_entries: 2,
+            min_ttl: Duration::from_secs(1),
+            max_ttl: Duration::from_secs(60),
+            ..Default::default()
+        });
+
+        let a = |name: &str, ttl: u32| record(name, QueryType::A, ttl, RecordData::A(Ipv4Addr::new(10, 0, 0, 1)));
+        let ttl = |name: &str| cache.lookup(&Question::new(name.to_string(), QueryType::A)).map(|answer| answer.answers[0].ttl);
+
+        // ttls are kept between the minimum and the maximum
+        cache.insert(vec![a("one.example.com", 3600), a("two.example.com", 0)]);
+        assert_eq!(ttl("one.example.com"), Some(60));
+        assert_eq!(ttl("two.example.com"), None);
+
+        // the entry used the longest time ago makes room for a new one
+        cache.insert(vec![a("two.example.com", 1)]);
+        assert_eq!(ttl("one.example.com"), Some(60));
+        cache.insert(vec![a("three.example.com", 1)]);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(ttl("two.example.com"), None);
+        assert_eq!(ttl("one.example.com"), Some(60));
+
+        // ttls count down, and expired entries are swept even if nobody asks for them
+        tokio::time::advance(Duration::from_millis(1100)).await;
+        assert_eq!(ttl("one.example.com"), Some(59));
+        assert_eq!(cache.remove_expired(), 1);
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stale_and_prefetch() {
+        let cache = DnsCache::new(&CacheContext {
+            stale_window: Duration::from_secs(60),
+            prefetch: true,
+            ..Default::default()
+        });
+
+        let question = Question::new("www.example.com".to_string(), QueryType::A);
+        cache.insert(vec![record("www.example.com", QueryType::A, 2, RecordData::A(Ipv4Addr::new(10, 0, 0, 1)))]);
+        assert!(!cache.prefetch(&question));
+
+        // in the last tenth of its ttl an answer is refreshed once
+        tokio::time::advance(Duration::from_millis(1850)).await;
+        assert!(cache.prefetch(&question));
+        assert!(!cache.prefetch(&question));
+
+        // once expired it's only served when nothing else can be
+        tokio::time::advance(Duration::from_millis(150)).await;
+        assert!(cache.lookup(&question).is_none());
+        assert_eq!(cache.lookup_stale(&question).unwrap().answers[0].ttl, STALE_TTL);
+        assert_eq!(cache.remove_expired(), 0);
+        assert_eq!(cache.len(), 1);
+    }
+}
@@ -13,6 +13,8 @@ pub struct Config {
     pub resolver: ResolverConfig,
     #[serde(default)]
     pub server: ServerConfig,
+    #[serde(default)]
+    pub cache: CacheConfig,
     #[serde(skip_deserializing)]
     pub mode: Mode,
 }
@@ -40,6 +42,7 @@ impl Config {
                             PathBuf::from(zones)
                         }),
                         nested_zones: args.nested_zones,
+                        ..Default::default()
                     })
                 }
             };
@@ -65,6 +68,7 @@ impl Config {
                     self.server.authoritative = Some(Authoritative {
                         zones: Some(path.join("zones")),
                         nested_zones: args.nested_zones,
+                        ..Default::default()
                     });
                 },
                 None => warn!("no zones provided, if there aren't any in database either, you are not serving any zone!")
@@ -82,7 +86,8 @@ pub struct ListenerConfig {
     pub port: Option<u16>,
     pub host: Option<String>,
     pub proto: Option<String>,
-    pub max_packet_buf: Option<usize>
+    pub max_packet_buf: Option<usize>,
+    pub tcp_idle_timeout: Option<String>
 }
 
 #[derive(Default, Deserialize, Debug)]
@@ -97,7 +102,47 @@ pub struct ServerConfig {
 #[derive(Default, Clone, Deserialize, Debug)]
 pub struct Authoritative {
     pub zones: Option<PathBuf>,
-    pub nested_zones: Option<bool>
+    pub nested_zones: Option<bool>,
+    // how often zone files are checked for changes, they're only reloaded on SIGHUP without it
+    pub reload_interval: Option<String>,
+    // addresses, networks and keys allowed to transfer our zones
+    pub allow_transfer: Option<Vec<String>>,
+    // addresses, networks and keys allowed to change our zones with dynamic updates
+    pub allow_update: Option<Vec<String>>,
+    // secondaries told about changes to our zones
+    pub notify: Option<Vec<String>>,
+    // the key NOTIFY messages are signed with
+    pub notify_key: Option<String>,
+    pub key: Option<Vec<TsigKey>>,
+    pub primary: Option<Vec<Primary>>,
+    pub secondary: Option<Vec<Secondary>>
+}
+
+// a TSIG key, the secret is base64 encoded
+#[derive(Default, Clone, Deserialize, Debug)]
+pub struct TsigKey {
+    pub name: String,
+    pub algorithm: String,
+    pub secret: String
+}
+
+// one of our own zones with access lists of its own, they replace the server wide ones
+#[derive(Default, Clone, Deserialize, Debug)]
+pub struct Primary {
+    pub zone: String,
+    pub allow_transfer: Option<Vec<String>>,
+    pub allow_update: Option<Vec<String>>
+}
+
+// a zone copied from another server
+#[derive(Default, Clone, Deserialize, Debug)]
+pub struct Secondary {
+    pub zone: String,
+    pub primaries: Vec<String>,
+    // where the copy of the zone is kept, defaults to the secondary directory in the home directory
+    pub file: Option<PathBuf>,
+    // the key requests to the primaries are signed with, notifies have to be signed with it too
+    pub key: Option<String>
 }
 
 #[derive(Default, Deserialize, Debug)]
@@ -128,6 +173,20 @@ pub struct ResolverConfig {
     pub max_parse_jumps: Option<usize>
 }
 
+#[derive(Default, Deserialize, Debug)]
+pub struct CacheConfig {
+    pub max_entries: Option<usize>,
+    // the ttls of cached records are kept between these
+    pub min_ttl: Option<String>,
+    pub max_ttl: Option<String>,
+    pub sweep_interval: Option<String>,
+    pub stale_window: Option<String>,
+    pub prefetch: Option<bool>,
+    // the file the cache is saved to and loaded from at startup
+    pub snapshot: Option<PathBuf>,
+    pub snapshot_interval: Option<String>
+}
+
 #[derive(Default, Deserialize, Debug)]
 pub enum Mode {
     #[default]
@@ -2,19 +2,23 @@ use std::fmt::{Display, Formatter};
 use std::net::{IpAddr, SocketAddr};
 use std::ops::{Add};
 use std::path::PathBuf;
+use std::sync::Arc;
 use std::str::FromStr;
 use std::time::Duration;
 use crate::Args;
-use crate::cache::DnsCache;
+use crate::acl::Acl;
 use anyhow::{bail, Result};
 use crate::handler::{HandlerStrategy, HandlerTarget};
 use tokio::net::{ToSocketAddrs};
 use tracing::error;
-use crate::config::{load_config, Config, ForwardAddr, Mode};
+use crate::config::{load_config, Config, ForwardAddr, Mode, Primary, Secondary};
 use crate::duration::parse;
+use crate::fs::get_home_dir;
+use crate::parser::MAX_MESSAGE_SIZE;
+use crate::tsig::{Key, Keyring};
 
 pub struct Context {
-    pub(crate) cache: DnsCache,
+    pub(crate) cache: CacheContext,
     pub(crate) listener: ListenerContext,
     pub(crate) server: ServerContext,
     pub(crate) resolver: ResolverContext,
@@ -26,15 +30,17 @@ impl Context {
         cfg = cfg.apply_args(args);
         
         let mode = Self::get_server_mode(&cfg)?;
-        let proto = ListenerProtocol::from(cfg.listener.proto.unwrap_or_default());
+        let cache = Self::get_cache(&cfg)?;
+        let proto = ListenerProtocol::from(&cfg.listener.proto.unwrap_or_default())?;
 
         Ok(Self {
-            cache: DnsCache::new(),
+            cache,
             listener: ListenerContext {
                 host: cfg.listener.host.unwrap_or("0.0.0.0".to_string()),
                 port: cfg.listener.port.unwrap_or(53),
                 proto,
-                max_packet_buf: cfg.listener.max_packet_buf.unwrap_or(512)
+                max_packet_buf: cfg.listener.max_packet_buf.unwrap_or(4096).min(MAX_MESSAGE_SIZE),
+                tcp_idle_timeout: parse(&cfg.listener.tcp_idle_timeout.unwrap_or("10s".to_string()))?,
             },
             server: ServerContext {
                 retry_interval: parse(&cfg.server.retry_interval.unwrap_or("5s".to_string()))?,
@@ -72,13 +78,64 @@ impl Context {
         }
     }
     
+    fn get_cache(cfg: &Config) -> Result<CacheContext> {
+        let default = CacheContext::default();
+        let cache = CacheContext {
+            max_entries: cfg.cache.max_entries.unwrap_or(default.max_entries),
+            min_ttl: cfg.cache.min_ttl.as_ref().map(|ttl| parse(ttl)).transpose()?.unwrap_or(default.min_ttl),
+            max_ttl: cfg.cache.max_ttl.as_ref().map(|ttl| parse(ttl)).transpose()?.unwrap_or(default.max_ttl),
+            sweep_interval: cfg.cache.sweep_interval.as_ref().map(|interval| parse(interval)).transpose()?.unwrap_or(default.sweep_interval),
+            stale_window: cfg.cache.stale_window.as_ref().map(|window| parse(window)).transpose()?.unwrap_or(default.stale_window),
+            prefetch: cfg.cache.prefetch.unwrap_or(default.prefetch),
+            snapshot: cfg.cache.snapshot.clone(),
+            snapshot_interval: cfg.cache.snapshot_interval.as_ref().map(|interval| parse(interval)).transpose()?.unwrap_or(default.snapshot_interval)
+        };
+
+        if cache.min_ttl > cache.max_ttl {
+            bail!("the minimum ttl of the cache is greater than its maximum ttl");
+        }
+
+        if cache.sweep_interval.is_zero() {
+            bail!("the sweep interval of the cache can't be 0");
+        }
+
+        if cache.snapshot_interval.is_zero() {
+            bail!("the snapshot interval of the cache can't be 0");
+        }
+
+        Ok(cache)
+    }
+
     fn get_server_mode(cfg: &Config) -> Result<ServerMode> {
         match cfg.mode { 
             Mode::RECURSIVE => Ok(ServerMode::Recursive),
             Mode::AUTHORITATIVE => {
+                let authoritative = cfg.server.authoritative.clone().unwrap_or_default();
+
+                let keys = Keyring::from(authoritative.key.unwrap_or_default().iter().map(|key| {
+                    Key::new(&key.name, &key.algorithm, &key.secret)
+                }).collect::<Result<Vec<Key>>>()?)?;
+
+                let allow_transfer = get_acl(&authoritative.allow_transfer.unwrap_or_default(), &keys)?;
+                let allow_update = get_acl(&authoritative.allow_update.unwrap_or_default(), &keys)?;
+
                 Ok(ServerMode::Authoritative {
-                    zones: cfg.server.authoritative.clone().unwrap_or_default().zones.unwrap_or_default(),
-                    nested_zones: cfg.server.authoritative.clone().unwrap_or_default().nested_zones.unwrap_or_default(),
+                    zones: authoritative.zones.unwrap_or_default(),
+                    nested_zones: authoritative.nested_zones.unwrap_or_default(),
+                    reload_interval: authoritative.reload_interval.map(|interval| parse(&interval)).transpose()?,
+                    primaries: authoritative.primary.unwrap_or_default().iter().map(|primary| {
+                        PrimaryZone::from(primary, &allow_transfer, &allow_update, &keys)
+                    }).collect::<Result<Vec<PrimaryZone>>>()?,
+                    allow_transfer,
+                    allow_update,
+                    notify: authoritative.notify.unwrap_or_default().iter().map(|addr| {
+                        parse_addr(addr, 53)
+                    }).collect::<Result<Vec<SocketAddr>>>()?,
+                    notify_key: get_key(&authoritative.notify_key, &keys)?,
+                    secondaries: authoritative.secondary.unwrap_or_default().iter().map(|secondary| {
+                        SecondaryZone::from(secondary, &keys)
+                    }).collect::<Result<Vec<SecondaryZone>>>()?,
+                    keys,
                 })
             },
             Mode::PROXY => {
@@ -130,6 +187,14 @@ pub enum ServerMode {
     Authoritative {
         zones: PathBuf,
         nested_zones: bool,
+        reload_interval: Option<Duration>,
+        keys: Keyring,
+        allow_transfer: Acl,
+        allow_update: Acl,
+        primaries: Vec<PrimaryZone>,
+        notify: Vec<SocketAddr>,
+        notify_key: Option<Arc<Key>>,
+        secondaries: Vec<SecondaryZone>,
     },
     Proxy {
         forward: Vec<HandlerTarget>,
@@ -142,8 +207,11 @@ impl Display for ServerMode {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
             ServerMode::Recursive => write!(f, "Recursive"),
-            ServerMode::Authoritative { zones, .. } => {
-                write!(f, "Authoritative (zones in {})", zones.display())
+            ServerMode::Authoritative { zones, secondaries, .. } => {
+                match secondaries.len() {
+                    0 => write!(f, "Authoritative (zones in {})", zones.display()),
+                    n => write!(f, "Authoritative (zones in {}, {} secondary zones)", zones.display(), n)
+                }
             },
             ServerMode::Proxy { forward, .. } => {
                 write!(f, "Proxy (Forwarding to {})", join_addrs(forward, ", "))
@@ -152,11 +220,75 @@ impl Display for ServerMode {
     }
 }
 
+// one of our own zones, with the clients allowed to transfer and change it
+#[derive(Clone, Debug)]
+pub struct PrimaryZone {
+    pub origin: String,
+    pub allow_transfer: Acl,
+    pub allow_update: Acl
+}
+
+impl PrimaryZone {
+    pub fn from(cfg: &Primary, allow_transfer: &Acl, allow_update: &Acl, keys: &Keyring) -> Result<Self> {
+        let origin = cfg.zone.trim_end_matches('.').to_lowercase();
+
+        Ok(Self {
+            origin,
+            allow_transfer: match &cfg.allow_transfer {
+                Some(entries) => get_acl(entries, keys)?,
+                None => allow_transfer.clone()
+            },
+            allow_update: match &cfg.allow_update {
+                Some(entries) => get_acl(entries, keys)?,
+                None => allow_update.clone()
+            }
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SecondaryZone {
+    pub origin: String,
+    pub primaries: Vec<SocketAddr>,
+    pub file: PathBuf,
+    pub key: Option<Arc<Key>>
+}
+
+impl SecondaryZone {
+    pub fn from(cfg: &Secondary, keys: &Keyring) -> Result<Self> {
+        let origin = cfg.zone.trim_end_matches('.').to_lowercase();
+
+        if cfg.primaries.is_empty() {
+            bail!("secondary zone {} has no primaries", origin);
+        }
+
+        let primaries = cfg.primaries.iter().map(|primary| {
+            parse_addr(primary, 53)
+        }).collect::<Result<Vec<SocketAddr>>>()?;
+
+        let file = match &cfg.file {
+            Some(file) => file.clone(),
+            None => match get_home_dir() {
+                Some(home) => home.join("secondary").join(format!("{}.zone", origin)),
+                None => bail!("no file to keep secondary zone {} in", origin)
+            }
+        };
+
+        Ok(Self {
+            origin,
+            primaries,
+            file,
+            key: get_key(&cfg.key, keys)?
+        })
+    }
+}
+
 pub struct ListenerContext {
     pub port: u16,
     pub host: String,
     pub proto: ListenerProtocol,
-    pub max_packet_buf: usize
+    pub max_packet_buf: usize,
+    pub tcp_idle_timeout: Duration
 }
 
 impl ListenerContext {
@@ -165,7 +297,8 @@ impl ListenerContext {
             host: host.to_string(),
             port,
             proto,
-            max_packet_buf
+            max_packet_buf,
+            tcp_idle_timeout: Duration::from_secs(10)
         }
     }
 
@@ -180,18 +313,22 @@ impl Display for ListenerContext {
     }
 }
 
-#[derive(Default, PartialEq, Eq, Copy, Clone)]
+#[derive(Default, PartialEq, Eq, Copy, Clone, Debug)]
 pub enum ListenerProtocol {
-    #[default]
     UDP,
-    TCP
+    TCP,
+    #[default]
+    BOTH
 }
 
 impl ListenerProtocol {
-    pub fn from(proto: String) -> Self {
+    // an empty value means the default of serving both
+    pub fn from(proto: &str) -> Result<Self> {
         match proto.to_lowercase().as_str() {
-            "tcp" => Self::TCP,
-            _ => Self::UDP
+            "udp" => Ok(Self::UDP),
+            "tcp" => Ok(Self::TCP),
+            "both" | "" => Ok(Self::BOTH),
+            _ => bail!("unknown listener protocol {}, expected udp, tcp or both", proto)
         }
     }
 }
@@ -200,13 +337,46 @@ impl Display for ListenerProtocol {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
             ListenerProtocol::UDP => write!(f, "udp"),
-            ListenerProtocol::TCP => write!(f, "tcp")
+            ListenerProtocol::TCP => write!(f, "tcp"),
+            ListenerProtocol::BOTH => write!(f, "udp+tcp")
+        }
+    }
+}
+
+// how many records the cache keeps and for how long
+#[derive(Clone, Debug)]
+pub struct CacheContext {
+    pub max_entries: usize,
+    pub min_ttl: Duration,
+    pub max_ttl: Duration,
+    // how often expired records are removed
+    pub sweep_interval: Duration,
+    // how long expired records are kept to answer with when the upstreams fail, 0 to not keep them
+    pub stale_window: Duration,
+    // whether records that are asked for shortly before they expire are refreshed in the background
+    pub prefetch: bool,
+    // where the cache is kept across restarts, it's saved every `snapshot_interval` and on shutdown
+    pub snapshot: Option<PathBuf>,
+    pub snapshot_interval: Duration
+}
+
+impl Default for CacheContext {
+    fn default() -> Self {
+        Self {
+            max_entries: 100000,
+            min_ttl: Duration::ZERO,
+            max_ttl: Duration::from_secs(86400),
+            sweep_interval: Duration::from_secs(60),
+            stale_window: Duration::ZERO,
+            prefetch: false,
+            snapshot: None,
+            snapshot_interval: Duration::from_secs(300)
         }
     }
 }
 
 #[derive(Default, PartialEq, Eq, Clone)]
-pub enum CacheContext {
+pub enum CacheBackend {
     #[default]
     Internal,
     Redis {
@@ -234,6 +404,41 @@ impl Default for ResolverContext {
     }
 }
 
+// parses an address with an optional port
+fn parse_addr(addr: &str, default_port: u16) -> Result<SocketAddr> {
+    if let Ok(addr) = SocketAddr::from_str(addr) {
+        return Ok(addr);
+    }
+
+    match IpAddr::from_str(addr) {
+        Ok(ip) => Ok(SocketAddr::new(ip, default_port)),
+        Err(_) => bail!("invalid address {}", addr)
+    }
+}
+
+// builds an access list, the keys it refers to have to be defined.
+fn get_acl(entries: &[String], keys: &Keyring) -> Result<Acl> {
+    let acl = Acl::from(entries)?;
+
+    for name in acl.keys() {
+        if keys.get(name).is_none() {
+            bail!("access list refers to the undefined key {}", name);
+        }
+    }
+
+    Ok(acl)
+}
+
+fn get_key(name: &Option<String>, keys: &Keyring) -> Result<Option<Arc<Key>>> {
+    match name {
+        Some(name) => match keys.get(name) {
+            Some(key) => Ok(Some(key)),
+            None => bail!("key {} isn't defined", name)
+        },
+        None => Ok(None)
+    }
+}
+
 fn join_addrs(targets: &Vec<HandlerTarget>, sep: &str) -> String {
     let mut res = String::new();
 
@@ -5,6 +5,7 @@ use std::time::Duration;
 static MINUTE: u64 = 60;
 static HOUR: u64 = 60 * MINUTE;
 static DAY: u64 = 24 * HOUR;
+static WEEK: u64 = 7 * DAY;
 
 pub(crate) enum TimeUnit {
     NanoSecond,
@@ -14,6 +15,7 @@ pub(crate) enum TimeUnit {
     Minute,
     Hour,
     Day,
+    Week,
 }
 
 impl TimeUnit {
@@ -25,12 +27,14 @@ impl TimeUnit {
             TimeUnit::Second => Duration::from_secs(n),
             TimeUnit::Minute => Duration::from_secs(MINUTE * n),
             TimeUnit::Hour => Duration::from_secs(HOUR * n),
-            TimeUnit::Day => Duration::from_secs(DAY * n)
+            TimeUnit::Day => Duration::from_secs(DAY * n),
+            TimeUnit::Week => Duration::from_secs(WEEK * n)
         }
     }
 
     pub fn from(s: &str) -> Result<Self> {
         match s {
+            "w" | "W" => Ok(Self::Week),
             "d" | "D" => Ok(Self::Day),
             "h" | "H" => Ok(Self::Hour),
             "m" | "M" => Ok(Self::Minute),
@@ -73,30 +77,22 @@ fn split_by_nums(s: &str) -> Result<SplitUnits> {
 fn split_nums(s: &str) -> Vec<u64> {
     let mut res = Vec::new();
     
-    let mut temp: u64 = 0;
+    let mut temp: Option<u64> = None;
     for ch in s.chars() {
-        if ch.is_ascii_digit() {
-            if temp == 0 {
-                if let Some(d) = ch.to_digit(10) {
-                    temp = d as u64;
+        match ch.to_digit(10) {
+            Some(d) => {
+                temp = Some(temp.unwrap_or_default().saturating_mul(10).saturating_add(d as u64));
+            },
+            None => {
+                if let Some(num) = temp.take() {
+                    res.push(num)
                 }
-            } else {
-                if let Some(d) = ch.to_digit(10) {
-                    temp *= 10;
-                    temp += d as u64;
-                }
-            }
-        } else {
-            if temp != 0 {
-                res.push(temp)
             }
-            
-            temp = 0;
         }
     }
     
-    if temp != 0 {
-        res.push(temp);
+    if let Some(num) = temp {
+        res.push(num);
     }
     
     res
@@ -134,6 +130,7 @@ mod test {
         assert_eq!(parse("12s").ok(), Some(Duration::from_secs(12)));
         assert_eq!(parse("1m10s").ok(), Some(Duration::from_secs(70)));
         assert_eq!(parse("1h15m10s").ok(), Some(Duration::from_secs(HOUR + (15 * MINUTE) + 10)));
+        assert_eq!(parse("2w0d").ok(), Some(Duration::from_secs(2 * WEEK)));
         
         assert_eq!(parse("1G").ok(), None);
         assert_eq!(parse("1h34m23g").ok(), None);
@@ -0,0 +1,65 @@
+use crate::query_type::QueryType;
+use crate::record::{Record, RecordData};
+
+// the udp payload size advertised to clients and upstream servers, it's small enough
+// to avoid ip fragmentation on most paths (DNS flag day 2020).
+pub const EDNS_UDP_PAYLOAD_SIZE: u16 = 1232;
+
+pub const EDNS_VERSION: u8 = 0;
+
+// BADVERS is a 12 bit result code (RFC 6891 6.1.3), its upper 8 bits live in the OPT record.
+pub const BADVERS: u16 = 16;
+
+#[derive(Clone, Default, Debug, PartialEq, Eq)]
+pub struct Edns {
+    pub udp_payload_size: u16,
+    pub extended_code: u8,
+    pub version: u8,
+    pub dnssec_ok: bool,
+    pub options: Vec<EdnsOption>
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EdnsOption {
+    pub code: u16,
+    pub data: Vec<u8>
+}
+
+impl Edns {
+    pub fn new(udp_payload_size: u16, dnssec_ok: bool) -> Self {
+        Self {
+            udp_payload_size,
+            version: EDNS_VERSION,
+            dnssec_ok,
+            ..Default::default()
+        }
+    }
+
+    // the OPT record reuses the class field for the payload size and the ttl field
+    // for the extended result code, version and flags (RFC 6891 6.1.3).
+    pub fn from(udp_payload_size: u16, ttl: u32, options: Vec<EdnsOption>) -> Self {
+        Self {
+            udp_payload_size,
+            extended_code: (ttl >> 24) as u8,
+            version: ((ttl >> 16) & 0xFF) as u8,
+            dnssec_ok: (ttl & 0x8000) != 0,
+            options
+        }
+    }
+
+    pub fn ttl(&self) -> u32 {
+        (self.extended_code as u32) << 24
+            | (self.version as u32) << 16
+            | (self.dnssec_ok as u32) << 15
+    }
+
+    pub fn into_record(self) -> Record {
+        Record {
+            domain: String::new(),
+            rtype: QueryType::OPT,
+            ttl: self.ttl(),
+            data: RecordData::OPT(self),
+            ..Default::default()
+        }
+    }
+}
@@ -1,5 +1,5 @@
-use std::io::{Error, ErrorKind};
-use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream, UdpSocket};
 use std::str::FromStr;
 use std::sync::{Arc, RwLock};
 use rand::{thread_rng, Rng};
@@ -17,7 +17,10 @@ use tokio::{
 };
 use tracing::error;
 use crate::context::{Context, ServerMode};
+use crate::edns::EDNS_UDP_PAYLOAD_SIZE;
 use crate::packet::Packet;
+use crate::pair::BytesPair;
+use crate::parser::PacketParser;
 use crate::root::get_root_servers_socket_addrs;
 use crate::writer::PacketWriter;
 
@@ -68,6 +71,7 @@ impl UdpHandler {
         let failures = self.failures.clone();
         let targets = self.targets.clone();
         let retry_interval = self.ctx.server.retry_interval;
+        let max_packet_buf = self.ctx.listener.max_packet_buf;
 
          tokio::spawn(async move {
             let mut interval = tokio::time::interval(retry_interval);
@@ -90,7 +94,7 @@ impl UdpHandler {
                                         continue;
                                     }
 
-                                    let mut buf = [0u8; 512];
+                                    let mut buf = vec![0; max_packet_buf];
                                     match socket.recv(&mut buf) {
                                         Ok(_n) => {
                                             failures.write().unwrap().remove(i);  
@@ -127,7 +131,7 @@ impl UdpHandler {
 
 impl Handler for UdpHandler {
     fn send(&self, buf: &[u8]) -> Result<Vec<u8>> {
-        let mut res = [0u8; 512];
+        let mut res = vec![0; self.recv_buf_size()];
         let mut sent = false;
         let mut queue = self.targets.write().expect("");
         
@@ -146,18 +150,25 @@ impl Handler for UdpHandler {
                 bail!(e);
             }
 
-            if let Err(e) = self.socket.recv_from(&mut res) {
-                if is_timeout(&e) {
-                    if let Some(target) = queue.remove() {
-                        self.failures.write().unwrap().push(target);
-                    }
+            match self.socket.recv_from(&mut res) {
+                Ok((n, _)) => res.truncate(n),
+                Err(e) => {
+                    if is_timeout(&e) {
+                        if let Some(target) = queue.remove() {
+                            self.failures.write().unwrap().push(target);
+                        }
 
-                    error!("{} not responding, moving to the next resource", target.addr);
+                        error!("{} not responding, moving to the next resource", target.addr);
 
-                    continue;
+                        continue;
+                    }
+
+                    bail!(e);
                 }
+            }
 
-                bail!(e);
+            if is_truncated(&res) {
+                res = self.send_tcp(buf, &target.addr)?;
             }
             
             sent = true;
@@ -168,11 +179,11 @@ impl Handler for UdpHandler {
             bail!("all of the given addresses failed to serve the request")
         }
 
-        Ok(res.to_vec())
+        Ok(res)
     }
 
     fn send_to(&self, buf: &[u8], addrs: &[SocketAddr]) -> Result<Vec<u8>> {
-        let mut res = vec![0; 512];
+        let mut res = vec![0; self.recv_buf_size()];
         let mut sent = false;
         
         for addr in addrs {
@@ -188,17 +199,24 @@ impl Handler for UdpHandler {
                 bail!(e);
             }
 
-            if let Err(e) = self.socket.recv_from(&mut res) {
-                if is_timeout(&e) {
-                    continue;
-                }
+            match self.socket.recv_from(&mut res) {
+                Ok((n, _)) => res.truncate(n),
+                Err(e) => {
+                    if is_timeout(&e) {
+                        continue;
+                    }
 
-                bail!(e);
+                    bail!(e);
+                }
             }
 
             if res.is_empty() {
                 bail!("Got empty response from {}", addr.to_string());
             }
+
+            if is_truncated(&res) {
+                res = self.send_tcp(buf, addr)?;
+            }
             
             sent = true;
             break;
@@ -212,6 +230,32 @@ impl Handler for UdpHandler {
     }
 }
 
+impl UdpHandler {
+    // upstream servers may answer with as many bytes as we advertise in our OPT records.
+    fn recv_buf_size(&self) -> usize {
+        self.ctx.listener.max_packet_buf.max(EDNS_UDP_PAYLOAD_SIZE as usize)
+    }
+
+    // retries a query over tcp, used when the udp response was truncated.
+    fn send_tcp(&self, buf: &[u8], addr: &SocketAddr) -> Result<Vec<u8>> {
+        let mut stream = TcpStream::connect_timeout(addr, self.ctx.server.default_timeout)?;
+        stream.set_read_timeout(Some(self.ctx.server.default_timeout))?;
+        stream.set_write_timeout(Some(self.ctx.server.default_timeout))?;
+
+        let mut req = BytesPair::from(buf.len() as u16).bytes();
+        req.extend_from_slice(buf);
+        stream.write_all(req.as_slice())?;
+
+        let mut len = [0u8; 2];
+        stream.read_exact(&mut len)?;
+
+        let mut res = vec![0; BytesPair::new(len[0], len[1]).to_u16() as usize];
+        stream.read_exact(&mut res)?;
+
+        Ok(res)
+    }
+}
+
 impl Drop for UdpHandler {
     fn drop(&mut self) {
         self.shutdown_fn.try_send(Zero).unwrap_or_else(|err| {
@@ -388,6 +432,13 @@ impl HandlerQueue for RoundRobinQueue {
     }
 }
 
+fn is_truncated(res: &[u8]) -> bool {
+    match PacketParser::new(res).parse_header() {
+        Ok(header) => header.truncation,
+        Err(_) => false
+    }
+}
+
 fn is_timeout(err: &Error) -> bool {
     err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut
 }
@@ -1,6 +1,7 @@
 mod packet;
 mod header;
 mod result_code;
+mod opcode;
 mod question;
 mod query_type;
 mod record;
@@ -20,13 +21,33 @@ mod args;
 mod duration;
 mod config;
 mod fs;
+mod edns;
+mod acl;
+mod transfer;
+mod tcp;
+mod secondary;
+mod notify;
+mod update;
+mod tsig;
+mod reload;
+mod name;
+mod snapshot;
 
+use std::future::pending;
+use std::sync::Arc;
 use clap::{Parser};
-use tracing::{error, Level};
+use tokio::select;
+use tokio::signal::ctrl_c;
+use tokio::signal::unix::{signal, SignalKind};
+use crate::context::ServerMode;
+use crate::zone::error::ParserErrors;
+use crate::zone::parser::Zone;
+use crate::zone::tree::ZoneTree;
+use tracing::{error, info, Level};
 use tracing_subscriber::FmtSubscriber;
 use crate::args::Args;
 use crate::context::{Context, ListenerProtocol};
-use crate::server::{DnsServer, UdpDnsServer};
+use crate::server::{new_resolver, DnsServer, TcpDnsServer, UdpDnsServer, UdpTcpDnsServer};
 
 #[tokio::main]
 async fn main() {
@@ -35,9 +56,17 @@ async fn main() {
     tracing::subscriber::set_global_default(subscriber).expect("Failed to initialize logger");
 
     let args = Args::parse();
+    let check = args.check_zones;
     
     let ctx = Context::from(args).unwrap();
 
+    if check {
+        std::process::exit(match check_zones(&ctx) {
+            true => 0,
+            false => 1
+        });
+    }
+
     println!(r"
    __  _____  _____  _  ______
   /  |/  /\ \/ / _ \/ |/ / __/
@@ -45,15 +74,101 @@ async fn main() {
 /_/  /_/   /_/____/_/|_/___/
     ");
     
-    match ctx.listener.proto {
-        ListenerProtocol::UDP => {
-            let dns_server = UdpDnsServer::new(ctx);
-            if let Err(e) = dns_server.start().await {
-                error!("Failed to start dns server: {}", e.to_string())
+    let ctx = Arc::new(ctx);
+    let (resolver, snapshotter) = match new_resolver(ctx.clone()) {
+        Ok(resolver) => resolver,
+        Err(e) => {
+            error!("Failed to start dns server: {}", e.to_string());
+
+            return;
+        }
+    };
+
+    let server = async {
+        match ctx.listener.proto {
+            ListenerProtocol::UDP => {
+                UdpDnsServer::new(ctx, resolver).start().await
+            },
+            ListenerProtocol::TCP => {
+                TcpDnsServer::new(ctx, resolver).start().await
+            },
+            ListenerProtocol::BOTH => {
+                UdpTcpDnsServer::new(ctx, resolver).start().await
             }
-        },
+        }
+    };
+
+    let res = select! {
+        res = server => res,
+        _ = shutdown() => Ok(())
+    };
+
+    if let Err(e) = res {
+        error!("Failed to start dns server: {}", e.to_string())
+    }
+
+    // the cache is saved one last time so the next start picks up from here
+    if let Some(snapshotter) = snapshotter {
+        snapshotter.write();
+    }
+}
+
+// waits until the server is asked to stop with SIGTERM or SIGINT
+async fn shutdown() {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => Some(terminate),
+        Err(e) => {
+            error!("couldn't listen for SIGTERM: {}", e);
+
+            None
+        }
+    };
+
+    select! {
+        Some(_) = async { terminate.as_mut()?.recv().await } => info!("received SIGTERM, shutting down"),
+        Ok(_) = ctrl_c() => info!("received SIGINT, shutting down"),
+        else => pending().await
+    }
+}
+
+// reads the zones directory and prints every error found in it, so it can be linted before
+// being deployed. returns whether the zones can be loaded.
+fn check_zones(ctx: &Context) -> bool {
+    let (dir, nested) = match &ctx.server.mode {
+        ServerMode::Authoritative { zones, nested_zones, .. } => (zones, *nested_zones),
         _ => {
-            error!("{} is not supported yet", ctx.listener.proto)
+            eprintln!("zones can only be checked in authoritative mode");
+
+            return false;
+        }
+    };
+
+    let zones = match Zone::parse_directory(dir, nested) {
+        Ok(zones) => zones,
+        Err(e) => {
+            match e.downcast_ref::<ParserErrors>() {
+                Some(errors) => {
+                    for error in &errors.0 {
+                        eprintln!("{}", error);
+                    }
+
+                    eprintln!("{} errors in {}", errors.0.len(), dir.display());
+                },
+                None => eprintln!("{}: {}", dir.display(), e)
+            }
+
+            return false;
         }
+    };
+
+    let count = zones.len();
+    if let Err(e) = ZoneTree::from(zones) {
+        eprintln!("{}", e);
+
+        return false;
     }
+
+    println!("{} zones in {} are valid", count, dir.display());
+
+    true
 }
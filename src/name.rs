@@ -0,0 +1,114 @@
+use anyhow::{bail, Result};
+
+// domain names are kept in their presentation format (RFC 1035 5.1): labels are separated by
+// dots, and a dot, a backslash or any other special character inside a label is escaped.
+
+// splits a name into its labels without their escapes, the root domain ("" or ".") has no labels.
+pub fn labels(name: &str) -> Result<Vec<Vec<u8>>> {
+    let mut res = Vec::new();
+    let mut label = Vec::new();
+    let mut bytes = name.bytes();
+
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'.' => {
+                if label.is_empty() {
+                    if name == "." {
+                        break;
+                    }
+
+                    bail!("empty label in {}", name);
+                }
+
+                res.push(std::mem::take(&mut label));
+            },
+            b'\\' => {
+                match bytes.next() {
+                    Some(digit) if digit.is_ascii_digit() => {
+                        let mut value = (digit - b'0') as u16;
+                        for _ in 0..2 {
+                            match bytes.next() {
+                                Some(digit) if digit.is_ascii_digit() => value = value * 10 + (digit - b'0') as u16,
+                                _ => bail!("invalid escape sequence in {}", name)
+                            }
+                        }
+
+                        if value > 255 {
+                            bail!("invalid escape sequence in {}", name);
+                        }
+
+                        label.push(value as u8);
+                    },
+                    Some(byte) => label.push(byte),
+                    None => bail!("invalid escape sequence in {}", name)
+                }
+            },
+            _ => label.push(byte)
+        }
+    }
+
+    if !label.is_empty() {
+        res.push(label);
+    }
+
+    let mut len = 1;
+    for label in &res {
+        if label.len() > 63 {
+            bail!("label of {} exceeds 63 character limit", name);
+        }
+
+        len += label.len() + 1;
+    }
+
+    if len > 255 {
+        bail!("{} exceeds 255 character limit", name);
+    }
+
+    Ok(res)
+}
+
+// writes a label in presentation format, escaping what can't appear in it as it is
+pub fn escape_label(label: &[u8]) -> String {
+    let mut res = String::new();
+
+    for byte in label {
+        match byte {
+            b'.' | b'\\' | b'"' | b'(' | b')' | b';' | b'@' | b'$' => {
+                res.push('\\');
+                res.push(*byte as char);
+            },
+            0x21..=0x7e => res.push(*byte as char),
+            _ => res.push_str(&format!("\\{:03}", byte))
+        }
+    }
+
+    res
+}
+
+// whether a name ends with a dot that isn't escaped
+pub fn is_absolute(name: &str) -> bool {
+    let escapes = name.bytes().rev().skip(1).take_while(|byte| *byte == b'\\').count();
+
+    name.ends_with('.') && escapes % 2 == 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn escapes() {
+        assert_eq!(labels("www.example.com.").unwrap(), vec![b"www".to_vec(), b"example".to_vec(), b"com".to_vec()]);
+        assert_eq!(labels("a\\.b.c").unwrap(), vec![b"a.b".to_vec(), b"c".to_vec()]);
+        assert_eq!(labels("\\065\\\\b").unwrap(), vec![b"A\\b".to_vec()]);
+        assert!(labels(".").unwrap().is_empty());
+        assert!(labels("a..b").is_err());
+        assert!(labels("a\\256").is_err());
+        assert!(labels(&"a".repeat(64)).is_err());
+
+        assert_eq!(escape_label(b"a.b\\c d\x01"), "a\\.b\\\\c\\032d\\001");
+        assert!(is_absolute("example.com."));
+        assert!(!is_absolute("example\\."));
+        assert!(is_absolute("example\\\\."));
+    }
+}
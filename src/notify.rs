@@ -0,0 +1,195 @@
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use anyhow::{bail, Result};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::time::{sleep, timeout, Duration};
+use tracing::{info, warn};
+use crate::opcode::Opcode;
+use crate::packet::Packet;
+use crate::parser::PacketParser;
+use crate::query_type::QueryType;
+use crate::question::Question;
+use crate::record::Record;
+use crate::result_code::ResultCode;
+use crate::tsig::{Key, Transaction};
+use crate::writer::PacketWriter;
+use crate::zone::tree::SharedTree;
+
+// how many times a NOTIFY is sent to a secondary that doesn't answer (RFC 1996 3.6)
+const NOTIFY_ATTEMPTS: usize = 5;
+
+// the zones are announced once the server has started listening, secondaries
+// would come asking for them right away.
+const STARTUP_DELAY: Duration = Duration::from_secs(5);
+
+// tells the secondaries of our zones that a zone has changed, so they don't have to
+// wait for their refresh timer to pick the change up (RFC 1996).
+pub struct Notifier {
+    targets: Vec<SocketAddr>,
+    // the key the notifies are signed with
+    key: Option<Arc<Key>>,
+    tree: Arc<SharedTree>,
+    timeout: Duration
+}
+
+impl Notifier {
+    pub fn new(targets: Vec<SocketAddr>, key: Option<Arc<Key>>, tree: Arc<SharedTree>, timeout: Duration) -> Self {
+        Self {
+            targets,
+            key,
+            tree,
+            timeout
+        }
+    }
+
+    pub async fn run(self, mut changes: UnboundedReceiver<String>) {
+        // the zones may have changed while we were down
+        sleep(STARTUP_DELAY).await;
+        for origin in self.tree.load().origins() {
+            self.notify(&origin);
+        }
+
+        while let Some(origin) = changes.recv().await {
+            self.notify(&origin);
+        }
+    }
+
+    fn notify(&self, origin: &str) {
+        let soa = match self.tree.load().get(origin) {
+            Some(zone) => zone.soa().clone(),
+            None => return
+        };
+
+        for target in self.targets.clone() {
+            let soa = soa.clone();
+            let key = self.key.clone();
+            let limit = self.timeout;
+
+            tokio::spawn(async move {
+                match send_notify(target, &soa, key, limit).await {
+                    Ok(_) => info!("notified {} of changes to zone {}", target, soa.domain),
+                    Err(e) => warn!("couldn't notify {} of changes to zone {}: {}", target, soa.domain, e)
+                }
+            });
+        }
+    }
+}
+
+// sends a NOTIFY for a zone until the secondary acknowledges it. the message carries
+// the new SOA record of the zone as a hint.
+pub async fn send_notify(target: SocketAddr, soa: &Record, key: Option<Arc<Key>>, limit: Duration) -> Result<()> {
+    let socket = match target {
+        SocketAddr::V4(_) => UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?,
+        SocketAddr::V6(_) => UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await?
+    };
+
+    let id = rand::random();
+
+    let mut req = Packet::new();
+    req.header.id = id;
+    req.header.opcode = Opcode::NOTIFY.to_u8();
+    req.header.authoritative = true;
+    req.questions.push(Question::new(soa.domain.clone(), QueryType::SOA));
+    req.answers.push(soa.clone());
+
+    let mut transaction = key.map(Transaction::new);
+    let buf = match &mut transaction {
+        Some(transaction) => PacketWriter::from(req).sign(transaction)?,
+        None => PacketWriter::from(req).write()?
+    };
+
+    let mut res_buf = vec![0; 512];
+    for _ in 0..NOTIFY_ATTEMPTS {
+        socket.send_to(&buf, target).await?;
+
+        let n = match timeout(limit, socket.recv(&mut res_buf)).await {
+            Ok(n) => n?,
+            Err(_) => continue
+        };
+
+        let mut parser = PacketParser::new(&res_buf[..n]);
+        let res = match parser.parse() {
+            Ok(res) => res,
+            Err(_) => continue
+        };
+
+        if res.header.id != id || !res.header.response || Opcode::from(res.header.opcode) != Opcode::NOTIFY {
+            continue;
+        }
+
+        if let Some(transaction) = &mut transaction {
+            transaction.verify(&parser, &res)?;
+        }
+
+        if res.header.code != ResultCode::NOERROR.to_u8() {
+            bail!("the notify was answered with {:?}", ResultCode::from(res.header.code));
+        }
+
+        return Ok(());
+    }
+
+    bail!("no answer after {} attempts", NOTIFY_ATTEMPTS)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::record::RecordData;
+
+    fn soa() -> Record {
+        Record {
+            domain: "example.com".to_string(),
+            rtype: QueryType::SOA,
+            ttl: 3600,
+            data: RecordData::SOA {
+                mname: "ns1.example.com".to_string(),
+                rname: "admin.example.com".to_string(),
+                serial: 2,
+                refresh: 3600,
+                retry: 600,
+                expire: 86400,
+                minimum: 300
+            },
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn retries() {
+        let secondary = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target = secondary.local_addr().unwrap();
+
+        // the secondary drops the first notify and acknowledges the second one
+        let acknowledged = tokio::spawn(async move {
+            let mut buf = vec![0; 512];
+            let mut notifies = Vec::new();
+            for _ in 0..2 {
+                let (n, source) = secondary.recv_from(&mut buf).await.unwrap();
+                let req = PacketParser::new(&buf[..n]).parse().unwrap();
+
+                if !notifies.is_empty() {
+                    let mut res = Packet::from(&req);
+                    res.header.response = true;
+                    secondary.send_to(&PacketWriter::from(res).write().unwrap(), source).await.unwrap();
+                }
+
+                notifies.push(req);
+            }
+
+            notifies
+        });
+
+        send_notify(target, &soa(), None, Duration::from_millis(200)).await.unwrap();
+
+        // the retry is the same message, so the answer to it matches the id the notifier waits for
+        let notifies = acknowledged.await.unwrap();
+        assert_eq!(notifies[0].header.id, notifies[1].header.id);
+        for req in notifies {
+            assert_eq!(Opcode::from(req.header.opcode), Opcode::NOTIFY);
+            assert!(req.header.authoritative && !req.header.response);
+            assert_eq!(req.questions[0].domain, "example.com");
+            assert!(matches!(req.answers[0].data, RecordData::SOA { serial: 2, .. }));
+        }
+    }
+}
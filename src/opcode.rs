@@ -0,0 +1,34 @@
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Opcode {
+    QUERY, // 0
+    IQUERY,
+    STATUS,
+    NOTIFY, // 4
+    UPDATE,
+    // any opcode we don't have a name for, keeps the raw value
+    UNKNOWN(u8),
+}
+
+impl Opcode {
+    pub fn from(value: u8) -> Opcode {
+        match value {
+            0 => Opcode::QUERY,
+            1 => Opcode::IQUERY,
+            2 => Opcode::STATUS,
+            4 => Opcode::NOTIFY,
+            5 => Opcode::UPDATE,
+            _ => Opcode::UNKNOWN(value),
+        }
+    }
+
+    pub fn to_u8(&self) -> u8 {
+        match *self {
+            Opcode::QUERY => 0,
+            Opcode::IQUERY => 1,
+            Opcode::STATUS => 2,
+            Opcode::NOTIFY => 4,
+            Opcode::UPDATE => 5,
+            Opcode::UNKNOWN(value) => value,
+        }
+    }
+}
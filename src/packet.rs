@@ -1,8 +1,9 @@
 use rand::random;
+use crate::edns::Edns;
 use crate::query_type::QueryType;
 use crate::header::Header;
 use crate::question::Question;
-use crate::record::Record;
+use crate::record::{Record, RecordData};
 
 #[derive(Debug, Default)]
 pub struct Packet {
@@ -40,4 +41,18 @@ impl Packet {
             ..Default::default()
         }
     }
-}
\ No newline at end of file
+
+    // the TSIG record signing the message, it's always the last record (RFC 8945 4.1).
+    pub fn tsig(&self) -> Option<&Record> {
+        self.resources.last().filter(|record| record.rtype == QueryType::TSIG)
+    }
+
+    pub fn edns(&self) -> Option<&Edns> {
+        self.resources.iter().find_map(|record| {
+            match &record.data {
+                RecordData::OPT(edns) => Some(edns),
+                _ => None
+            }
+        })
+    }
+}
@@ -1,22 +1,29 @@
 use anyhow::{bail, Result};
 use crate::query_class::QueryClass;
 use crate::header::Header;
+use crate::name::escape_label;
 use crate::packet::Packet;
 use crate::pair::BytesPair;
 use crate::query_type::QueryType;
 use crate::question::Question;
-use crate::record::Record;
+use crate::record::{Record, RecordData};
+
+// the largest message that can be carried over tcp, it's also an upper bound for edns0 udp payloads.
+pub const MAX_MESSAGE_SIZE: usize = u16::MAX as usize;
 
 pub struct PacketParser {
     buf: Vec<u8>,
-    offset: u16
+    offset: usize,
+    // where the TSIG record starts and the id the message had when it was signed
+    signed: Option<(usize, u16)>
 }
 
 impl PacketParser {
     pub fn new(data: &[u8]) -> PacketParser {
         PacketParser {
-            buf: data.to_vec(),
+            buf: data[..data.len().min(MAX_MESSAGE_SIZE)].to_vec(),
             offset: 0,
+            signed: None
         }
     }
 
@@ -24,22 +31,22 @@ impl PacketParser {
         self.buf.as_slice()
     }
 
-    pub fn offset(&self) -> u16 {
+    pub fn offset(&self) -> usize {
         self.offset
     }
 
-    pub fn seek(&mut self, n: u16) -> Result<()> {
+    pub fn seek(&mut self, n: usize) -> Result<()> {
+        if n > self.buf.len() {
+            bail!("End Of Buffer");
+        }
+
         self.offset = n;
 
         Ok(())
     }
 
     pub fn next(&mut self) -> Result<u8> {
-        if self.offset >= 512 {
-            bail!("End Of Buffer");
-        }
-
-        let res = self.buf[self.offset as usize];
+        let res = self.get(self.offset)?;
         self.offset += 1;
 
         Ok(res)
@@ -60,24 +67,25 @@ impl PacketParser {
         Ok(res)
     }
 
-    pub fn get(&self, n: u16) -> Result<u8> {
-        if n >= 512 {
-            bail!("End Of Buffer");
-        }
-
-        let res = self.buf[n as usize];
+    pub fn next_bytes(&mut self, len: usize) -> Result<Vec<u8>> {
+        let res = self.range(self.offset, len)?.to_vec();
+        self.offset += len;
 
         Ok(res)
     }
 
-    pub fn range(&self, start: u16, len: u16) -> Result<&[u8]> {
-        if start + len >= 512 {
-            bail!("End Of Buffer");
+    pub fn get(&self, n: usize) -> Result<u8> {
+        match self.buf.get(n) {
+            Some(res) => Ok(*res),
+            None => bail!("End Of Buffer")
         }
+    }
 
-        let res = &self.buf[start as usize..(start + len) as usize];
-
-        Ok(&res)
+    pub fn range(&self, start: usize, len: usize) -> Result<&[u8]> {
+        match self.buf.get(start..start + len) {
+            Some(res) => Ok(res),
+            None => bail!("End Of Buffer")
+        }
     }
 
     pub fn parse(&mut self) -> Result<Packet> {
@@ -97,13 +105,38 @@ impl PacketParser {
             packet.authorities.push(Record::parse(self)?);
         }
 
-        for _ in 0..packet.header.resource_count {
-            packet.resources.push(Record::parse(self)?);
+        for i in 0..packet.header.resource_count {
+            let start = self.offset;
+            let record = Record::parse(self)?;
+
+            if let RecordData::TSIG(tsig) = &record.data {
+                if i + 1 != packet.header.resource_count {
+                    bail!("the TSIG record isn't the last record of the message");
+                }
+
+                self.signed = Some((start, tsig.original_id));
+            }
+
+            packet.resources.push(record);
         }
 
         Ok(packet)
     }
 
+    // the message as it was before it was signed, without its TSIG record and with its
+    // original id (RFC 8945 4.3.1).
+    pub fn unsigned_message(&self) -> Option<Vec<u8>> {
+        let (start, id) = self.signed?;
+
+        let mut res = self.buf[..start].to_vec();
+        res[0..2].copy_from_slice(&id.to_be_bytes());
+
+        let count = u16::from_be_bytes([res[10], res[11]]).checked_sub(1)?;
+        res[10..12].copy_from_slice(&count.to_be_bytes());
+
+        Some(res)
+    }
+
     pub fn parse_header(&mut self) -> Result<Header> {
         let mut header = Header::new();
 
@@ -125,13 +158,13 @@ impl PacketParser {
     pub fn parse_header_flags(&mut self, header: &mut Header) -> Result<()> {
         let pair = BytesPair::from(self.next_u16()?);
 
-        header.response = (pair.0 & (1 << 7)) == 1;
+        header.response = (pair.0 & (1 << 7)) != 0;
         header.opcode = (pair.0 >> 3) & 0x0F;
-        header.authoritative = (pair.0 & (1 << 2)) == 1;
-        header.truncation = (pair.0 & (1 << 1)) == 1;
-        header.recursion_desired = (pair.0 & (1 << 0)) == 1;
+        header.authoritative = (pair.0 & (1 << 2)) != 0;
+        header.truncation = (pair.0 & (1 << 1)) != 0;
+        header.recursion_desired = (pair.0 & (1 << 0)) != 0;
 
-        header.recursion_available = (pair.1 & (1 << 7)) == 1;
+        header.recursion_available = (pair.1 & (1 << 7)) != 0;
         header.reserved = (pair.1 >> 4) & 0x07;
         header.code = pair.1 & 0x0F;
 
@@ -146,10 +179,19 @@ impl PacketParser {
         Ok(Question::new_with_class(name, QueryType::from(qtype), QueryClass::from(qclass)))
     }
 
+    // a <character-string> is a length octet followed by that many bytes (RFC 1035 3.3).
+    pub fn parse_character_string(&mut self) -> Result<Vec<u8>> {
+        let len = self.next()?;
+
+        self.next_bytes(len as usize)
+    }
+
     pub fn parse_domain_name(&mut self) -> Result<String> {
         let mut res = String::new();
 
         let mut pos = self.offset();
+        // wire length of the name, including the length octets and the root label
+        let mut len_on_wire = 1;
 
         let mut total_jumps = 0;
         let max_jumps = 5;
@@ -166,13 +208,20 @@ impl PacketParser {
                     self.seek(pos + 2)?;
                 }
 
-                let next_byte = self.get(pos + 1)? as u16;
-                let offset = (((len as u16) ^ 0xC0) << 8) | next_byte;
+                let next_byte = self.get(pos + 1)? as usize;
+                let offset = (((len as usize) ^ 0xC0) << 8) | next_byte;
+
+                // a pointer can only refer to a name that has been seen before
+                if offset >= pos {
+                    bail!("Invalid compression pointer at offset {}", pos);
+                }
                 pos = offset;
 
                 total_jumps += 1;
 
                 continue;
+            } else if (len & 0xC0) != 0 {
+                bail!("Unsupported label type at offset {}", pos);
             } else {
                 pos += 1;
 
@@ -180,14 +229,19 @@ impl PacketParser {
                     break;
                 }
 
+                len_on_wire += len as usize + 1;
+                if len_on_wire > 255 {
+                    bail!("Domain name exceeds 255 octets");
+                }
+
                 if !res.is_empty() {
                     res.push('.');
                 }
 
-                let bytes = self.range(pos, len as u16)?;
-                res.push_str(&String::from_utf8_lossy(bytes).to_lowercase());
+                let bytes = self.range(pos, len as usize)?;
+                res.push_str(&escape_label(&bytes.to_ascii_lowercase()));
 
-                pos += len as u16;
+                pos += len as usize;
             }
         }
 
@@ -197,4 +251,4 @@ impl PacketParser {
 
         Ok(res)
     }
-}
\ No newline at end of file
+}
@@ -1,11 +1,15 @@
-#[derive(Clone, Default, Debug)]
+#[derive(Clone, Default, Debug, PartialEq, Eq, Hash)]
 pub enum QueryClass {
     #[default]
     IN, // 1
     CS,
     CH,
     HS,
-    ASTERISK
+    // only used in updates (RFC 2136 2.4)
+    NONE,
+    ASTERISK,
+    // any class we don't have a name for, keeps the raw value (RFC 3597)
+    UNKNOWN(u16)
 }
 
 impl QueryClass {
@@ -15,7 +19,9 @@ impl QueryClass {
             2 => QueryClass::CS,
             3 => QueryClass::CH,
             4 => QueryClass::HS,
-            _ => QueryClass::ASTERISK
+            254 => QueryClass::NONE,
+            255 => QueryClass::ASTERISK,
+            _ => QueryClass::UNKNOWN(value)
         }
     }
 
@@ -25,7 +31,26 @@ impl QueryClass {
             QueryClass::CS => 2,
             QueryClass::CH => 3,
             QueryClass::HS => 4,
-            QueryClass::ASTERISK => 255
+            QueryClass::NONE => 254,
+            QueryClass::ASTERISK => 255,
+            QueryClass::UNKNOWN(value) => value
+        }
+    }
+
+    // the mnemonic used in zone files, classes without one use the CLASSnnn form (RFC 3597 5).
+    pub fn from_name(name: &str) -> Option<QueryClass> {
+        let name = name.to_uppercase();
+
+        match name.as_str() {
+            "IN" => Some(QueryClass::IN),
+            "CS" => Some(QueryClass::CS),
+            "CH" => Some(QueryClass::CH),
+            "HS" => Some(QueryClass::HS),
+            _ => {
+                let value = name.strip_prefix("CLASS")?.parse::<u16>().ok()?;
+
+                Some(QueryClass::from(value))
+            }
         }
     }
 }
\ No newline at end of file
@@ -12,11 +12,15 @@ pub enum QueryType {
     AAAA,
     SRV, // 33
     OPT, // 41
+    TSIG, // 250
     // QTYPE
+    IXFR, // 251
     AXFR, // 252
     MAILB,
     MAILA,
     ASTERISK,
+    // any type we don't have a name for, keeps the raw value (RFC 3597)
+    UNKNOWN(u16),
 }
 
 impl QueryType {
@@ -33,10 +37,13 @@ impl QueryType {
             28 => QueryType::AAAA,
             33 => QueryType::SRV,
             41 => QueryType::OPT,
+            250 => QueryType::TSIG,
+            251 => QueryType::IXFR,
             252 => QueryType::AXFR,
             253 => QueryType::MAILB,
             254 => QueryType::MAILA,
-            _ => QueryType::ASTERISK,
+            255 => QueryType::ASTERISK,
+            _ => QueryType::UNKNOWN(value),
         }
     }
 
@@ -53,10 +60,54 @@ impl QueryType {
             QueryType::AAAA => 28,
             QueryType::SRV => 33,
             QueryType::OPT => 41,
+            QueryType::TSIG => 250,
+            QueryType::IXFR => 251,
             QueryType::AXFR => 252,
             QueryType::MAILB => 253,
             QueryType::MAILA => 254,
             QueryType::ASTERISK => 255,
+            QueryType::UNKNOWN(value) => value,
         }
     }
-}
\ No newline at end of file
+
+    // parses a type mnemonic as it appears in zone files, including the
+    // TYPEnnn generic form of RFC 3597.
+    pub fn from_name(name: &str) -> Option<QueryType> {
+        let name = name.to_uppercase();
+
+        match name.as_str() {
+            "A" => Some(QueryType::A),
+            "NS" => Some(QueryType::NS),
+            "CNAME" => Some(QueryType::CNAME),
+            "SOA" => Some(QueryType::SOA),
+            "PTR" => Some(QueryType::PTR),
+            "HINFO" => Some(QueryType::HINFO),
+            "MX" => Some(QueryType::MX),
+            "TXT" => Some(QueryType::TXT),
+            "AAAA" => Some(QueryType::AAAA),
+            "SRV" => Some(QueryType::SRV),
+            _ => {
+                let value = name.strip_prefix("TYPE")?.parse::<u16>().ok()?;
+
+                Some(QueryType::from(value))
+            }
+        }
+    }
+
+    // the mnemonic written to zone files, types without one use the TYPEnnn form.
+    pub fn to_name(&self) -> String {
+        match self {
+            QueryType::A => "A".to_string(),
+            QueryType::NS => "NS".to_string(),
+            QueryType::CNAME => "CNAME".to_string(),
+            QueryType::SOA => "SOA".to_string(),
+            QueryType::PTR => "PTR".to_string(),
+            QueryType::HINFO => "HINFO".to_string(),
+            QueryType::MX => "MX".to_string(),
+            QueryType::TXT => "TXT".to_string(),
+            QueryType::AAAA => "AAAA".to_string(),
+            QueryType::SRV => "SRV".to_string(),
+            _ => format!("TYPE{}", self.to_num())
+        }
+    }
+}
@@ -1,8 +1,12 @@
-use anyhow::Result;
+use anyhow::{bail, Result};
 use std::net::{Ipv4Addr, Ipv6Addr};
+use crate::bytes_util::BytesUtil;
+use crate::edns::{Edns, EdnsOption};
+use crate::pair::BytesPair;
 use crate::query_class::QueryClass;
 use crate::parser::PacketParser;
 use crate::query_type::QueryType;
+use crate::tsig::Tsig;
 
 #[derive(Default, Debug, Clone)]
 pub struct Record {
@@ -19,7 +23,8 @@ impl Record {
         let domain = parser.parse_domain_name()?;
 
         let rtype = QueryType::from(parser.next_u16()?);
-        let rclass = QueryClass::from(parser.next_u16()?);
+        let raw_class = parser.next_u16()?;
+        let rclass = QueryClass::from(raw_class);
         let ttl = parser.next_u32()?;
         let len = parser.next_u16()?;
 
@@ -29,9 +34,19 @@ impl Record {
             rclass,
             ttl,
             len,
-            data: RecordData::UNKNOWN(len),
+            data: RecordData::default(),
         };
 
+        let data_start = parser.offset();
+        if data_start + len as usize > parser.bytes().len() {
+            bail!("record data of {} exceeds the message length", record.domain);
+        }
+
+        // records without data only appear in updates, where they stand for whole RRsets (RFC 2136 2.5.2)
+        if len == 0 && record.rtype != QueryType::OPT {
+            return Ok(record);
+        }
+
         match record.rtype {
             QueryType::A => {
                 let raw_addr = parser.next_u32()?;
@@ -43,28 +58,30 @@ impl Record {
                         ((raw_addr >> 0) & 0xFF) as u8,
                     )
                 );
-
-                Ok(record)
             },
             QueryType::NS => {
                 record.data = RecordData::NS(parser.parse_domain_name()?);
-
-                Ok(record)
             },
             QueryType::CNAME => {
                 record.data = RecordData::CNAME(parser.parse_domain_name()?);
-
-                Ok(record)
             },
             QueryType::PTR => {
                 record.data = RecordData::PTR(parser.parse_domain_name()?);
-
-                Ok(record)
             },
             QueryType::TXT => {
-                record.data = RecordData::TXT(parser.parse_domain_name()?);
+                let mut strings = Vec::new();
 
-                Ok(record)
+                while parser.offset() < data_start + len as usize {
+                    strings.push(parser.parse_character_string()?);
+                }
+
+                record.data = RecordData::TXT(strings);
+            },
+            QueryType::HINFO => {
+                record.data = RecordData::HINFO {
+                    cpu: parser.parse_character_string()?,
+                    os: parser.parse_character_string()?,
+                };
             },
             QueryType::SOA => {
                 record.data = RecordData::SOA {
@@ -76,16 +93,20 @@ impl Record {
                       expire: parser.next_u32()?,
                       minimum: parser.next_u32()?,
                 };
-
-                Ok(record)
             },
             QueryType::MX => {
                 record.data = RecordData::MX {
                     preference: parser.next_u16()?,
                     exchange: parser.parse_domain_name()?,
                 };
-
-                Ok(record)
+            },
+            QueryType::SRV => {
+                record.data = RecordData::SRV {
+                    priority: parser.next_u16()?,
+                    weight: parser.next_u16()?,
+                    port: parser.next_u16()?,
+                    host: parser.parse_domain_name()?,
+                };
             },
             QueryType::AAAA => {
                 let first_part = parser.next_u32()?;
@@ -103,17 +124,57 @@ impl Record {
                     ((fourth_part >> 16) & 0xFFFF) as u16,
                     (fourth_part & 0xFFFF) as u16,
                 ));
+            },
+            QueryType::OPT => {
+                let mut options = Vec::new();
 
-                Ok(record)
-            }
+                while parser.offset() < data_start + len as usize {
+                    let code = parser.next_u16()?;
+                    let len = parser.next_u16()?;
+
+                    options.push(EdnsOption {
+                        code,
+                        data: parser.next_bytes(len as usize)?
+                    });
+                }
+
+                record.data = RecordData::OPT(Edns::from(raw_class, ttl, options));
+            },
+            QueryType::TSIG => {
+                let algorithm = parser.parse_domain_name()?;
+                let time_signed = (parser.next_u16()? as u64) << 32 | parser.next_u32()? as u64;
+                let fudge = parser.next_u16()?;
+                let mac_len = parser.next_u16()?;
+                let mac = parser.next_bytes(mac_len as usize)?;
+                let original_id = parser.next_u16()?;
+                let error = parser.next_u16()?;
+                let other_len = parser.next_u16()?;
+
+                record.data = RecordData::TSIG(Tsig {
+                    algorithm,
+                    time_signed,
+                    fudge,
+                    mac,
+                    original_id,
+                    error,
+                    other: parser.next_bytes(other_len as usize)?
+                });
+            },
+            // keep the data of every other type as is, so it can be passed on unchanged
             _ => {
-                Ok(record)
+                record.data = RecordData::UNKNOWN(parser.next_bytes(len as usize)?);
             }
         }
+
+        if parser.offset() != data_start + len as usize {
+            bail!("record data of {} doesn't match its length", record.domain);
+        }
+
+        Ok(record)
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum RecordData {
     A(Ipv4Addr),
     NS(String),
@@ -129,14 +190,15 @@ pub enum RecordData {
     },
     PTR(String),
     HINFO {
-        cpu: String,
-        os: String
+        cpu: Vec<u8>,
+        os: Vec<u8>
     },
     MX {
         preference: u16,
         exchange: String
     },
-    TXT(String),
+    // a list of <character-string>s, each one is at most 255 bytes
+    TXT(Vec<Vec<u8>>),
     AAAA(Ipv6Addr),
     SRV {
         priority: u16,
@@ -144,11 +206,34 @@ pub enum RecordData {
         port: u16,
         host: String,
     },
-    UNKNOWN(u16)
+    OPT(Edns),
+    TSIG(Tsig),
+    UNKNOWN(Vec<u8>)
+}
+
+impl RecordData {
+    // decodes the uncompressed wire format of a record's data, like the one
+    // in RFC 3597 generic zone file entries.
+    pub fn from_wire(rtype: QueryType, data: &[u8]) -> Result<RecordData> {
+        if data.len() > u16::MAX as usize {
+            bail!("record data is too large");
+        }
+
+        let mut buf = vec![0];
+        buf.extend_from_slice(&BytesPair::from(rtype.to_num()).bytes());
+        buf.extend_from_slice(&BytesPair::from(QueryClass::IN.to_num()).bytes());
+        buf.extend_from_slice(&BytesUtil::from_u32(0));
+        buf.extend_from_slice(&BytesPair::from(data.len() as u16).bytes());
+        buf.extend_from_slice(data);
+
+        let record = Record::parse(&mut PacketParser::new(&buf))?;
+
+        Ok(record.data)
+    }
 }
 
 impl Default for RecordData {
     fn default() -> Self {
-        Self::UNKNOWN(u16::default())
+        Self::UNKNOWN(Vec::new())
     }
 }
\ No newline at end of file
@@ -0,0 +1,206 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+use anyhow::{bail, Result};
+use tokio::select;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::{sleep, Duration};
+use tracing::{error, info, warn};
+use crate::transfer::serial_gt;
+use crate::zone::fs::read_dir;
+use crate::zone::parser::Zone;
+use crate::zone::tree::{SharedTree, ZoneTree};
+
+// reads every zone of the zones directory and swaps them in for the ones being served.
+// nothing changes unless all of the files can be read, secondary zones are left alone.
+pub fn load_zones(tree: &SharedTree, dir: &Path, nested: bool, secondaries: &[String]) -> Result<()> {
+    // a server may only serve secondary zones
+    if dir.as_os_str().is_empty() {
+        return Ok(());
+    }
+
+    let loaded = ZoneTree::from(Zone::parse_directory(dir, nested)?)?;
+    if let Some(origin) = secondaries.iter().find(|origin| loaded.contains(origin)) {
+        bail!("zone {} is a secondary zone, it can't be loaded from {}", origin, dir.display());
+    }
+
+    tree.update(|current| {
+        let mut keep = secondaries.to_vec();
+
+        // a zone changed by a dynamic update while the files were being read is newer than its file
+        for origin in loaded.origins() {
+            if let (Some(zone), Some(old)) = (loaded.get(&origin), current.get(&origin)) {
+                if serial_gt(old.serial(), zone.serial()) {
+                    warn!("zone {} is newer than its file, keeping serial {}", origin, old.serial());
+                    keep.push(origin);
+                }
+            }
+        }
+
+        current.replace(loaded, &keep);
+
+        Ok(())
+    })
+}
+
+// reloads the zones directory on SIGHUP and, if an interval is set, whenever one of its
+// files changes. a zone file that can't be read keeps the previous zones in service.
+pub struct Reloader {
+    tree: Arc<SharedTree>,
+    dir: PathBuf,
+    nested: bool,
+    secondaries: Vec<String>,
+    // how often the files are checked for changes
+    interval: Option<Duration>
+}
+
+impl Reloader {
+    pub fn new(tree: Arc<SharedTree>, dir: PathBuf, nested: bool, secondaries: Vec<String>, interval: Option<Duration>) -> Self {
+        Self {
+            tree,
+            dir,
+            nested,
+            secondaries,
+            interval
+        }
+    }
+
+    pub async fn run(self) {
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => Some(hangup),
+            Err(e) => {
+                error!("couldn't listen for SIGHUP, zones are only reloaded when their files change: {}", e);
+
+                None
+            }
+        };
+
+        if hangup.is_none() && self.interval.is_none() {
+            return;
+        }
+
+        let mut files = self.files();
+        loop {
+            select! {
+                Some(_) = async { hangup.as_mut()?.recv().await } => {
+                    info!("reloading zones after SIGHUP");
+                },
+                _ = sleep(self.interval.unwrap_or_default()), if self.interval.is_some() => {
+                    if self.files() == files {
+                        continue;
+                    }
+
+                    info!("zone files in {} have changed, reloading zones", self.dir.display());
+                }
+            }
+
+            files = self.files();
+            self.reload();
+        }
+    }
+
+    fn reload(&self) {
+        match load_zones(&self.tree, &self.dir, self.nested, &self.secondaries) {
+            Ok(_) => info!("reloaded zones from {}", self.dir.display()),
+            Err(e) => error!("failed to reload zones, still serving the previous ones: {}", e)
+        }
+    }
+
+    // the zone files along with their sizes and modification times, to tell when any of them changes.
+    // the files included by the zones being served are watched too, wherever they are.
+    fn files(&self) -> Vec<(PathBuf, Option<SystemTime>, u64)> {
+        let tree = self.tree.load();
+        let includes = tree.origins().into_iter()
+            .filter_map(|origin| tree.get(&origin).map(|zone| zone.includes.clone()))
+            .flatten();
+
+        let mut paths = read_dir(&self.dir, self.nested).unwrap_or_default();
+        paths.extend(includes);
+
+        let mut files: Vec<(PathBuf, Option<SystemTime>, u64)> = paths
+            .into_iter()
+            .filter_map(|path| {
+                let metadata = fs::metadata(&path).ok()?;
+
+                Some((path, metadata.modified().ok(), metadata.len()))
+            })
+            .collect();
+        files.sort();
+        files.dedup();
+
+        files
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::query_type::QueryType;
+    use crate::zone::parser::parse;
+    use crate::zone::tree::AuthZone;
+
+    fn zone(serial: u32, addr: &str) -> String {
+        format!("$ORIGIN example.com.
+@ 3600 IN SOA ns1.example.com. admin.example.com. ( {} 7200 3600 1209600 300 )
+@ 3600 IN NS ns1
+ns1 3600 IN A {}
+", serial, addr)
+    }
+
+    #[test]
+    fn reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = SharedTree::default();
+        let secondaries = vec!["example.org".to_string()];
+
+        fs::write(dir.path().join("example.com.zone"), zone(1, "10.0.0.1")).unwrap();
+        load_zones(&tree, dir.path(), false, &secondaries).unwrap();
+
+        // a secondary zone transferred in the meantime
+        tree.update(|current| {
+            let secondary = zone(1, "10.0.0.9").replace("example.com", "example.org");
+            current.insert(AuthZone::from(parse(secondary.into_bytes())?)?);
+
+            Ok(())
+        }).unwrap();
+
+        // a broken file keeps every zone as it was and names the file and line
+        fs::write(dir.path().join("broken.zone"), "$ORIGIN example.net.\n@ 3600 IN A 10.0.0.300\n").unwrap();
+        let err = load_zones(&tree, dir.path(), false, &secondaries).unwrap_err().to_string();
+        assert!(err.contains("broken.zone:2:13: invalid address"), "{}", err);
+        assert_eq!(tree.load().get("example.com").unwrap().serial(), 1);
+
+        fs::remove_file(dir.path().join("broken.zone")).unwrap();
+        fs::write(dir.path().join("example.com.zone"), zone(2, "10.0.0.2")).unwrap();
+        load_zones(&tree, dir.path(), false, &secondaries).unwrap();
+
+        let current = tree.load();
+        let zone = current.get("example.com").unwrap();
+        assert_eq!(zone.serial(), 2);
+        assert_eq!(zone.journal().since(1).map(|diffs| diffs.len()), Some(1));
+        assert!(zone.get("ns1.example.com", QueryType::A).is_some());
+        assert!(current.contains("example.org"));
+    }
+
+    #[test]
+    fn watched_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let fragments = tempfile::tempdir().unwrap();
+        let hosts = fragments.path().join("hosts.inc");
+
+        fs::write(&hosts, "www 3600 IN A 10.0.0.5\n").unwrap();
+        fs::write(dir.path().join("example.com.zone"), format!("{}$INCLUDE {}\n", zone(1, "10.0.0.1"), hosts.display())).unwrap();
+
+        let tree = Arc::new(SharedTree::default());
+        load_zones(&tree, dir.path(), false, &[]).unwrap();
+
+        // a file included from outside the zones directory is watched too
+        let reloader = Reloader::new(tree, dir.path().to_path_buf(), false, vec![], Some(Duration::from_secs(1)));
+        let files = reloader.files();
+        assert_eq!(files.len(), 2);
+
+        fs::write(&hosts, "www 3600 IN A 10.0.0.5\nmail 3600 IN A 10.0.0.6\n").unwrap();
+        assert_ne!(reloader.files(), files);
+    }
+}
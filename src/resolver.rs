@@ -1,39 +1,87 @@
-use std::collections::{HashMap};
+use std::collections::{HashMap, HashSet};
 use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
 use std::ops::Deref;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 use anyhow::{bail, Result};
 use rand::{random};
-use tracing::{error};
-use crate::cache::{DnsCache, DnsCacheItem};
-use crate::context::Context;
+use tokio::sync::Notify;
+use tokio::task::spawn_blocking;
+use tracing::{error, info, warn};
+use crate::cache::{CachedAnswer, DnsCache};
+use crate::acl::Acl;
+use crate::context::{Context, ListenerProtocol, PrimaryZone, SecondaryZone};
+use crate::edns::{Edns, BADVERS, EDNS_UDP_PAYLOAD_SIZE, EDNS_VERSION};
 use crate::query_type::QueryType;
 use crate::handler::{Handler, UdpHandler};
+use crate::opcode::Opcode;
 use crate::packet::Packet;
 use crate::parser::PacketParser;
 use crate::question::Question;
 use crate::record::{Record, RecordData};
 use crate::result_code::ResultCode;
+use crate::reload::{load_zones, Reloader};
+use crate::secondary::Secondary;
+use crate::server::{max_udp_payload, truncate};
+use crate::tsig::{verify_request, Keyring, TsigError};
+use crate::transfer::{axfr_records, ixfr_records, soa_serial, write_transfer};
 use crate::writer::PacketWriter;
-use crate::zone::parser::Zone;
+use crate::update;
+use crate::zone::tree::{is_subdomain, AuthZone, Lookup, SharedTree, ZoneTree};
+use crate::zone::writer::save_zone;
+
+// the longest chain of aliases followed inside our own zones.
+const MAX_CNAME_CHAIN: usize = 16;
 
 pub trait Resolver: Send + Sync {
     fn resolve(&self, buf: Arc<Vec<u8>>) -> Result<Vec<u8>>;
+
+    // resolves a query knowing where it came from, some queries are answered with
+    // several messages (zone transfers) and only to some clients.
+    fn resolve_from(&self, buf: Arc<Vec<u8>>, _source: &Source) -> Result<Vec<Vec<u8>>> {
+        Ok(vec![self.resolve(buf)?])
+    }
+}
+
+// the client a query was received from and the transport it used
+#[derive(Clone, Copy, Debug)]
+pub struct Source {
+    pub addr: SocketAddr,
+    pub proto: ListenerProtocol
 }
 
 pub struct AuthoritativeResolver {
-    cache: Arc<DnsCache>,
+    tree: Arc<SharedTree>,
     zones: PathBuf,
-    nested_zones: bool
+    nested_zones: bool,
+    keys: Keyring,
+    allow_transfer: Acl,
+    allow_update: Acl,
+    // our own zones with access lists of their own
+    primaries: HashMap<String, PrimaryZone>,
+    secondaries: HashMap<String, SecondaryHandle>
+}
+
+// what's needed to accept notifies for a secondary zone
+struct SecondaryHandle {
+    primaries: Vec<SocketAddr>,
+    // the key notifies have to be signed with
+    key: Option<String>,
+    refresh: Arc<Notify>
 }
 
 impl AuthoritativeResolver {
-    pub fn new(zones: PathBuf, nested: bool) -> Result<Self> {
-        let mut res = Self {
-            cache: Arc::new(DnsCache::new()),
+    pub fn new(zones: PathBuf, nested: bool, keys: Keyring, allow_transfer: Acl, allow_update: Acl) -> Result<Self> {
+        let res = Self {
+            tree: Arc::new(SharedTree::default()),
             nested_zones: nested,
             zones,
+            keys,
+            allow_transfer,
+            allow_update,
+            primaries: HashMap::new(),
+            secondaries: HashMap::new()
         };
 
         res.load_zones()?;
@@ -41,97 +89,444 @@ impl AuthoritativeResolver {
         Ok(res)
     }
     
-    pub fn load_zones(&mut self) -> Result<()> {
-        let zones = Zone::parse_directory(&self.zones, self.nested_zones)?;
-        
-        let cache = self.cache.clone();
-        for zone in zones {
-            cache.set(zone.origin.as_str(), DnsCacheItem::new(zone.records));    
+    pub fn load_zones(&self) -> Result<()> {
+        load_zones(&self.tree, &self.zones, self.nested_zones, &self.secondary_origins())
+    }
+
+    // the task reloading the zones directory, secondary zones have to be added first
+    pub fn reloader(&self, interval: Option<Duration>) -> Reloader {
+        Reloader::new(self.tree(), self.zones.clone(), self.nested_zones, self.secondary_origins(), interval)
+    }
+
+    fn secondary_origins(&self) -> Vec<String> {
+        self.secondaries.keys().cloned().collect()
+    }
+
+    // the zones being served, shared with the tasks that keep secondary zones up to date
+    pub fn tree(&self) -> Arc<SharedTree> {
+        self.tree.clone()
+    }
+
+    // gives one of our zones access lists of its own, the zone itself is loaded from the zones directory.
+    pub fn add_primary(&mut self, zone: PrimaryZone) -> Result<()> {
+        if self.primaries.contains_key(&zone.origin) {
+            bail!("zone {} is defined more than once", zone.origin);
         }
-        
+
+        self.primaries.insert(zone.origin.clone(), zone);
+
         Ok(())
     }
-}
 
-impl Resolver for AuthoritativeResolver {
-    fn resolve(&self, buf: Arc<Vec<u8>>) -> Result<Vec<u8>> {
-        let req = match PacketParser::new(buf.deref()).parse() {
-            Ok(packet) => packet,
-            Err(_e) => {
-                let mut res = Packet::new();
-                res.header.code = ResultCode::FORMERR.to_u8();
-                res.header.authoritative = true;
-                res.header.recursion_available = false;
-                res.header.response = true;
-                
-                return PacketWriter::from(res).write();
-            }
-        };
-        
-        let mut res = Packet::from(&req);
+    // adds a zone copied from other servers, the returned task keeps it up to date.
+    pub fn add_secondary(&mut self, zone: SecondaryZone, timeout: Duration) -> Result<Secondary> {
+        if self.tree.load().contains(&zone.origin)
+            || self.primaries.contains_key(&zone.origin)
+            || self.secondaries.contains_key(&zone.origin) {
+            bail!("zone {} is defined more than once", zone.origin);
+        }
+
+        let secondary = Secondary::new(zone.clone(), self.tree(), timeout);
+        self.secondaries.insert(zone.origin, SecondaryHandle {
+            primaries: zone.primaries,
+            key: zone.key.map(|key| key.name.clone()),
+            refresh: secondary.refresh_signal()
+        });
+
+        Ok(secondary)
+    }
+
+    // `key` is the key the request was signed with
+    fn respond(&self, req: &Packet, source: Option<&Source>, key: Option<&str>) -> Result<Vec<u8>> {
+        let mut res = Packet::from(req);
         res.header.authoritative = true;
         res.header.recursion_available = false;
         res.header.response = true;
         res.header.code = ResultCode::NOERROR.to_u8();
+
+        if unsupported_edns_version(req, &mut res) {
+            return PacketWriter::from(res).write();
+        }
+
+        match Opcode::from(req.header.opcode) {
+            Opcode::QUERY => {},
+            Opcode::NOTIFY => {
+                res.header.code = self.notified(req, source, key).to_u8();
+                set_edns(req, &mut res);
+
+                return PacketWriter::from(res).write();
+            },
+            Opcode::UPDATE => {
+                res.header.authoritative = false;
+                res.header.code = self.update(req, source, key).to_u8();
+                set_edns(req, &mut res);
+
+                return PacketWriter::from(res).write();
+            },
+            _ => {
+                res.header.code = ResultCode::NOTIMP.to_u8();
+                set_edns(req, &mut res);
+
+                return PacketWriter::from(res).write();
+            }
+        }
         
         if req.questions.len() == 0 {
             res.header.code = ResultCode::FORMERR.to_u8();
+            set_edns(req, &mut res);
             
             return PacketWriter::from(res).write();
         }
+
+        // zone transfers are only served on their own, full ones only over tcp
+        if req.questions.iter().any(|question| is_transfer(question.qtype)) {
+            res.header.code = ResultCode::NOTIMP.to_u8();
+            set_edns(req, &mut res);
+
+            return PacketWriter::from(res).write();
+        }
         
         for question in &req.questions {
-            if let Some(records) = self.cache.get(question.domain.as_str()) {
-                records.iter().for_each(|record| {
-                    match record.rtype { 
-                        QueryType::NS => {
-                            res.authorities.push(record.clone());
-                            res.header.authority_count += 1;
-                            
-                            if let RecordData::NS(ns) = &record.data {
-                                if let Some(mut ns) = self.cache.get(ns) {
-                                    res.header.resource_count += ns.len() as u16;
-                                    res.resources.append(&mut ns);
-                                }
-                            }
-                        },
-                        QueryType::SOA => {
-                            return
-                        },
-                        _ => {
-                            res.answers.push(record.clone());
-                            res.header.answer_count += 1;
-                        }
+            self.answer(question, &mut res);
+        }
+
+        set_edns(req, &mut res);
+
+        PacketWriter::from(res).write()
+    }
+
+    // sends a zone to a client that is allowed to transfer it, either whole (RFC 5936) or
+    // the changes since the client's version of it (RFC 1995).
+    fn transfer(&self, req: &Packet, source: &Source, key: Option<&str>) -> Result<Vec<Vec<u8>>> {
+        let question = &req.questions[0];
+        if question.qtype == QueryType::AXFR && source.proto != ListenerProtocol::TCP {
+            return Ok(vec![self.respond(req, Some(source), key)?]);
+        }
+
+        let mut res = Packet::from(req);
+        res.header.authoritative = true;
+        res.header.recursion_available = false;
+        res.header.response = true;
+        res.header.code = ResultCode::NOERROR.to_u8();
+
+        let origin = question.domain.to_lowercase();
+        let acl = self.primaries.get(&origin).map_or(&self.allow_transfer, |zone| &zone.allow_transfer);
+        if !acl.allows(&source.addr.ip(), key) {
+            warn!("refused transfer of {} to {}", question.domain, source.addr);
+            res.header.code = ResultCode::REFUSED.to_u8();
+
+            return Ok(vec![PacketWriter::from(res).write()?]);
+        }
+
+        let tree = self.tree.load();
+        let zone = match tree.get(&question.domain) {
+            Some(zone) => zone,
+            None => {
+                res.header.code = ResultCode::NOTAUTH.to_u8();
+
+                return Ok(vec![PacketWriter::from(res).write()?]);
+            }
+        };
+
+        let records = match question.qtype {
+            QueryType::IXFR => {
+                let serial = match req.authorities.iter().find_map(soa_serial) {
+                    Some(serial) => serial,
+                    None => {
+                        res.header.code = ResultCode::FORMERR.to_u8();
+
+                        return Ok(vec![PacketWriter::from(res).write()?]);
                     }
-                });
+                };
 
-                if res.answers.len() == 0 {
-                    if let Some(record) = records.iter().find_map(|record| {
-                        if record.rtype == QueryType::SOA {
-                            return Some(record)
-                        }
+                match source.proto {
+                    // a udp answer only tells the client whether it's up to date, the
+                    // changes are sent over tcp (RFC 1995 2)
+                    ListenerProtocol::UDP => vec![zone.soa().clone()],
+                    _ => ixfr_records(zone, serial).unwrap_or_else(|| axfr_records(zone))
+                }
+            },
+            _ => axfr_records(zone)
+        };
+
+        info!("transferring {} ({:?}) to {}", question.domain, question.qtype, source.addr);
 
-                        None
-                    }) {
-                        res.answers.push(record.clone());
-                        res.header.answer_count += 1;
+        write_transfer(res, records)
+    }
+
+    // a primary tells us that one of our secondary zones has changed, the zone is
+    // refreshed right away (RFC 1996 3.7).
+    fn notified(&self, req: &Packet, source: Option<&Source>, key: Option<&str>) -> ResultCode {
+        if req.questions.len() != 1 {
+            return ResultCode::FORMERR;
+        }
+
+        let origin = req.questions[0].domain.to_lowercase();
+        let secondary = match self.secondaries.get(&origin) {
+            Some(secondary) => secondary,
+            None => return ResultCode::NOTAUTH
+        };
+
+        // only the primaries of the zone may tell us about changes
+        match source {
+            Some(source) if secondary.primaries.iter().any(|primary| primary.ip() == source.addr.ip()) => {},
+            _ => {
+                warn!("refused a notify for {} from {:?}", origin, source.map(|source| source.addr));
+
+                return ResultCode::REFUSED;
+            }
+        }
+
+        if secondary.key.is_some() && secondary.key.as_deref() != key {
+            warn!("refused a notify for {} that isn't signed with key {:?}", origin, secondary.key);
+
+            return ResultCode::REFUSED;
+        }
+
+        secondary.refresh.notify_one();
+
+        ResultCode::NOERROR
+    }
+
+    // changes one of our own zones on behalf of a client (RFC 2136), the new version
+    // of the zone is written back to its file before it's served.
+    fn update(&self, req: &Packet, source: Option<&Source>, key: Option<&str>) -> ResultCode {
+        if req.questions.len() != 1 || req.questions[0].qtype != QueryType::SOA {
+            return ResultCode::FORMERR;
+        }
+
+        let origin = req.questions[0].domain.to_lowercase();
+
+        let acl = self.primaries.get(&origin).map_or(&self.allow_update, |zone| &zone.allow_update);
+        match source {
+            Some(source) if acl.allows(&source.addr.ip(), key) => {},
+            _ => {
+                warn!("refused an update of {} from {:?}", origin, source.map(|source| source.addr));
+
+                return ResultCode::REFUSED;
+            }
+        }
+
+        // secondary zones are changed on their primaries
+        if self.secondaries.contains_key(&origin) {
+            return ResultCode::NOTIMP;
+        }
+
+        let mut code = ResultCode::NOERROR;
+        let res = self.tree.update(|tree| {
+            let zone = match tree.get(&origin) {
+                Some(zone) => zone,
+                None => {
+                    code = ResultCode::NOTAUTH;
+
+                    return Ok(());
+                }
+            };
+
+            // the zone is written back as a single file, which would leave its included files behind
+            if !zone.includes.is_empty() {
+                warn!("refused an update of {}, the zone includes other files", origin);
+                code = ResultCode::REFUSED;
+
+                return Ok(());
+            }
+
+            let updated = match update::apply(zone, req) {
+                Ok(Some(updated)) => AuthZone::from(updated)?,
+                Ok(None) => return Ok(()),
+                Err(e) => {
+                    code = e;
+
+                    return Ok(());
+                }
+            };
+
+            if let Some(file) = &updated.file {
+                save_zone(&updated, file)?;
+            }
+
+            info!("updated zone {} to serial {}", origin, updated.serial());
+            tree.insert(updated);
+
+            Ok(())
+        });
+
+        match res {
+            Ok(_) => code,
+            Err(e) => {
+                error!("couldn't update zone {}: {}", origin, e);
+
+                ResultCode::SERVFAIL
+            }
+        }
+    }
+
+    // answers a question from our zones, aliases are followed as long as their targets
+    // are in one of the zones we serve (RFC 1034 4.3.2).
+    fn answer(&self, question: &Question, res: &mut Packet) {
+        let tree = self.tree.load();
+        let mut name = question.domain.to_lowercase();
+        let mut visited = HashSet::new();
+
+        loop {
+            let zone = match tree.find(&name) {
+                Some(zone) => zone,
+                None => {
+                    // we are not authoritative for this name, the client has to follow
+                    // the alias itself if we have answered with one
+                    if res.answers.is_empty() {
+                        res.header.authoritative = false;
+                        res.header.code = ResultCode::REFUSED.to_u8();
                     }
+
+                    break;
                 }
+            };
+
+            if !visited.insert(name.clone()) || visited.len() > MAX_CNAME_CHAIN {
+                error!("CNAME loop detected while resolving {}", question.domain);
+
+                break;
             }
+
+            match zone.lookup(&name, question.qtype) {
+                Lookup::Answer(mut records) => {
+                    res.answers.append(&mut records);
+                },
+                Lookup::CName(record) => {
+                    if let RecordData::CNAME(target) = &record.data {
+                        name = target.to_lowercase();
+                    }
+                    res.answers.push(record);
+
+                    continue;
+                },
+                Lookup::Referral { mut ns, mut glue } => {
+                    // the child zone is the authority for the name, not us
+                    if res.answers.is_empty() {
+                        res.header.authoritative = false;
+                    }
+                    res.authorities.append(&mut ns);
+                    res.resources.append(&mut glue);
+                },
+                Lookup::NoData => {
+                    res.authorities.push(zone.negative_soa());
+                },
+                Lookup::NxDomain => {
+                    res.header.code = ResultCode::NXDOMAIN.to_u8();
+                    res.authorities.push(zone.negative_soa());
+                }
+            }
+
+            break;
         }
-        
-        if res.authorities.len() == 0 && res.answers.len() == 0 {
-            res.header.code = ResultCode::NXDOMAIN.to_u8();
 
-            return PacketWriter::from(res).write();
+        Self::add_additionals(&tree, res);
+    }
+
+    // adds the addresses of the hosts that NS, MX and SRV records refer to, so the
+    // client doesn't have to look them up right after.
+    fn add_additionals(tree: &ZoneTree, res: &mut Packet) {
+        let mut seen: HashSet<(String, QueryType)> = res.resources.iter().map(|record| {
+            (record.domain.to_lowercase(), record.rtype)
+        }).collect();
+
+        let hosts: Vec<String> = res.answers.iter().chain(res.authorities.iter()).filter_map(|record| {
+            match &record.data {
+                RecordData::NS(host) => Some(host.to_lowercase()),
+                RecordData::MX { exchange, .. } => Some(exchange.to_lowercase()),
+                RecordData::SRV { host, .. } => Some(host.to_lowercase()),
+                _ => None
+            }
+        }).collect();
+
+        for host in hosts {
+            let zone = match tree.find(&host) {
+                Some(zone) => zone,
+                None => continue
+            };
+
+            for rtype in [QueryType::A, QueryType::AAAA] {
+                if !seen.insert((host.clone(), rtype)) {
+                    continue;
+                }
+
+                if let Lookup::Answer(mut addrs) = zone.lookup(&host, rtype) {
+                    res.resources.append(&mut addrs);
+                }
+            }
         }
+    }
+}
 
-        PacketWriter::from(res).write()
+impl Resolver for AuthoritativeResolver {
+    fn resolve(&self, buf: Arc<Vec<u8>>) -> Result<Vec<u8>> {
+        match PacketParser::new(buf.deref()).parse() {
+            Ok(req) => self.respond(&req, None, None),
+            Err(_) => format_error()
+        }
+    }
+
+    // signed requests get signed answers, each message of a transfer is chained to
+    // the one before it.
+    fn resolve_from(&self, buf: Arc<Vec<u8>>, source: &Source) -> Result<Vec<Vec<u8>>> {
+        let mut parser = PacketParser::new(buf.deref());
+        let req = match parser.parse() {
+            Ok(req) => req,
+            Err(_) => return Ok(vec![format_error()?])
+        };
+
+        let transaction = match verify_request(&parser, &req, &self.keys) {
+            Ok(transaction) => transaction,
+            Err(e) => {
+                warn!("rejected the signature of a request from {}: {:?}", source.addr, e);
+
+                let mut res = Packet::from(&req);
+                res.header.response = true;
+                res.header.code = match e {
+                    TsigError::Malformed => ResultCode::FORMERR.to_u8(),
+                    _ => ResultCode::NOTAUTH.to_u8()
+                };
+
+                return Ok(vec![e.sign(&PacketWriter::from(res).write()?)?]);
+            }
+        };
+        let key = transaction.as_ref().map(|transaction| transaction.key().name.clone());
+
+        let query = Opcode::from(req.header.opcode) == Opcode::QUERY;
+        let messages = if query && req.questions.len() == 1 && is_transfer(req.questions[0].qtype) {
+            self.transfer(&req, source, key.as_deref())?
+        } else {
+            vec![self.respond(&req, Some(source), key.as_deref())?]
+        };
+
+        // a signed answer that doesn't fit in a udp datagram is truncated before it's signed,
+        // the server couldn't sign it once it had been truncated (RFC 8945 5.3)
+        let limit = match source.proto {
+            ListenerProtocol::UDP => max_udp_payload(&buf),
+            _ => usize::MAX
+        };
+
+        match transaction {
+            Some(mut transaction) => messages.iter().map(|message| {
+                let mut signing = transaction.clone();
+                let signed = signing.sign(message)?;
+                if signed.len() <= limit {
+                    transaction = signing;
+
+                    return Ok(signed);
+                }
+
+                transaction.sign(&truncate(message)?)
+            }).collect(),
+            None => Ok(messages)
+        }
     }
 }
 
+#[derive(Clone)]
 pub struct RecursiveResolver {
-    pub base_handler: Box<dyn Handler + Send + Sync>,
+    pub base_handler: Arc<dyn Handler + Send + Sync>,
     cache: Arc<DnsCache>,
     max_recursion_depth: usize,
 }
@@ -139,23 +534,41 @@ pub struct RecursiveResolver {
 impl RecursiveResolver {
     pub fn new(ctx: Arc<Context>) -> Self {
         Self {
-            base_handler: Box::new(UdpHandler::new(ctx.clone())),
-            cache: Arc::new(DnsCache::new()),
+            base_handler: Arc::new(UdpHandler::new(ctx.clone())),
+            cache: Arc::new(DnsCache::new(&ctx.cache)),
             max_recursion_depth: ctx.resolver.max_recursion_depth
         }
     }
 
+    pub fn cache(&self) -> Arc<DnsCache> {
+        self.cache.clone()
+    }
+
     pub fn recursive_lookup(
         &self,
         question: &Question,
         addrs: Option<Vec<SocketAddr>>,
         depth: usize
+    ) -> Result<Packet> {
+        self.resolve_question(question, addrs, depth, true)
+    }
+
+    // without the cache, the question is asked all the way down to its zone's name servers
+    fn resolve_question(
+        &self,
+        question: &Question,
+        addrs: Option<Vec<SocketAddr>>,
+        depth: usize,
+        cached: bool
     ) -> Result<Packet> {
         if depth == self.max_recursion_depth {
             bail!("maximum resolve recursion depth exceeded")
         }
 
-        let res = lookup(self.cache.clone(), &self.base_handler, &question, addrs)?;
+        let res = match cached {
+            true => lookup(self.cache.clone(), &self.base_handler, question, addrs)?,
+            false => query(&self.cache, &self.base_handler, question, addrs)?
+        };
         
         if is_resolved(question, &res) {
             return Ok(res);
@@ -181,19 +594,32 @@ impl RecursiveResolver {
         
         let resolved_ns = get_resolved_ns(&res.resources);
         if !resolved_ns.is_empty() {
-            return self.recursive_lookup(
+            return self.resolve_question(
                 question,
                 Some(resolved_ns),
-                depth + 1);
+                depth + 1,
+                cached);
         }
 
         let ns = self.get_unresolved(&res.authorities)?;
         if !ns.is_empty() {
-            return self.recursive_lookup(question, Some(ns), depth + 1);
+            return self.resolve_question(question, Some(ns), depth + 1, cached);
         }
 
         Ok(res)
     }
+
+    // resolves the question again in the background, before its answer expires
+    fn prefetch(&self, question: &Question) {
+        let resolver = self.clone();
+        let question = question.clone();
+
+        spawn_blocking(move || {
+            if let Err(e) = resolver.resolve_question(&question, None, 0, false) {
+                warn!("failed to prefetch {} {:?}: {}", question.domain, question.qtype, e);
+            }
+        });
+    }
     
     fn get_unresolved(&self, authorities: &Vec<Record>) -> Result<Vec<SocketAddr>> {
         let mut res: Vec<SocketAddr> = Vec::new();
@@ -236,17 +662,26 @@ impl Resolver for RecursiveResolver {
         res.header.recursion_available = true;
         res.header.response = true;
 
+        if unsupported_edns_version(&req, &mut res) || unsupported_opcode(&req, &mut res) {
+            return PacketWriter::from(res).write();
+        }
+
         if req.questions.len() == 0 {
             let mut res = Packet::from(&req);
             res.header.recursion_available = true;
             res.header.response = true;
             res.header.code = ResultCode::FORMERR.to_u8();
+            set_edns(&req, &mut res);
 
             return PacketWriter::from(res).write();
         }
 
-        for question in req.questions {
-            match self.recursive_lookup(&question, None, 0) {
+        for question in &req.questions {
+            if self.cache.prefetch(question) {
+                self.prefetch(question);
+            }
+
+            match self.recursive_lookup(question, None, 0) {
                 Ok(result) => {
                     res.header.code = result.header.code;
                     
@@ -262,22 +697,41 @@ impl Resolver for RecursiveResolver {
             }
         }
 
+        set_edns(&req, &mut res);
+
         PacketWriter::from(res).write()
     }
 }
 
+#[derive(Clone)]
 pub struct ForwardResolver {
-    pub base_handler: Box<dyn Handler + Send + Sync>,
+    pub base_handler: Arc<dyn Handler + Send + Sync>,
     cache: Arc<DnsCache>,
 }
 
 impl ForwardResolver {
     pub fn new(ctx: Arc<Context>) -> Self {
         Self {
-            base_handler: Box::new(UdpHandler::new(ctx.clone())),
-            cache: Arc::new(DnsCache::new())
+            base_handler: Arc::new(UdpHandler::new(ctx.clone())),
+            cache: Arc::new(DnsCache::new(&ctx.cache))
         }
     }
+
+    pub fn cache(&self) -> Arc<DnsCache> {
+        self.cache.clone()
+    }
+
+    // asks the upstreams again in the background, before the answer expires
+    fn prefetch(&self, question: &Question) {
+        let resolver = self.clone();
+        let question = question.clone();
+
+        spawn_blocking(move || {
+            if let Err(e) = query(&resolver.cache, &resolver.base_handler, &question, None) {
+                warn!("failed to prefetch {} {:?}: {}", question.domain, question.qtype, e);
+            }
+        });
+    }
 }
 
 impl Resolver for ForwardResolver {
@@ -287,17 +741,28 @@ impl Resolver for ForwardResolver {
         res.header.recursion_available = true;
         res.header.response = true;
 
+        if unsupported_edns_version(&req, &mut res) || unsupported_opcode(&req, &mut res) {
+            return PacketWriter::from(res).write();
+        }
+
         if req.questions.len() == 0 {
             let mut res = Packet::from(&req);
             res.header.recursion_available = true;
             res.header.response = true;
             res.header.code = ResultCode::FORMERR.to_u8();
+            set_edns(&req, &mut res);
 
             return PacketWriter::from(res).write();
         }
 
-        for question in req.questions {
-            if let Ok(result) = lookup(self.cache.clone(), &self.base_handler, &question, None) {
+        for question in &req.questions {
+            if self.cache.prefetch(question) {
+                self.prefetch(question);
+            }
+
+            if let Ok(result) = lookup(self.cache.clone(), &self.base_handler, question, None) {
+                res.header.code = result.header.code;
+
                 append_results(&mut res, result);
             } else {
                 res.header.code = ResultCode::SERVFAIL.to_u8();
@@ -306,63 +771,121 @@ impl Resolver for ForwardResolver {
             }
         }
 
+        set_edns(&req, &mut res);
+
         PacketWriter::from(res).write()
     }
 }
 
 pub fn lookup(
     cache: Arc<DnsCache>,
-    handler: &Box<dyn Handler + Send + Sync>,
+    handler: &Arc<dyn Handler + Send + Sync>,
     question: &Question, 
     addrs: Option<Vec<SocketAddr>>)
     -> Result<Packet> {
-    let req = new_query_packet(question.clone());
-    
-    if let Some(records) = cache.get(question.domain.as_str()) {
-        return Ok(create_resp_packet(&req, records));
+    if let Some(answer) = cache.lookup(question) {
+        return Ok(create_resp_packet(&new_query_packet(question.clone()), answer));
     }
 
-    let req_buf = PacketWriter::from(req).write()?;
+    query(&cache, handler, question, addrs)
+}
 
-    let mut res_buf = Vec::new();
-    match addrs { 
-        Some(addrs) => {
-            res_buf = handler.send_to(req_buf.as_slice(), addrs.as_slice())?;
-        },
-        None => {
-            res_buf = handler.send(req_buf.as_slice())?;
+// asks the servers, or the upstreams when there are none, and caches what they answer. when
+// they can't be reached or fail, an answer that has expired is better than none (RFC 8767).
+fn query(
+    cache: &DnsCache,
+    handler: &Arc<dyn Handler + Send + Sync>,
+    question: &Question,
+    addrs: Option<Vec<SocketAddr>>)
+    -> Result<Packet> {
+    let req_buf = PacketWriter::from(new_query_packet(question.clone())).write()?;
+
+    let res = match addrs { 
+        Some(addrs) => handler.send_to(req_buf.as_slice(), addrs.as_slice()),
+        None => handler.send(req_buf.as_slice())
+    }.and_then(|res_buf| PacketParser::new(&res_buf).parse());
+
+    let mut res = match res {
+        Ok(res) if res.header.code != ResultCode::SERVFAIL.to_u8() => res,
+        failed => {
+            if let Some(answer) = cache.lookup_stale(question) {
+                warn!("answering {} {:?} from the cache, the servers failed", question.domain, question.qtype);
+
+                return Ok(create_resp_packet(&new_query_packet(question.clone()), answer));
+            }
+
+            failed?
         }
-    }
+    };
 
-    let mut res = PacketParser::new(&res_buf).parse()?;
     res.header.recursion_available = true;
     res.header.response = true;
     
-    if !res.answers.is_empty() {
-        let filter = |record: &Record| {
-            if record.domain != question.domain {
-                return None;    
-            }
-            
-            return match record.rtype {
-                QueryType::A | QueryType::AAAA | QueryType::SOA => {
-                    Some(record.clone())
-                },
-                _ => {
-                    None
-                }
-            }
-        };
-        
-        let mut resolved: Vec<Record> = res.answers.iter().filter_map(filter).collect();
-        resolved.append(&mut res.resources.iter().filter_map(filter).collect());
-        
-        cache.set(question.domain.as_str(), DnsCacheItem::new(resolved));
+    cache_response(cache, question, &res);
+
+    // the answer says as long as the cache would
+    for record in res.answers.iter_mut().chain(res.authorities.iter_mut()) {
+        record.ttl = cache.clamp_ttl(record.ttl);
     }
     
     Ok(res)
 }
 
+// caches the answer to a question. an answer without the records that were asked for is
+// cached as well, when it comes with the SOA record of the zone (RFC 2308 5).
+fn cache_response(cache: &DnsCache, question: &Question, res: &Packet) {
+    let code = ResultCode::from(res.header.code);
+    if code != ResultCode::NOERROR && code != ResultCode::NXDOMAIN {
+        return;
+    }
+
+    let (records, name) = answer_chain(question, &res.answers);
+    let answered = records.iter().any(|record| record.rtype == question.qtype);
+    cache.insert(records);
+
+    if answered || question.qtype == QueryType::ASTERISK {
+        return;
+    }
+
+    // the SOA record has to be the one of the zone the name is in, a referral has none
+    let soa = res.authorities.iter().find(|record| {
+        record.rtype == QueryType::SOA && record.rclass == question.qclass && is_subdomain(&name, &record.domain.to_ascii_lowercase())
+    });
+
+    match (code, soa) {
+        (ResultCode::NXDOMAIN, Some(soa)) => cache.insert_nxdomain(&name, question.qclass.clone(), soa.clone()),
+        (ResultCode::NOERROR, Some(soa)) => cache.insert_nodata(&name, question.qtype, question.qclass.clone(), soa.clone()),
+        _ => {}
+    }
+}
+
+// the records of the answer that are about the question, the CNAME records leading from its
+// name and the RRsets of the names they lead to, along with the last of those names. anything
+// else the server added isn't cached.
+fn answer_chain(question: &Question, answers: &[Record]) -> (Vec<Record>, String) {
+    let mut names = vec![question.domain.to_ascii_lowercase()];
+
+    // the CNAME records may come in any order
+    for _ in 0..answers.len() {
+        let name = &names[names.len() - 1];
+        let target = answers.iter().find_map(|record| match &record.data {
+            RecordData::CNAME(target) if record.domain.eq_ignore_ascii_case(name) => Some(target.to_ascii_lowercase()),
+            _ => None
+        });
+
+        match target {
+            Some(target) if !names.contains(&target) => names.push(target),
+            _ => break
+        }
+    }
+
+    let records = answers.iter().filter(|record| {
+        names.contains(&record.domain.to_ascii_lowercase()) && record.rclass == question.qclass
+    }).cloned().collect();
+
+    (records, names.pop().unwrap_or_default())
+}
+
 fn is_resolved(question: &Question, result: &Packet) -> bool {
     if result.answers.is_empty() {
         return false;
@@ -444,17 +967,319 @@ fn new_query_packet(question: Question) -> Packet {
     req.header.recursion_desired = true;
     req.header.question_count = 1;
     req.questions.push(question);
+    req.header.resource_count = 1;
+    req.resources.push(Edns::new(EDNS_UDP_PAYLOAD_SIZE, false).into_record());
 
     req
 }
 
-fn create_resp_packet(req: &Packet, records: Vec<Record>) -> Packet {
+// zone transfers, full or incremental
+fn is_transfer(qtype: QueryType) -> bool {
+    qtype == QueryType::AXFR || qtype == QueryType::IXFR
+}
+
+// the answer to a message that couldn't be parsed
+fn format_error() -> Result<Vec<u8>> {
+    let mut res = Packet::new();
+    res.header.code = ResultCode::FORMERR.to_u8();
+    res.header.authoritative = true;
+    res.header.recursion_available = false;
+    res.header.response = true;
+
+    PacketWriter::from(res).write()
+}
+
+// recursive and forwarding servers only answer standard queries (RFC 1035 4.1.1).
+fn unsupported_opcode(req: &Packet, res: &mut Packet) -> bool {
+    if Opcode::from(req.header.opcode) == Opcode::QUERY {
+        return false;
+    }
+
+    res.header.code = ResultCode::NOTIMP.to_u8();
+    set_edns(req, res);
+
+    true
+}
+
+// answers with BADVERS when the client speaks an edns version we don't implement (RFC 6891 6.1.3).
+fn unsupported_edns_version(req: &Packet, res: &mut Packet) -> bool {
+    match req.edns() {
+        Some(edns) if edns.version > EDNS_VERSION => {
+            let mut opt = Edns::new(EDNS_UDP_PAYLOAD_SIZE, edns.dnssec_ok);
+            opt.extended_code = (BADVERS >> 4) as u8;
+
+            res.header.code = (BADVERS & 0x0F) as u8;
+            res.resources.push(opt.into_record());
+
+            true
+        },
+        _ => false
+    }
+}
+
+// echoes an OPT record back to clients that sent one (RFC 6891 7), the OPT records of
+// upstream responses are hop-by-hop and never passed on.
+fn set_edns(req: &Packet, res: &mut Packet) {
+    res.resources.retain(|record| record.rtype != QueryType::OPT);
+
+    if let Some(edns) = req.edns() {
+        res.resources.push(Edns::new(EDNS_UDP_PAYLOAD_SIZE, edns.dnssec_ok).into_record());
+    }
+}
+
+fn create_resp_packet(req: &Packet, answer: CachedAnswer) -> Packet {
     let mut packet = Packet::from(req);
     packet.header.recursion_available = true;
     packet.header.response = true;
-    packet.header.answer_count = records.len() as u16;
+    packet.header.code = answer.code.to_u8();
+    packet.header.answer_count = answer.answers.len() as u16;
+    packet.header.authority_count = answer.authorities.len() as u16;
 
-    packet.answers = records;
+    packet.answers = answer.answers;
+    packet.authorities = answer.authorities;
     
     packet
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tsig::{Key, Transaction};
+    use crate::zone::parser::parse;
+
+    fn resolver() -> AuthoritativeResolver {
+        let src = "$ORIGIN example.com.
+$TTL 3600
+@ IN SOA ns1.example.com. admin.example.com. (
+    1
+    7200
+    3600
+    1209600
+    300 )
+@ IN NS ns1
+@ IN MX 10 mail
+ns1 IN A 10.0.0.1
+mail IN A 10.0.0.2
+mail IN AAAA ::2
+www IN CNAME web
+web IN CNAME host
+host IN A 10.0.0.3
+external IN CNAME www.example.net.
+loop1 IN CNAME loop2
+loop2 IN CNAME loop1
+dangling IN CNAME missing
+_sip._tcp IN SRV 0 0 5060 host
+";
+
+        let resolver = AuthoritativeResolver::new(PathBuf::new(), false, Keyring::default(), Acl::default(), Acl::from(&["10.0.0.0/8".to_string()]).unwrap()).unwrap();
+        resolver.tree.update(|tree| {
+            *tree = ZoneTree::from(vec![parse(src.as_bytes().to_vec())?])?;
+
+            Ok(())
+        }).unwrap();
+
+        resolver
+    }
+
+    fn answer(name: &str, qtype: QueryType) -> Packet {
+        let mut res = Packet::new();
+        res.header.authoritative = true;
+        resolver().answer(&Question::new(name.to_string(), qtype), &mut res);
+
+        res
+    }
+
+    #[test]
+    fn follow_cnames() {
+        let res = answer("www.example.com", QueryType::A);
+        assert_eq!(res.answers.len(), 3);
+        assert!(matches!(res.answers[2].data, RecordData::A(_)));
+
+        let res = answer("external.example.com", QueryType::A);
+        assert_eq!(res.answers.len(), 1);
+        assert!(res.header.authoritative);
+        assert_eq!(res.header.code, ResultCode::NOERROR.to_u8());
+
+        let res = answer("loop1.example.com", QueryType::A);
+        assert_eq!(res.answers.len(), 2);
+
+        let res = answer("dangling.example.com", QueryType::A);
+        assert_eq!(res.answers.len(), 1);
+        assert_eq!(res.header.code, ResultCode::NXDOMAIN.to_u8());
+        assert_eq!(res.authorities[0].rtype, QueryType::SOA);
+    }
+
+    #[test]
+    fn additionals() {
+        let res = answer("example.com", QueryType::MX);
+        assert_eq!(res.resources.len(), 2);
+        assert!(res.resources.iter().all(|record| record.domain == "mail.example.com"));
+
+        let res = answer("example.com", QueryType::NS);
+        assert_eq!(res.resources.len(), 1);
+        assert_eq!(res.resources[0].domain, "ns1.example.com");
+
+        let res = answer("_sip._tcp.example.com", QueryType::SRV);
+        assert_eq!(res.resources.len(), 1);
+        assert_eq!(res.resources[0].domain, "host.example.com");
+    }
+
+    fn request(opcode: Opcode, domain: &str) -> Arc<Vec<u8>> {
+        let mut req = Packet::new();
+        req.header.id = 1234;
+        req.header.opcode = opcode.to_u8();
+        req.questions.push(Question::new(domain.to_string(), QueryType::SOA));
+
+        Arc::new(PacketWriter::from(req).write().unwrap())
+    }
+
+    fn code(res: &[u8]) -> ResultCode {
+        ResultCode::from(PacketParser::new(res).parse().unwrap().header.code)
+    }
+
+    #[tokio::test]
+    async fn notifies() {
+        let mut resolver = resolver();
+        let secondary = resolver.add_secondary(SecondaryZone {
+            origin: "example.org".to_string(),
+            primaries: vec!["10.0.0.1:53".parse().unwrap()],
+            file: PathBuf::from("example.org.zone"),
+            key: None
+        }, Duration::from_secs(1)).unwrap();
+
+        let primary = Source {
+            addr: "10.0.0.1:4321".parse().unwrap(),
+            proto: ListenerProtocol::UDP
+        };
+        let stranger = Source {
+            addr: "10.0.0.2:4321".parse().unwrap(),
+            proto: ListenerProtocol::UDP
+        };
+
+        let res = resolver.resolve_from(request(Opcode::NOTIFY, "example.org"), &primary).unwrap();
+        let packet = PacketParser::new(&res[0]).parse().unwrap();
+        assert_eq!(packet.header.code, ResultCode::NOERROR.to_u8());
+        assert_eq!(Opcode::from(packet.header.opcode), Opcode::NOTIFY);
+        assert!(packet.header.response && packet.header.authoritative);
+        tokio::time::timeout(Duration::from_secs(1), secondary.refresh_signal().notified()).await.unwrap();
+
+        let res = resolver.resolve_from(request(Opcode::NOTIFY, "example.org"), &stranger).unwrap();
+        assert_eq!(code(&res[0]), ResultCode::REFUSED);
+        let res = resolver.resolve_from(request(Opcode::NOTIFY, "example.com"), &primary).unwrap();
+        assert_eq!(code(&res[0]), ResultCode::NOTAUTH);
+
+        assert!(resolver.add_secondary(SecondaryZone {
+            origin: "example.com".to_string(),
+            primaries: vec![],
+            file: PathBuf::from("example.com.zone"),
+            key: None
+        }, Duration::from_secs(1)).is_err());
+    }
+
+    #[test]
+    fn unsupported_opcodes() {
+        let resolver = resolver();
+
+        assert_eq!(code(&resolver.resolve(request(Opcode::STATUS, "example.com")).unwrap()), ResultCode::NOTIMP);
+        assert_eq!(code(&resolver.resolve(request(Opcode::UNKNOWN(9), "example.com")).unwrap()), ResultCode::NOTIMP);
+        assert_eq!(code(&resolver.resolve(request(Opcode::QUERY, "example.com")).unwrap()), ResultCode::NOERROR);
+    }
+
+    #[test]
+    fn updates() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("example.com.zone"), "$ORIGIN example.com.
+@ 3600 IN SOA ns1.example.com. admin.example.com. ( 1 7200 3600 1209600 300 )
+@ 3600 IN NS ns1
+ns1 3600 IN A 10.0.0.1
+").unwrap();
+
+        let allowed = Acl::from(&["10.0.0.0/8".to_string()]).unwrap();
+        let resolver = AuthoritativeResolver::new(dir.path().to_path_buf(), false, Keyring::default(), Acl::default(), allowed).unwrap();
+
+        let mut req = PacketParser::new(&request(Opcode::UPDATE, "example.com")).parse().unwrap();
+        req.authorities.push(Record {
+            domain: "host.example.com".to_string(),
+            rtype: QueryType::A,
+            ttl: 300,
+            len: 4,
+            data: RecordData::A("10.0.0.9".parse().unwrap()),
+            ..Default::default()
+        });
+        let req = Arc::new(PacketWriter::from(req).write().unwrap());
+
+        let stranger = Source {
+            addr: "192.168.0.1:4321".parse().unwrap(),
+            proto: ListenerProtocol::UDP
+        };
+        let res = resolver.resolve_from(req.clone(), &stranger).unwrap();
+        assert_eq!(code(&res[0]), ResultCode::REFUSED);
+
+        let client = Source {
+            addr: "10.0.0.5:4321".parse().unwrap(),
+            proto: ListenerProtocol::UDP
+        };
+        let res = resolver.resolve_from(req, &client).unwrap();
+        let packet = PacketParser::new(&res[0]).parse().unwrap();
+        assert_eq!(packet.header.code, ResultCode::NOERROR.to_u8());
+        assert_eq!(Opcode::from(packet.header.opcode), Opcode::UPDATE);
+
+        // the change is written back to the zone file and survives a reload
+        resolver.load_zones().unwrap();
+        let tree = resolver.tree.load();
+        let zone = tree.get("example.com").unwrap();
+        assert_eq!(zone.serial(), 2);
+        assert!(zone.get("host.example.com", QueryType::A).is_some());
+
+        let res = resolver.resolve_from(request(Opcode::UPDATE, "example.org"), &client).unwrap();
+        assert_eq!(code(&res[0]), ResultCode::NOTAUTH);
+    }
+
+    #[test]
+    fn signed_truncation() {
+        let key = Key::new("xfr", "hmac-sha256", "c2VjcmV0c2VjcmV0c2VjcmV0c2VjcmV0").unwrap();
+        let mut resolver = resolver();
+        resolver.keys = Keyring::from(vec![key.clone()]).unwrap();
+
+        let mut src = "$ORIGIN example.org.\n@ 3600 IN SOA ns1 admin ( 1 7200 3600 1209600 300 )\n@ 3600 IN NS ns1\n".to_string();
+        for i in 0..40 {
+            src.push_str(&format!("big 3600 IN TXT \"{} {}\"\n", i, "x".repeat(20)));
+        }
+        resolver.tree.update(|tree| {
+            *tree = ZoneTree::from(vec![parse(src.into_bytes())?])?;
+
+            Ok(())
+        }).unwrap();
+
+        let mut req = Packet::new();
+        req.header.id = 1234;
+        req.questions.push(Question::new("big.example.org".to_string(), QueryType::TXT));
+        let mut client = Transaction::new(Arc::new(key));
+        let req = client.sign(&PacketWriter::from(req).write().unwrap()).unwrap();
+
+        let source = Source {
+            addr: "10.0.0.5:4321".parse().unwrap(),
+            proto: ListenerProtocol::UDP
+        };
+        let res = resolver.resolve_from(Arc::new(req.clone()), &source).unwrap();
+        assert!(res[0].len() <= 512);
+
+        // the truncated answer is signed, so the client can trust it and retry over tcp
+        let mut parser = PacketParser::new(&res[0]);
+        let packet = parser.parse().unwrap();
+        assert!(packet.header.truncation && packet.answers.is_empty());
+        assert_eq!(packet.questions.len(), 1);
+        client.clone().verify(&parser, &packet).unwrap();
+
+        // over tcp the whole answer is sent
+        let source = Source {
+            proto: ListenerProtocol::TCP,
+            ..source
+        };
+        let res = resolver.resolve_from(Arc::new(req), &source).unwrap();
+        let mut parser = PacketParser::new(&res[0]);
+        let packet = parser.parse().unwrap();
+        assert!(!packet.header.truncation && packet.answers.len() == 40);
+        client.clone().verify(&parser, &packet).unwrap();
+    }
+}
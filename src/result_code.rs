@@ -7,9 +7,10 @@ pub enum ResultCode {
     NOTIMP = 4,
     REFUSED = 5,
     YXDOMAIN = 6,
-    XRRSET = 7,
-    NOTAUTH = 8,
-    NOTZONE = 9,
+    YXRRSET = 7,
+    NXRRSET = 8,
+    NOTAUTH = 9,
+    NOTZONE = 10,
 }
 
 impl ResultCode {
@@ -21,9 +22,10 @@ impl ResultCode {
             4 => ResultCode::NOTIMP,
             5 => ResultCode::REFUSED,
             6 => ResultCode::YXDOMAIN,
-            7 => ResultCode::XRRSET,
-            8 => ResultCode::NOTAUTH,
-            9 => ResultCode::NOTZONE,
+            7 => ResultCode::YXRRSET,
+            8 => ResultCode::NXRRSET,
+            9 => ResultCode::NOTAUTH,
+            10 => ResultCode::NOTZONE,
             0 | _ => ResultCode::NOERROR,
         }
     }
@@ -37,9 +39,10 @@ impl ResultCode {
             ResultCode::NOTIMP => 4,
             ResultCode::REFUSED => 5,
             ResultCode::YXDOMAIN => 6,
-            ResultCode::XRRSET => 7,
-            ResultCode::NOTAUTH => 8,
-            ResultCode::NOTZONE => 9,
+            ResultCode::YXRRSET => 7,
+            ResultCode::NXRRSET => 8,
+            ResultCode::NOTAUTH => 9,
+            ResultCode::NOTZONE => 10,
         }
     }
 }
\ No newline at end of file
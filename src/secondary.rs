@@ -0,0 +1,357 @@
+use std::fs;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Instant, SystemTime};
+use anyhow::{anyhow, bail, Result};
+use tokio::select;
+use tokio::sync::Notify;
+use tokio::time::{sleep, Duration};
+use tracing::{error, info, warn};
+use crate::context::SecondaryZone;
+use crate::record::{Record, RecordData};
+use crate::transfer::{fetch_changes, fetch_soa, fetch_zone, serial_gt, soa_serial};
+use crate::zone::parser::Zone;
+use crate::zone::tree::{AuthZone, SharedTree};
+use crate::zone::writer::save_zone;
+
+// how long to wait before trying again when we don't have a copy of the zone,
+// and so no SOA timers to go by.
+const DEFAULT_RETRY: Duration = Duration::from_secs(60);
+
+// keeps the copy of a zone of another server (its primary) up to date. the primary is
+// asked for the zone's serial every `refresh` seconds, or every `retry` seconds after a
+// failure, and the zone is no longer served once the primary hasn't been reached for
+// `expire` seconds (RFC 1034 4.3.5).
+pub struct Secondary {
+    zone: SecondaryZone,
+    tree: Arc<SharedTree>,
+    timeout: Duration,
+    // the SOA record of our copy of the zone
+    soa: Option<Record>,
+    // the last time a primary confirmed our copy is current
+    refreshed: Option<Instant>,
+    // wakes the task up to refresh the zone right away, when a primary notifies us of a change
+    refresh_now: Arc<Notify>
+}
+
+impl Secondary {
+    pub fn new(zone: SecondaryZone, tree: Arc<SharedTree>, timeout: Duration) -> Self {
+        Self {
+            zone,
+            tree,
+            timeout,
+            soa: None,
+            refreshed: None,
+            refresh_now: Arc::new(Notify::new())
+        }
+    }
+
+    pub fn refresh_signal(&self) -> Arc<Notify> {
+        self.refresh_now.clone()
+    }
+
+    pub async fn run(mut self) {
+        if let Err(e) = self.load() {
+            warn!("couldn't load the copy of zone {}: {}", self.zone.origin, e);
+        }
+
+        loop {
+            let wait = match self.refresh().await {
+                Ok(_) => self.timer(|refresh, _, _| refresh),
+                Err(e) => {
+                    warn!("failed to refresh zone {}: {}", self.zone.origin, e);
+                    self.check_expired();
+
+                    self.timer(|_, retry, _| retry)
+                }
+            };
+
+            select! {
+                _ = sleep(wait) => {},
+                _ = self.refresh_now.notified() => {
+                    info!("refreshing zone {} after a notify", self.zone.origin);
+                }
+            }
+        }
+    }
+
+    // serves the copy of the zone kept on disk, if it hasn't expired yet. the file is
+    // as old as the last time a primary was found to have the same zone.
+    pub fn load(&mut self) -> Result<()> {
+        if !self.zone.file.exists() {
+            return Ok(());
+        }
+
+        let zone = AuthZone::from(Zone::parse_file(&self.zone.file)?)?;
+        if zone.origin != self.zone.origin {
+            bail!("{} has zone {} instead", self.zone.file.display(), zone.origin);
+        }
+
+        let age = SystemTime::now().duration_since(fs::metadata(&self.zone.file)?.modified()?).unwrap_or_default();
+
+        self.soa = Some(zone.soa().clone());
+        self.refreshed = Instant::now().checked_sub(age);
+
+        if self.is_expired() {
+            info!("the copy of zone {} has expired, waiting for a transfer", self.zone.origin);
+
+            return Ok(());
+        }
+
+        self.tree.update(|tree| {
+            tree.insert(zone);
+
+            Ok(())
+        })?;
+
+        info!("loaded zone {} from {}", self.zone.origin, self.zone.file.display());
+
+        Ok(())
+    }
+
+    // checks the primaries in order until one of them answers.
+    pub async fn refresh(&mut self) -> Result<()> {
+        let mut res = Err(anyhow!("no primaries"));
+
+        for primary in self.zone.primaries.clone() {
+            res = self.refresh_from(primary).await;
+            if res.is_ok() {
+                break;
+            }
+        }
+
+        res
+    }
+
+    async fn refresh_from(&mut self, primary: SocketAddr) -> Result<()> {
+        let origin = self.zone.origin.clone();
+
+        let soa = fetch_soa(primary, &origin, self.zone.key.clone(), self.timeout).await?;
+        let serial = match soa_serial(&soa) {
+            Some(serial) => serial,
+            None => bail!("invalid SOA record from {}", primary)
+        };
+
+        let current = self.soa.as_ref().and_then(soa_serial);
+        if let Some(current) = current {
+            if self.tree.load().contains(&origin) && !serial_gt(serial, current) {
+                self.confirmed();
+
+                return Ok(());
+            }
+        }
+
+        // ask for the changes since our version first, and for the whole zone if that fails
+        let tree = self.tree.load();
+        let zone = match tree.get(&origin) {
+            Some(current) => match fetch_changes(primary, current, self.zone.key.clone(), self.timeout).await {
+                Ok(Some(zone)) => zone,
+                Ok(None) => {
+                    self.confirmed();
+
+                    return Ok(());
+                },
+                Err(e) => {
+                    warn!("incremental transfer of {} from {} failed, transferring the whole zone: {}", origin, primary, e);
+
+                    fetch_zone(primary, &origin, self.zone.key.clone(), self.timeout).await?
+                }
+            },
+            None => fetch_zone(primary, &origin, self.zone.key.clone(), self.timeout).await?
+        };
+        let zone = AuthZone::from(zone)?;
+
+        if let Err(e) = save_zone(&zone, &self.zone.file) {
+            error!("couldn't save a copy of zone {}: {}", origin, e);
+        }
+
+        self.soa = Some(zone.soa().clone());
+        self.refreshed = Some(Instant::now());
+
+        self.tree.update(|tree| {
+            tree.insert(zone);
+
+            Ok(())
+        })?;
+
+        info!("transferred zone {} serial {} from {}", origin, serial, primary);
+
+        Ok(())
+    }
+
+    // a primary has the same serial as our copy. the file is touched, since its modification
+    // time is how long ago the zone was refreshed after a restart.
+    fn confirmed(&mut self) {
+        self.refreshed = Some(Instant::now());
+
+        let res = fs::File::options().write(true).open(&self.zone.file).and_then(|file| file.set_modified(SystemTime::now()));
+        if let Err(e) = res {
+            warn!("couldn't touch the copy of zone {}: {}", self.zone.origin, e);
+        }
+    }
+
+    // stops serving the zone if the primaries have been unreachable for too long.
+    pub fn check_expired(&mut self) {
+        if !self.is_expired() || !self.tree.load().contains(&self.zone.origin) {
+            return;
+        }
+
+        let res = self.tree.update(|tree| {
+            tree.remove(&self.zone.origin);
+
+            Ok(())
+        });
+
+        match res {
+            Ok(_) => error!("zone {} has expired, it's no longer served", self.zone.origin),
+            Err(e) => error!("couldn't expire zone {}: {}", self.zone.origin, e)
+        }
+    }
+
+    fn is_expired(&self) -> bool {
+        match self.refreshed {
+            Some(refreshed) => refreshed.elapsed() > self.timer(|_, _, expire| expire),
+            None => true
+        }
+    }
+
+    // one of the refresh, retry and expire timers of our copy of the zone.
+    fn timer<F: Fn(u32, u32, u32) -> u32>(&self, f: F) -> Duration {
+        match self.soa.as_ref().map(|soa| &soa.data) {
+            Some(RecordData::SOA { refresh, retry, expire, .. }) => {
+                Duration::from_secs(f(*refresh, *retry, *expire).max(1) as u64)
+            },
+            _ => DEFAULT_RETRY
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::acl::Acl;
+    use crate::context::{CacheContext, Context, ListenerContext, ListenerProtocol, ResolverContext, ServerContext};
+    use crate::resolver::AuthoritativeResolver;
+    use crate::server::{DnsServer, SharedResolver, TcpDnsServer};
+    use crate::tsig::{Key, Keyring};
+    use crate::zone::parser::parse;
+
+    fn zone_file(serial: u32, addr: &str) -> String {
+        format!("$ORIGIN example.com.
+$TTL 3600
+@ IN SOA ns1.example.com. admin.example.com. ( {} 7200 3600 1209600 300 )
+@ IN NS ns1
+ns1 IN A 10.0.0.1
+www IN A {}
+", serial, addr)
+    }
+
+    fn key() -> Key {
+        Key::new("xfr", "hmac-sha512", "c2VjcmV0c2VjcmV0c2VjcmV0c2VjcmV0").unwrap()
+    }
+
+    // runs a primary server on loopback, serving the zones in the given directory to
+    // clients that sign their requests with the test key
+    async fn primary(zones: &std::path::Path) -> (SocketAddr, Arc<SharedTree>) {
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+
+        let resolver = AuthoritativeResolver::new(
+            zones.to_path_buf(),
+            false,
+            Keyring::from(vec![key()]).unwrap(),
+            Acl::from(&["key:xfr".to_string()]).unwrap(),
+            Acl::default()
+        ).unwrap();
+        let tree = resolver.tree();
+
+        let ctx = Arc::new(Context {
+            cache: CacheContext::default(),
+            listener: ListenerContext::new(ListenerProtocol::TCP, "127.0.0.1", port, 4096),
+            server: ServerContext::default(),
+            resolver: ResolverContext::default()
+        });
+
+        let resolver: SharedResolver = Arc::new(Box::new(resolver));
+        tokio::spawn(async move {
+            TcpDnsServer::new(ctx, resolver).start().await
+        });
+        sleep(Duration::from_millis(100)).await;
+
+        (SocketAddr::from(([127, 0, 0, 1], port)), tree)
+    }
+
+    fn www(tree: &SharedTree) -> Option<String> {
+        let tree = tree.load();
+        let records = tree.get("example.com")?.get("www.example.com", crate::query_type::QueryType::A)?.clone();
+
+        match &records[0].data {
+            RecordData::A(addr) => Some(addr.to_string()),
+            _ => None
+        }
+    }
+
+    #[tokio::test]
+    async fn transfer_from_primary() {
+        let dir = tempfile::tempdir().unwrap();
+        let zones = dir.path().join("zones");
+        fs::create_dir(&zones).unwrap();
+        fs::write(zones.join("example.com.zone"), zone_file(1, "10.0.0.2")).unwrap();
+
+        let (addr, primary_tree) = primary(&zones).await;
+
+        let zone = SecondaryZone {
+            origin: "example.com".to_string(),
+            primaries: vec![addr],
+            file: dir.path().join("secondary").join("example.com.zone"),
+            key: Some(Arc::new(key()))
+        };
+
+        // unsigned requests aren't allowed to transfer the zone
+        assert!(fetch_zone(addr, "example.com", None, Duration::from_secs(2)).await.is_err());
+
+        let tree = Arc::new(SharedTree::default());
+        let mut secondary = Secondary::new(zone.clone(), tree.clone(), Duration::from_secs(2));
+        secondary.refresh().await.unwrap();
+
+        assert_eq!(www(&tree).as_deref(), Some("10.0.0.2"));
+        assert!(zone.file.exists());
+
+        // a new serial on the primary is picked up on the next refresh
+        primary_tree.update(|tree| {
+            tree.insert(AuthZone::from(parse(zone_file(2, "10.0.0.3").into_bytes())?)?);
+
+            Ok(())
+        }).unwrap();
+
+        let changes = fetch_changes(addr, tree.load().get("example.com").unwrap(), zone.key.clone(), Duration::from_secs(2)).await.unwrap();
+        assert!(changes.is_some_and(|zone| zone.records.len() == 4));
+
+        secondary.refresh().await.unwrap();
+        assert_eq!(www(&tree).as_deref(), Some("10.0.0.3"));
+
+        // a refresh that finds the same serial makes the copy on disk current again
+        let old = SystemTime::now() - Duration::from_secs(1209601);
+        fs::File::options().write(true).open(&zone.file).unwrap().set_modified(old).unwrap();
+        secondary.refresh().await.unwrap();
+        assert!(fs::metadata(&zone.file).unwrap().modified().unwrap() > old);
+
+        // the copy on disk is served after a restart
+        let restarted = Arc::new(SharedTree::default());
+        Secondary::new(zone.clone(), restarted.clone(), Duration::from_secs(2)).load().unwrap();
+        assert_eq!(www(&restarted).as_deref(), Some("10.0.0.3"));
+
+        // and stops being served once it expires
+        let mut unreachable = Secondary::new(SecondaryZone {
+            primaries: vec![SocketAddr::from(([127, 0, 0, 1], 1))],
+            ..zone
+        }, restarted.clone(), Duration::from_secs(2));
+        unreachable.load().unwrap();
+        assert!(unreachable.refresh().await.is_err());
+        unreachable.check_expired();
+        assert!(restarted.load().contains("example.com"));
+
+        unreachable.refreshed = Instant::now().checked_sub(Duration::from_secs(1209601));
+        unreachable.check_expired();
+        assert!(!restarted.load().contains("example.com"));
+    }
+}
@@ -1,91 +1,511 @@
+use std::io::ErrorKind;
+use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::net::{UdpSocket};
+use tokio::io::AsyncReadExt;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::Mutex;
+use tokio::time::{timeout, Duration};
 use anyhow::{bail, Result};
 use tracing::{error, info};
+use crate::cache::DnsCache;
 use crate::context::{Context, ListenerProtocol, ServerMode};
-use crate::resolver::{AuthoritativeResolver, ForwardResolver, RecursiveResolver, Resolver};
+use crate::packet::Packet;
+use crate::parser::PacketParser;
+use crate::query_type::QueryType;
+use crate::notify::Notifier;
+use crate::snapshot::Snapshotter;
+use crate::resolver::{AuthoritativeResolver, ForwardResolver, RecursiveResolver, Resolver, Source};
+use crate::tcp::write_message;
+use crate::writer::PacketWriter;
+
+const MAX_UDP_PAYLOAD: usize = 512;
 
 pub trait DnsServer {
     async fn start(&self) -> Result<()>;
 }
 
 pub struct UdpDnsServer {
-    pub ctx: Arc<Context>
+    pub ctx: Arc<Context>,
+    resolver: SharedResolver
 }
 
 impl UdpDnsServer {
-    pub fn new(ctx: Context) -> UdpDnsServer {
+    pub fn new(ctx: Arc<Context>, resolver: SharedResolver) -> UdpDnsServer {
         Self {
-            ctx: Arc::new(ctx)
+            ctx,
+            resolver
         }
     }
-}
 
-impl DnsServer for UdpDnsServer {
-    async fn start(&self) -> Result<()> {
-        let resolver: Arc<Box<dyn Resolver + Send + Sync>>;
+    async fn bind(&self) -> Arc<UdpSocket> {
+        match UdpSocket::bind(self.ctx.listener.to_addr()).await {
+            Ok(socket) => Arc::new(socket),
+            Err(err) => {
+                error!("Failed to start server: {}", err);
 
-        match &self.ctx.server.mode {
-            ServerMode::Authoritative { zones, nested_zones, .. } => {
-                resolver = Arc::new(Box::new(AuthoritativeResolver::new(zones.clone(), *nested_zones)?));
-            },
-            ServerMode::Proxy { .. } => {
-                resolver = Arc::new(Box::new(ForwardResolver::new(self.ctx.clone())));
-            },
-            ServerMode::Recursive { .. } => {
-                resolver = Arc::new(Box::new(RecursiveResolver::new(self.ctx.clone())));
+                std::process::exit(1);
             }
         }
-        
-        info!("Running in {} mode", self.ctx.server.mode);
-
-        let listener = &self.ctx.listener;
-
-        match listener.proto {
-            ListenerProtocol::UDP => {
-                let udp_socket = match UdpSocket::bind(self.ctx.listener.to_addr()).await {
-                    Ok(socket) => Arc::new(socket),
-                    Err(err) => {
-                        error!("Failed to start server: {}", err);
-
-                        std::process::exit(1);
-                    }
-                };
-                info!("Listening on {}", self.ctx.listener);
-
-                let mut buf = vec![0; self.ctx.listener.max_packet_buf];
-                loop {
-                    match udp_socket.recv_from(&mut buf).await {
-                        Ok((_size, source)) => {
-                            let buf = Arc::new(buf.clone());
-                            let udp_socket = udp_socket.clone();
-                            let resolver = resolver.clone();
-                            
-                            tokio::spawn(async move {
-                                let res = match resolver.resolve(buf) {
+    }
+
+    async fn serve(&self, udp_socket: Arc<UdpSocket>) -> Result<()> {
+        let mut buf = vec![0; self.ctx.listener.max_packet_buf];
+        loop {
+            match udp_socket.recv_from(&mut buf).await {
+                Ok((size, source)) => {
+                    let buf = Arc::new(buf[..size].to_vec());
+                    let udp_socket = udp_socket.clone();
+                    let resolver = self.resolver.clone();
+
+                    tokio::spawn(async move {
+                        let limit = max_udp_payload(&buf);
+                        let source = Source {
+                            addr: source,
+                            proto: ListenerProtocol::UDP
+                        };
+
+                        let messages = match resolver.resolve_from(buf, &source) {
+                            Ok(messages) => messages,
+                            Err(e) => {
+                                error!("Resolve error: {}", e.to_string());
+
+                                return;
+                            }
+                        };
+
+                        for mut res in messages {
+                            if res.len() > limit {
+                                res = match truncate(&res) {
                                     Ok(res) => res,
                                     Err(e) => {
-                                        error!("Resolve error: {}", e.to_string());
+                                        error!("Failed to truncate the response: {}", e);
 
                                         return;
                                     }
                                 };
+                            }
 
-                                udp_socket.
-                                    send_to(res.as_slice(), source).
-                                    await.
-                                    expect("Failed to send response");
-                            });
+                            udp_socket.
+                                send_to(res.as_slice(), source.addr).
+                                await.
+                                expect("Failed to send response");
                         }
-                        Err(e) => {
-                            error!("Error receiving records: {}", e);
+                    });
+                }
+                Err(e) => {
+                    error!("Error receiving records: {}", e);
+                }
+            }
+        }
+    }
+}
+
+impl DnsServer for UdpDnsServer {
+    async fn start(&self) -> Result<()> {
+        let udp_socket = self.bind().await;
+        info!("Listening on {}", self.ctx.listener);
+
+        self.serve(udp_socket).await
+    }
+}
+
+pub struct TcpDnsServer {
+    pub ctx: Arc<Context>,
+    resolver: SharedResolver
+}
+
+impl TcpDnsServer {
+    pub fn new(ctx: Arc<Context>, resolver: SharedResolver) -> TcpDnsServer {
+        Self {
+            ctx,
+            resolver
+        }
+    }
+
+    async fn bind(&self) -> TcpListener {
+        match TcpListener::bind(self.ctx.listener.to_addr()).await {
+            Ok(listener) => listener,
+            Err(err) => {
+                error!("Failed to start server: {}", err);
+
+                std::process::exit(1);
+            }
+        }
+    }
+
+    async fn serve(&self, tcp_listener: TcpListener) -> Result<()> {
+        let idle_timeout = self.ctx.listener.tcp_idle_timeout;
+        loop {
+            match tcp_listener.accept().await {
+                Ok((stream, source)) => {
+                    let resolver = self.resolver.clone();
+
+                    tokio::spawn(async move {
+                        if let Err(e) = serve_tcp_connection(stream, source, resolver, idle_timeout).await {
+                            error!("Error serving {}: {}", source, e);
                         }
-                    }
+                    });
+                },
+                Err(e) => {
+                    error!("Error accepting connection: {}", e);
+                }
+            }
+        }
+    }
+}
+
+impl DnsServer for TcpDnsServer {
+    async fn start(&self) -> Result<()> {
+        let tcp_listener = self.bind().await;
+        info!("Listening on {}", self.ctx.listener);
+
+        self.serve(tcp_listener).await
+    }
+}
+
+// serves udp and tcp on the same host and port, clients that receive a truncated
+// udp response are expected to retry the query over tcp (RFC 7766 5).
+pub struct UdpTcpDnsServer {
+    udp: UdpDnsServer,
+    tcp: TcpDnsServer
+}
+
+impl UdpTcpDnsServer {
+    pub fn new(ctx: Arc<Context>, resolver: SharedResolver) -> UdpTcpDnsServer {
+        Self {
+            udp: UdpDnsServer::new(ctx.clone(), resolver.clone()),
+            tcp: TcpDnsServer::new(ctx, resolver)
+        }
+    }
+}
+
+impl DnsServer for UdpTcpDnsServer {
+    async fn start(&self) -> Result<()> {
+        let udp_socket = self.udp.bind().await;
+        let tcp_listener = self.tcp.bind().await;
+        info!("Listening on {}", self.udp.ctx.listener);
+
+        tokio::try_join!(
+            self.udp.serve(udp_socket),
+            self.tcp.serve(tcp_listener)
+        )?;
+
+        Ok(())
+    }
+}
+
+pub type SharedResolver = Arc<Box<dyn Resolver + Send + Sync>>;
+
+// the resolver of the server mode, along with what keeps its cache across restarts if it has one
+pub fn new_resolver(ctx: Arc<Context>) -> Result<(SharedResolver, Option<Snapshotter>)> {
+    info!("Running in {} mode", ctx.server.mode);
+
+    let mut snapshotter = None;
+    let resolver: SharedResolver = match &ctx.server.mode {
+        ServerMode::Authoritative {
+            zones,
+            nested_zones,
+            reload_interval,
+            keys,
+            allow_transfer,
+            allow_update,
+            primaries,
+            notify,
+            notify_key,
+            secondaries
+        } => {
+            let mut resolver = AuthoritativeResolver::new(
+                zones.clone(),
+                *nested_zones,
+                keys.clone(),
+                allow_transfer.clone(),
+                allow_update.clone()
+            )?;
+
+            for zone in primaries {
+                resolver.add_primary(zone.clone())?;
+            }
+
+            if !notify.is_empty() {
+                let notifier = Notifier::new(
+                    notify.clone(),
+                    notify_key.clone(),
+                    resolver.tree(),
+                    ctx.server.default_timeout
+                );
+                tokio::spawn(notifier.run(resolver.tree().subscribe()));
+            }
+
+            for zone in secondaries {
+                let secondary = resolver.add_secondary(zone.clone(), ctx.server.default_timeout)?;
+                tokio::spawn(secondary.run());
+            }
+
+            if !zones.as_os_str().is_empty() {
+                tokio::spawn(resolver.reloader(*reload_interval).run());
+            }
+
+            Arc::new(Box::new(resolver))
+        },
+        ServerMode::Proxy { .. } => {
+            let resolver = ForwardResolver::new(ctx.clone());
+            snapshotter = start_cache(&ctx, resolver.cache());
+
+            Arc::new(Box::new(resolver))
+        },
+        ServerMode::Recursive => {
+            let resolver = RecursiveResolver::new(ctx.clone());
+            snapshotter = start_cache(&ctx, resolver.cache());
+
+            Arc::new(Box::new(resolver))
+        }
+    };
+
+    Ok((resolver, snapshotter))
+}
+
+// starts the background tasks of a cache, after filling it from its snapshot if it has one
+fn start_cache(ctx: &Context, cache: Arc<DnsCache>) -> Option<Snapshotter> {
+    tokio::spawn(cache.clone().sweep(ctx.cache.sweep_interval));
+
+    let path = ctx.cache.snapshot.as_ref()?;
+    let snapshotter = Snapshotter::new(cache, path.clone(), ctx.cache.snapshot_interval);
+    match snapshotter.load() {
+        Ok(loaded) => info!("loaded {} cache entries from {}", loaded, path.display()),
+        Err(e) => error!("failed to load the cache from {}, starting with an empty one: {}", path.display(), e)
+    }
+
+    tokio::spawn(snapshotter.clone().run());
+
+    Some(snapshotter)
+}
+
+// the largest response a udp client is able to receive, clients without edns are limited to 512 bytes.
+pub fn max_udp_payload(req: &[u8]) -> usize {
+    match PacketParser::new(req).parse() {
+        Ok(packet) => {
+            match packet.edns() {
+                Some(edns) => MAX_UDP_PAYLOAD.max(edns.udp_payload_size as usize),
+                None => MAX_UDP_PAYLOAD
+            }
+        },
+        Err(_) => MAX_UDP_PAYLOAD
+    }
+}
+
+// replaces a response that doesn't fit in a udp datagram with its header, questions and
+// OPT record, with the TC bit set so the client knows it has to retry over tcp.
+pub fn truncate(res: &[u8]) -> Result<Vec<u8>> {
+    let res = PacketParser::new(res).parse()?;
+
+    let mut packet = Packet::from(&res);
+    packet.header.truncation = true;
+    packet.resources = res.resources.into_iter().filter(|record| {
+        record.rtype == QueryType::OPT
+    }).collect();
+
+    PacketWriter::from(packet).write()
+}
+
+// clients may send several queries on the same connection without waiting for the answers
+// (RFC 7766 6.2.1.1), so each one is resolved on its own task and written back as soon as it's ready.
+async fn serve_tcp_connection(
+    stream: TcpStream,
+    source: SocketAddr,
+    resolver: SharedResolver,
+    idle_timeout: Duration
+) -> Result<()> {
+    let (mut reader, writer) = stream.into_split();
+    let writer = Arc::new(Mutex::new(writer));
+
+    loop {
+        let len = match timeout(idle_timeout, reader.read_u16()).await {
+            Ok(Ok(len)) => len,
+            Ok(Err(e)) => {
+                if e.kind() == ErrorKind::UnexpectedEof {
+                    return Ok(());
                 }
+
+                bail!(e);
             },
-            _ => {
-                bail!("{} is not supported yet", listener.proto)
+            // the client has been idle for too long, close the connection
+            Err(_) => return Ok(())
+        };
+
+        let mut buf = vec![0; len as usize];
+        match timeout(idle_timeout, reader.read_exact(&mut buf)).await {
+            Ok(Ok(_)) => {},
+            Ok(Err(e)) => bail!(e),
+            Err(_) => bail!("timed out while reading a {} bytes message", len)
+        }
+
+        let resolver = resolver.clone();
+        let writer = writer.clone();
+
+        tokio::spawn(async move {
+            let source = Source {
+                addr: source,
+                proto: ListenerProtocol::TCP
+            };
+
+            let messages = match resolver.resolve_from(Arc::new(buf), &source) {
+                Ok(messages) => messages,
+                Err(e) => {
+                    error!("Resolve error: {}", e.to_string());
+
+                    return;
+                }
+            };
+
+            // the messages of a zone transfer must not be interleaved with other answers
+            let mut writer = writer.lock().await;
+            for res in messages {
+                if let Err(e) = write_message(&mut *writer, &res).await {
+                    error!("Failed to send response: {}", e);
+
+                    return;
+                }
             }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use crate::context::{CacheContext, ListenerContext, ResolverContext, ServerContext};
+    use crate::edns::Edns;
+    use crate::question::Question;
+    use crate::record::{Record, RecordData};
+    use crate::tcp::read_message;
+
+    // answers every query with a large response
+    struct LargeResolver;
+
+    impl Resolver for LargeResolver {
+        fn resolve(&self, buf: Arc<Vec<u8>>) -> Result<Vec<u8>> {
+            Ok(response(&buf))
+        }
+    }
+
+    // starts a server on loopback with the given protocol and idle timeout
+    async fn server(proto: ListenerProtocol, idle_timeout: Duration) -> SocketAddr {
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+
+        let mut listener = ListenerContext::new(proto, "127.0.0.1", port, 4096);
+        listener.tcp_idle_timeout = idle_timeout;
+        let ctx = Arc::new(Context {
+            cache: CacheContext::default(),
+            listener,
+            server: ServerContext::default(),
+            resolver: ResolverContext::default()
+        });
+
+        let resolver: SharedResolver = Arc::new(Box::new(LargeResolver));
+        tokio::spawn(async move {
+            UdpTcpDnsServer::new(ctx, resolver).start().await
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn request(edns: Option<u16>) -> Vec<u8> {
+        let mut req = Packet::new();
+        req.header.id = 1234;
+        req.questions.push(Question::new("example.com".to_string(), QueryType::A));
+        if let Some(size) = edns {
+            req.resources.push(Edns::new(size, false).into_record());
         }
+
+        PacketWriter::from(req).write().unwrap()
+    }
+
+    // an answer of about 1600 bytes to the request
+    fn response(req: &[u8]) -> Vec<u8> {
+        let req = PacketParser::new(req).parse().unwrap();
+
+        let mut res = Packet::from(&req);
+        res.header.response = true;
+        res.resources = req.resources;
+        for i in 0..100 {
+            res.answers.push(Record {
+                domain: "example.com".to_string(),
+                rtype: QueryType::A,
+                ttl: 3600,
+                data: RecordData::A(Ipv4Addr::new(10, 0, 0, i)),
+                ..Default::default()
+            });
+        }
+
+        PacketWriter::from(res).write().unwrap()
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn truncation() {
+        // clients without edns only get 512 bytes, the others what they asked for
+        assert_eq!(max_udp_payload(&request(None)), 512);
+        assert_eq!(max_udp_payload(&request(Some(1232))), 1232);
+        assert_eq!(max_udp_payload(&request(Some(100))), 512);
+
+        let addr = server(ListenerProtocol::BOTH, Duration::from_secs(10)).await;
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut buf = vec![0; 65535];
+
+        for (edns, limit) in [(None, 512), (Some(1232), 1232)] {
+            let req = request(edns);
+            assert!(response(&req).len() > limit);
+
+            socket.send_to(&req, addr).await.unwrap();
+            let len = timeout(Duration::from_secs(2), socket.recv(&mut buf)).await.unwrap().unwrap();
+            assert!(len <= limit);
+
+            // the client keeps its question and edns, and is told to retry over tcp
+            let packet = PacketParser::new(&buf[..len]).parse().unwrap();
+            assert!(packet.header.truncation && packet.header.response);
+            assert!(packet.answers.is_empty() && packet.authorities.is_empty());
+            assert_eq!(packet.questions.len(), 1);
+            assert_eq!(packet.questions[0].domain, "example.com");
+            assert_eq!(packet.edns().map(|edns| edns.udp_payload_size), edns);
+        }
+
+        // an answer that fits is sent as it is
+        socket.send_to(&request(Some(4096)), addr).await.unwrap();
+        let len = timeout(Duration::from_secs(2), socket.recv(&mut buf)).await.unwrap().unwrap();
+        let packet = PacketParser::new(&buf[..len]).parse().unwrap();
+        assert!(!packet.header.truncation && packet.answers.len() == 100);
+    }
+
+    #[tokio::test]
+    async fn tcp_connections() {
+        let addr = server(ListenerProtocol::TCP, Duration::from_millis(200)).await;
+
+        // two queries sent one after the other on the same connection are both answered
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        for id in [1, 2] {
+            let mut req = request(None);
+            req[..2].copy_from_slice(&u16::to_be_bytes(id));
+            write_message(&mut stream, &req).await.unwrap();
+        }
+
+        let mut ids = Vec::new();
+        for _ in 0..2 {
+            let res = timeout(Duration::from_secs(2), read_message(&mut stream)).await.unwrap().unwrap();
+            let packet = PacketParser::new(&res).parse().unwrap();
+            assert!(!packet.header.truncation && packet.answers.len() == 100);
+
+            ids.push(packet.header.id);
+        }
+        ids.sort();
+        assert_eq!(ids, vec![1, 2]);
+
+        // a connection that stays idle is closed by the server
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let connected = std::time::Instant::now();
+        let mut buf = [0; 1];
+        let read = timeout(Duration::from_secs(2), stream.read(&mut buf)).await.unwrap();
+        assert!(matches!(read, Ok(0)));
+        assert!(connected.elapsed() >= Duration::from_millis(200));
+    }
+}
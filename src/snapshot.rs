@@ -0,0 +1,267 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+use anyhow::{bail, Result};
+use tokio::time::sleep;
+use tracing::{error, info, warn};
+use crate::cache::{CacheEntry, CacheKey, DnsCache, SavedEntry};
+use crate::packet::Packet;
+use crate::parser::PacketParser;
+use crate::question::Question;
+use crate::result_code::ResultCode;
+use crate::writer::PacketWriter;
+
+// a snapshot starts with these bytes followed by the version of its format
+const MAGIC: &[u8] = b"MYDNS CACHE\n";
+const VERSION: u16 = 1;
+
+// a snapshot is the magic bytes and the version, followed by the entries of the cache from
+// the least to the most recently used. each entry is when it expires in milliseconds since
+// the unix epoch (8 bytes), the ttl it was cached with (4 bytes), and a dns message holding
+// it (2 bytes of length first). the question of the message is the key of the entry, its
+// records are in the answer section and the SOA record of a negative answer in the authority
+// section, with NXDOMAIN as the code of the message when the name doesn't exist.
+pub fn write_snapshot(entries: &[SavedEntry]) -> Result<Vec<u8>> {
+    let mut res = MAGIC.to_vec();
+    res.extend_from_slice(&VERSION.to_be_bytes());
+
+    for saved in entries {
+        let message = match write_entry(saved) {
+            Ok(message) => message,
+            Err(e) => {
+                warn!("left {} {:?} out of the snapshot: {}", saved.key.name, saved.key.rtype, e);
+
+                continue;
+            }
+        };
+        let expires = saved.expires.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+
+        res.extend_from_slice(&expires.to_be_bytes());
+        res.extend_from_slice(&saved.ttl.to_be_bytes());
+        res.extend_from_slice(&(message.len() as u16).to_be_bytes());
+        res.extend_from_slice(&message);
+    }
+
+    Ok(res)
+}
+
+pub fn read_snapshot(buf: &[u8]) -> Result<Vec<SavedEntry>> {
+    let header = MAGIC.len() + 2;
+    if buf.len() < header || !buf.starts_with(MAGIC) {
+        bail!("the file isn't a cache snapshot");
+    }
+
+    let version = u16::from_be_bytes([buf[MAGIC.len()], buf[MAGIC.len() + 1]]);
+    if version != VERSION {
+        bail!("the snapshot is version {}, only version {} can be read", version, VERSION);
+    }
+
+    let mut res = Vec::new();
+    let mut rest = &buf[header..];
+    while !rest.is_empty() {
+        if rest.len() < 14 {
+            bail!("the snapshot ends in the middle of an entry");
+        }
+
+        let expires = u64::from_be_bytes(rest[0..8].try_into()?);
+        let ttl = u32::from_be_bytes(rest[8..12].try_into()?);
+        let len = u16::from_be_bytes(rest[12..14].try_into()?) as usize;
+
+        let message = match rest.get(14..14 + len) {
+            Some(message) => message,
+            None => bail!("the snapshot ends in the middle of an entry")
+        };
+
+        let (key, entry) = read_entry(message)?;
+        res.push(SavedEntry {
+            key,
+            entry,
+            expires: UNIX_EPOCH + Duration::from_millis(expires),
+            ttl
+        });
+
+        rest = &rest[14 + len..];
+    }
+
+    Ok(res)
+}
+
+fn write_entry(saved: &SavedEntry) -> Result<Vec<u8>> {
+    let mut packet = Packet::new();
+    packet.header.response = true;
+    packet.questions.push(Question::new_with_class(saved.key.name.clone(), saved.key.rtype, saved.key.class.clone()));
+
+    match &saved.entry {
+        CacheEntry::Records(records) => packet.answers = records.clone(),
+        CacheEntry::NoData(soa) => packet.authorities.push(soa.clone()),
+        CacheEntry::NxDomain(soa) => {
+            packet.header.code = ResultCode::NXDOMAIN.to_u8();
+            packet.authorities.push(soa.clone());
+        }
+    }
+
+    PacketWriter::from(packet).write()
+}
+
+fn read_entry(message: &[u8]) -> Result<(CacheKey, CacheEntry)> {
+    let mut packet = PacketParser::new(message).parse()?;
+
+    let key = match packet.questions.first() {
+        Some(question) => CacheKey::new(&question.domain, question.qtype, question.qclass.clone()),
+        None => bail!("an entry of the snapshot has no key")
+    };
+
+    let entry = match (ResultCode::from(packet.header.code), packet.authorities.pop()) {
+        (ResultCode::NOERROR, None) if !packet.answers.is_empty() => CacheEntry::Records(packet.answers),
+        (ResultCode::NOERROR, Some(soa)) => CacheEntry::NoData(soa),
+        (ResultCode::NXDOMAIN, Some(soa)) => CacheEntry::NxDomain(soa),
+        _ => bail!("the entry of {} in the snapshot is invalid", key.name)
+    };
+
+    Ok((key, entry))
+}
+
+// keeps the cache in a file so a restart doesn't begin with an empty one. the file is written
+// every `interval`, and by the server once more when it's stopped.
+#[derive(Clone)]
+pub struct Snapshotter {
+    cache: Arc<DnsCache>,
+    path: PathBuf,
+    interval: Duration
+}
+
+impl Snapshotter {
+    pub fn new(cache: Arc<DnsCache>, path: PathBuf, interval: Duration) -> Self {
+        Self {
+            cache,
+            path,
+            interval
+        }
+    }
+
+    // fills the cache with the entries of the snapshot that haven't expired, returning how many
+    // there were. there's nothing to load before the first snapshot is written.
+    pub fn load(&self) -> Result<usize> {
+        let buf = match fs::read(&self.path) {
+            Ok(buf) => buf,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into())
+        };
+
+        let loaded = read_snapshot(&buf)?.into_iter().filter(|saved| self.cache.restore(saved.clone())).count();
+
+        Ok(loaded)
+    }
+
+    // writes the cache to a temporary file first, so a crash never leaves a partial snapshot behind
+    pub fn save(&self) -> Result<usize> {
+        let entries = self.cache.saved_entries();
+        save_snapshot(&write_snapshot(&entries)?, &self.path)?;
+
+        Ok(entries.len())
+    }
+
+    pub async fn run(self) {
+        loop {
+            sleep(self.interval).await;
+
+            self.write();
+        }
+    }
+
+    pub fn write(&self) {
+        match self.save() {
+            Ok(saved) => info!("saved {} cache entries to {}", saved, self.path.display()),
+            Err(e) => error!("failed to save the cache to {}: {}", self.path.display(), e)
+        }
+    }
+}
+
+fn save_snapshot(buf: &[u8], path: &Path) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let mut tmp = path.to_path_buf().into_os_string();
+    tmp.push(".tmp");
+
+    fs::write(&tmp, buf)?;
+    fs::rename(&tmp, path)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use std::time::SystemTime;
+    use crate::context::CacheContext;
+    use crate::query_class::QueryClass;
+    use crate::query_type::QueryType;
+    use crate::record::{Record, RecordData};
+
+    fn record(domain: &str, rtype: QueryType, ttl: u32, data: RecordData) -> Record {
+        Record {
+            domain: domain.to_string(),
+            rtype,
+            rclass: QueryClass::IN,
+            ttl,
+            data,
+            ..Default::default()
+        }
+    }
+
+    fn soa(ttl: u32) -> Record {
+        record("example.com", QueryType::SOA, ttl, RecordData::SOA {
+            mname: "ns1.example.com".to_string(),
+            rname: "admin.example.com".to_string(),
+            serial: 1,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum: 300
+        })
+    }
+
+    #[test]
+    fn snapshot() {
+        let cache = DnsCache::new(&CacheContext::default());
+        cache.insert(vec![
+            record("www.example.com", QueryType::A, 300, RecordData::A(Ipv4Addr::new(10, 0, 0, 1))),
+            record("www.example.com", QueryType::A, 300, RecordData::A(Ipv4Addr::new(10, 0, 0, 2)))
+        ]);
+        cache.insert_nodata("www.example.com", QueryType::MX, QueryClass::IN, soa(300));
+        cache.insert_nxdomain("nope.example.com", QueryClass::IN, soa(300));
+
+        let dir = tempfile::tempdir().unwrap();
+        let snapshotter = Snapshotter::new(Arc::new(cache), dir.path().join("cache"), Duration::from_secs(60));
+        assert_eq!(snapshotter.save().unwrap(), 3);
+
+        let restored = Arc::new(DnsCache::new(&CacheContext::default()));
+        let loaded = Snapshotter::new(restored.clone(), dir.path().join("cache"), Duration::from_secs(60)).load().unwrap();
+        assert_eq!(loaded, 3);
+
+        let answer = restored.lookup(&Question::new("www.example.com".to_string(), QueryType::A)).unwrap();
+        assert_eq!(answer.answers.len(), 2);
+        assert!(answer.answers.iter().all(|record| record.ttl > 295 && record.ttl <= 300));
+
+        let answer = restored.lookup(&Question::new("www.example.com".to_string(), QueryType::MX)).unwrap();
+        assert!(answer.code == ResultCode::NOERROR && answer.answers.is_empty() && answer.authorities.len() == 1);
+
+        let answer = restored.lookup(&Question::new("nope.example.com".to_string(), QueryType::A)).unwrap();
+        assert!(answer.code == ResultCode::NXDOMAIN);
+
+        // entries that expired while the server was down are left out
+        let mut entries = read_snapshot(&fs::read(dir.path().join("cache")).unwrap()).unwrap();
+        entries[0].expires = SystemTime::now() - Duration::from_secs(1);
+        let restored = DnsCache::new(&CacheContext::default());
+        assert_eq!(entries.into_iter().filter(|saved| restored.restore(saved.clone())).count(), 2);
+
+        // snapshots written in another format aren't read
+        let mut buf = write_snapshot(&[]).unwrap();
+        buf[MAGIC.len() + 1] = 2;
+        assert!(read_snapshot(&buf).is_err());
+    }
+}
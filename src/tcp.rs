@@ -0,0 +1,26 @@
+use anyhow::{bail, Result};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use crate::pair::BytesPair;
+
+// every message on a tcp connection is prefixed with a two byte length field (RFC 1035 4.2.2).
+pub async fn write_message<W: AsyncWrite + Unpin>(writer: &mut W, msg: &[u8]) -> Result<()> {
+    if msg.len() > u16::MAX as usize {
+        bail!("message is too large to be sent over tcp ({} bytes)", msg.len());
+    }
+
+    let mut buf = BytesPair::from(msg.len() as u16).bytes();
+    buf.extend_from_slice(msg);
+
+    writer.write_all(buf.as_slice()).await?;
+
+    Ok(())
+}
+
+pub async fn read_message<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Vec<u8>> {
+    let len = reader.read_u16().await?;
+
+    let mut buf = vec![0; len as usize];
+    reader.read_exact(&mut buf).await?;
+
+    Ok(buf)
+}
@@ -0,0 +1,449 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use anyhow::{bail, Result};
+use tokio::net::TcpStream;
+use tokio::time::{timeout, Duration};
+use crate::packet::Packet;
+use crate::parser::PacketParser;
+use crate::query_type::QueryType;
+use crate::question::Question;
+use crate::record::{Record, RecordData};
+use crate::result_code::ResultCode;
+use crate::tcp::{read_message, write_message};
+use crate::tsig::{Key, Transaction};
+use crate::writer::PacketWriter;
+use crate::zone::parser::Zone;
+use crate::zone::tree::AuthZone;
+
+// the size the messages of a zone transfer are filled up to, well below the 64k
+// limit of tcp messages.
+const TRANSFER_MESSAGE_SIZE: usize = 16 * 1024;
+
+const HEADER_SIZE: usize = 12;
+
+// the records of a zone in the order they are sent in a full zone transfer, the
+// SOA record is sent first and once more at the end (RFC 5936 2.2).
+pub fn axfr_records(zone: &AuthZone) -> Vec<Record> {
+    let soa = zone.soa().clone();
+
+    let mut res = vec![soa.clone()];
+    res.extend(zone.records().filter(|record| record.rtype != QueryType::SOA).cloned());
+    res.push(soa);
+
+    res
+}
+
+// the records of an incremental transfer to a client that has the given version of the
+// zone: the current SOA record, the old SOA record, the removed records, the new SOA record
+// and the added records of every change since, and the current SOA record again (RFC 1995 4).
+// clients that are up to date only get the current SOA record. nothing is returned when the
+// journal doesn't go back to the client's version.
+pub fn ixfr_records(zone: &AuthZone, serial: u32) -> Option<Vec<Record>> {
+    let soa = zone.soa().clone();
+    if !serial_gt(zone.serial(), serial) {
+        return Some(vec![soa]);
+    }
+
+    let mut res = vec![soa.clone()];
+    for diff in zone.journal().since(serial)? {
+        res.push(diff.from.clone());
+        res.extend(diff.removed.iter().cloned());
+        res.push(diff.to.clone());
+        res.extend(diff.added.iter().cloned());
+    }
+    res.push(soa);
+
+    Some(res)
+}
+
+// splits the records of a transfer over as many messages as needed. every message
+// has the header of the response, only the first one repeats the question (RFC 5936 2.2.1).
+pub fn write_transfer(res: Packet, records: Vec<Record>) -> Result<Vec<Vec<u8>>> {
+    let mut messages = Vec::new();
+
+    let mut size = PacketWriter::from(Packet::from(&res)).write()?.len();
+    let mut packet = Packet::from(&res);
+
+    for record in records {
+        let len = record_size(&record)?;
+        if !packet.answers.is_empty() && size + len > TRANSFER_MESSAGE_SIZE {
+            messages.push(PacketWriter::from(packet).write()?);
+
+            packet = Packet {
+                header: res.header.clone(),
+                ..Default::default()
+            };
+            size = HEADER_SIZE;
+        }
+
+        size += len;
+        packet.answers.push(record);
+    }
+
+    messages.push(PacketWriter::from(packet).write()?);
+
+    Ok(messages)
+}
+
+// the size of a record written on its own, names can only get shorter once they
+// are compressed against the rest of the message.
+fn record_size(record: &Record) -> Result<usize> {
+    let packet = Packet {
+        answers: vec![record.clone()],
+        ..Default::default()
+    };
+
+    Ok(PacketWriter::from(packet).write()?.len() - HEADER_SIZE)
+}
+
+// asks a primary server for the SOA record of a zone. requests are signed with the
+// key if there's one, and so have to be the answers.
+pub async fn fetch_soa(primary: SocketAddr, origin: &str, key: Option<Arc<Key>>, limit: Duration) -> Result<Record> {
+    let mut stream = connect(primary, limit).await?;
+
+    let req = query(origin, QueryType::SOA);
+    let mut transaction = key.map(Transaction::new);
+    let res = exchange(&mut stream, &req, &mut transaction, limit).await?;
+
+    match res.answers.into_iter().find(|record| record.rtype == QueryType::SOA) {
+        Some(soa) => Ok(soa),
+        None => bail!("{} didn't answer with the SOA record of {}", primary, origin)
+    }
+}
+
+// transfers a whole zone from a primary server, the transfer ends with the
+// second SOA record (RFC 5936 2.2).
+pub async fn fetch_zone(primary: SocketAddr, origin: &str, key: Option<Arc<Key>>, limit: Duration) -> Result<Zone> {
+    let mut stream = connect(primary, limit).await?;
+
+    let req = query(origin, QueryType::AXFR);
+    let mut transaction = key.map(Transaction::new);
+    let mut records = read_transfer(&mut stream, &req, &mut transaction, limit, |records| {
+        records.len() > 1 && records.last().is_some_and(|record| record.rtype == QueryType::SOA)
+    }).await?;
+    records.pop();
+
+    Ok(Zone {
+        origin: origin.to_string(),
+        ttl: None,
+        records,
+        file: None,
+        includes: vec![]
+    })
+}
+
+// asks a primary server for the changes made to a zone since our version of it. the
+// primary may answer with the whole zone instead, nothing is returned if our version
+// is current.
+pub async fn fetch_changes(
+    primary: SocketAddr,
+    current: &AuthZone,
+    key: Option<Arc<Key>>,
+    limit: Duration
+) -> Result<Option<Zone>> {
+    let mut stream = connect(primary, limit).await?;
+
+    let mut req = query(&current.origin, QueryType::IXFR);
+    req.authorities.push(current.soa().clone());
+
+    let serial = current.serial();
+    let mut transaction = key.map(Transaction::new);
+    let records = read_transfer(&mut stream, &req, &mut transaction, limit, |records| {
+        is_ixfr_complete(records, serial)
+    }).await?;
+
+    let latest = soa_serial(&records[0]).unwrap_or_default();
+    if !serial_gt(latest, serial) {
+        return Ok(None);
+    }
+
+    let incremental = records[1].rtype == QueryType::SOA && soa_serial(&records[1]) != Some(latest);
+    if !incremental {
+        let mut records = records;
+        records.pop();
+
+        return Ok(Some(Zone {
+            origin: current.origin.clone(),
+            ttl: None,
+            records,
+            file: None,
+            includes: vec![]
+        }));
+    }
+
+    apply_changes(current, records).map(Some)
+}
+
+// whether all of the records of an incremental transfer have been received. an answer
+// in the AXFR format ends with its second SOA record, the incremental format ends with
+// the third SOA record of the latest version (RFC 1995 4).
+fn is_ixfr_complete(records: &[Record], serial: u32) -> bool {
+    let latest = match records.first().and_then(soa_serial) {
+        Some(latest) => latest,
+        None => return false
+    };
+
+    if records.len() == 1 {
+        return !serial_gt(latest, serial);
+    }
+
+    let last = &records[records.len() - 1];
+    if last.rtype != QueryType::SOA || soa_serial(last) != Some(latest) {
+        return false;
+    }
+
+    let incremental = records[1].rtype == QueryType::SOA && soa_serial(&records[1]) != Some(latest);
+    if !incremental {
+        return true;
+    }
+
+    records.iter().filter(|record| soa_serial(record) == Some(latest)).count() == 3
+}
+
+// applies the changes of an incremental transfer to our version of the zone, they have
+// to start from our serial and follow each other.
+fn apply_changes(current: &AuthZone, records: Vec<Record>) -> Result<Zone> {
+    let mut soa = current.soa().clone();
+    let mut serial = current.serial();
+    let mut zone: Vec<Record> = current.records().filter(|record| {
+        record.rtype != QueryType::SOA
+    }).cloned().collect();
+
+    let mut records = records.into_iter().skip(1).peekable();
+    while let Some(from) = records.next() {
+        if records.peek().is_none() {
+            break;
+        }
+
+        if soa_serial(&from) != Some(serial) {
+            bail!("the changes to {} don't start at serial {}", current.origin, serial);
+        }
+
+        while let Some(removed) = records.next_if(|record| record.rtype != QueryType::SOA) {
+            zone.retain(|record| {
+                !(record.rtype == removed.rtype
+                    && record.data == removed.data
+                    && record.domain.eq_ignore_ascii_case(&removed.domain))
+            });
+        }
+
+        soa = match records.next() {
+            Some(to) => to,
+            None => bail!("the changes to {} end without the new SOA record", current.origin)
+        };
+        serial = soa_serial(&soa).unwrap_or_default();
+
+        while let Some(added) = records.next_if(|record| record.rtype != QueryType::SOA) {
+            zone.push(added);
+        }
+    }
+
+    zone.insert(0, soa);
+
+    Ok(Zone {
+        origin: current.origin.clone(),
+        ttl: None,
+        records: zone,
+        file: None,
+        includes: vec![]
+    })
+}
+
+// serial numbers wrap around, a serial is newer than another one if it's less
+// than 2^31 ahead of it (RFC 1982 3.2).
+pub fn serial_gt(a: u32, b: u32) -> bool {
+    a != b && (a.wrapping_sub(b) as i32) > 0
+}
+
+pub fn soa_serial(soa: &Record) -> Option<u32> {
+    match soa.data {
+        RecordData::SOA { serial, .. } => Some(serial),
+        _ => None
+    }
+}
+
+fn query(origin: &str, qtype: QueryType) -> Packet {
+    let mut req = Packet::new();
+    req.header.id = rand::random();
+    req.questions.push(Question::new(origin.to_string(), qtype));
+
+    req
+}
+
+async fn connect(primary: SocketAddr, limit: Duration) -> Result<TcpStream> {
+    match timeout(limit, TcpStream::connect(primary)).await {
+        Ok(stream) => Ok(stream?),
+        Err(_) => bail!("timed out while connecting to {}", primary)
+    }
+}
+
+async fn exchange(
+    stream: &mut TcpStream,
+    req: &Packet,
+    transaction: &mut Option<Transaction>,
+    limit: Duration
+) -> Result<Packet> {
+    let mut writer = PacketWriter::from(Packet {
+        header: req.header.clone(),
+        questions: req.questions.clone(),
+        authorities: req.authorities.clone(),
+        ..Default::default()
+    });
+
+    let buf = match transaction {
+        Some(transaction) => writer.sign(transaction)?,
+        None => writer.write()?
+    };
+
+    write_message(stream, &buf).await?;
+
+    read_response(stream, req, transaction, limit).await
+}
+
+// reads the messages of a zone transfer until `done` says all of its records are there.
+async fn read_transfer<F: Fn(&[Record]) -> bool>(
+    stream: &mut TcpStream,
+    req: &Packet,
+    transaction: &mut Option<Transaction>,
+    limit: Duration,
+    done: F
+) -> Result<Vec<Record>> {
+    let mut records = exchange(stream, req, transaction, limit).await?.answers;
+    if records.is_empty() {
+        bail!("the transfer of {} has no records", req.questions[0].domain);
+    }
+
+    loop {
+        if records.first().is_some_and(|record| record.rtype != QueryType::SOA) {
+            bail!("the transfer of {} doesn't start with an SOA record", req.questions[0].domain);
+        }
+
+        if done(&records) {
+            if transaction.as_ref().is_some_and(|transaction| !transaction.is_complete()) {
+                bail!("the transfer of {} doesn't end with a signed message", req.questions[0].domain);
+            }
+
+            return Ok(records);
+        }
+
+        records.append(&mut read_response(stream, req, transaction, limit).await?.answers);
+    }
+}
+
+async fn read_response(
+    stream: &mut TcpStream,
+    req: &Packet,
+    transaction: &mut Option<Transaction>,
+    limit: Duration
+) -> Result<Packet> {
+    let buf = match timeout(limit, read_message(stream)).await {
+        Ok(buf) => buf?,
+        Err(_) => bail!("timed out while waiting for an answer")
+    };
+
+    let mut parser = PacketParser::new(&buf);
+    let res = parser.parse()?;
+    if res.header.id != req.header.id {
+        bail!("got an answer with a different id");
+    }
+
+    if let Some(transaction) = transaction {
+        transaction.verify(&parser, &res)?;
+    }
+
+    if res.header.code != ResultCode::NOERROR.to_u8() {
+        bail!("the server answered with {:?}", ResultCode::from(res.header.code));
+    }
+
+    Ok(res)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::PacketParser;
+    use crate::question::Question;
+    use crate::zone::parser::parse;
+    use crate::zone::tree::ZoneTree;
+
+    #[test]
+    fn split_transfers() {
+        let mut src = "$ORIGIN example.com.
+$TTL 3600
+@ IN SOA ns1.example.com. admin.example.com. ( 1 7200 3600 1209600 300 )
+@ IN NS ns1
+ns1 IN A 10.0.0.1
+".to_string();
+        for i in 0..2000 {
+            src.push_str(&format!("host{} IN A 10.1.{}.{}\n", i, i / 256, i % 256));
+        }
+
+        let zone = AuthZone::from(parse(src.into_bytes()).unwrap()).unwrap();
+        let records = axfr_records(&zone);
+        assert_eq!(records.len(), 2004);
+        assert_eq!(records.first().unwrap().rtype, QueryType::SOA);
+        assert_eq!(records.last().unwrap().rtype, QueryType::SOA);
+
+        let mut res = Packet::new();
+        res.header.id = 1234;
+        res.questions.push(Question::new("example.com".to_string(), QueryType::AXFR));
+
+        let messages = write_transfer(res, records).unwrap();
+        assert!(messages.len() > 1);
+
+        let mut count = 0;
+        for (i, message) in messages.iter().enumerate() {
+            assert!(message.len() <= TRANSFER_MESSAGE_SIZE);
+
+            let packet = PacketParser::new(message).parse().unwrap();
+            assert_eq!(packet.header.id, 1234);
+            assert_eq!(packet.questions.len(), if i == 0 { 1 } else { 0 });
+
+            count += packet.answers.len();
+        }
+
+        assert_eq!(count, 2004);
+    }
+
+    fn version(serial: u32, records: &str) -> AuthZone {
+        let src = format!("$ORIGIN example.com.
+$TTL 3600
+@ IN SOA ns1.example.com. admin.example.com. ( {} 7200 3600 1209600 300 )
+@ IN NS ns1
+ns1 IN A 10.0.0.1
+{}", serial, records);
+
+        AuthZone::from(parse(src.into_bytes()).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn incremental_transfers() {
+        let mut tree = ZoneTree::default();
+        tree.insert(version(1, "www IN A 10.0.0.2\n"));
+        tree.insert(version(2, "www IN A 10.0.0.3\nmail IN A 10.0.0.4\n"));
+        tree.insert(version(3, "www IN A 10.0.0.3\n"));
+
+        let zone = tree.get("example.com").unwrap();
+
+        let records = ixfr_records(zone, 1).unwrap();
+        let serials: Vec<Option<u32>> = records.iter().filter(|record| record.rtype == QueryType::SOA).map(soa_serial).collect();
+        assert_eq!(serials, vec![Some(3), Some(1), Some(2), Some(2), Some(3), Some(3)]);
+        assert_eq!(records.len(), 10);
+        assert!(is_ixfr_complete(&records, 1));
+        assert!(!is_ixfr_complete(&records[..9], 1));
+
+        let updated = AuthZone::from(apply_changes(&version(1, "www IN A 10.0.0.2\n"), records).unwrap()).unwrap();
+        assert_eq!(updated.serial(), 3);
+        assert_eq!(updated.records().count(), zone.records().count());
+        assert!(zone.records().all(|record| updated.contains(record)));
+
+        // clients that are up to date only get the SOA record, too old ones get nothing
+        assert_eq!(ixfr_records(zone, 3).unwrap().len(), 1);
+        assert!(is_ixfr_complete(&ixfr_records(zone, 3).unwrap(), 3));
+        assert!(ixfr_records(zone, 0).is_none());
+
+        // changes that don't start from our version are rejected
+        let records = ixfr_records(zone, 2).unwrap();
+        assert!(apply_changes(&version(1, "www IN A 10.0.0.2\n"), records).is_err());
+    }
+}
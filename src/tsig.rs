@@ -0,0 +1,557 @@
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use anyhow::{anyhow, bail, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use sha2::{Sha256, Sha512};
+use crate::packet::Packet;
+use crate::parser::PacketParser;
+use crate::query_class::QueryClass;
+use crate::query_type::QueryType;
+use crate::record::{Record, RecordData};
+use crate::writer::{append_record, write_domain};
+
+// how far apart the clocks of the signer and the verifier may be, in seconds (RFC 8945 10).
+pub const FUDGE: u16 = 300;
+
+// the TSIG error codes (RFC 8945 3), they're sent in the TSIG record next to a NOTAUTH.
+pub const BADSIG: u16 = 16;
+pub const BADKEY: u16 = 17;
+pub const BADTIME: u16 = 18;
+pub const BADTRUNC: u16 = 22;
+
+// a response to a zone transfer may leave up to 99 messages unsigned between two
+// signed ones (RFC 8945 5.3.1).
+const MAX_UNSIGNED_MESSAGES: usize = 99;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Algorithm {
+    HmacSha256,
+    HmacSha512
+}
+
+impl Algorithm {
+    pub fn from(name: &str) -> Option<Self> {
+        match name.trim_end_matches('.').to_lowercase().as_str() {
+            "hmac-sha256" => Some(Self::HmacSha256),
+            "hmac-sha512" => Some(Self::HmacSha512),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::HmacSha256 => "hmac-sha256",
+            Self::HmacSha512 => "hmac-sha512"
+        }
+    }
+
+    // the length of an untruncated MAC
+    fn len(&self) -> usize {
+        match self {
+            Self::HmacSha256 => 32,
+            Self::HmacSha512 => 64
+        }
+    }
+
+    fn mac(&self, secret: &[u8], data: &[u8]) -> Vec<u8> {
+        match self {
+            Self::HmacSha256 => {
+                let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("hmac takes keys of any size");
+                mac.update(data);
+
+                mac.finalize().into_bytes().to_vec()
+            },
+            Self::HmacSha512 => {
+                let mut mac = Hmac::<Sha512>::new_from_slice(secret).expect("hmac takes keys of any size");
+                mac.update(data);
+
+                mac.finalize().into_bytes().to_vec()
+            }
+        }
+    }
+
+    // compares in constant time, a truncated MAC is compared to the start of the full one.
+    fn verify(&self, secret: &[u8], data: &[u8], expected: &[u8]) -> bool {
+        match self {
+            Self::HmacSha256 => {
+                let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("hmac takes keys of any size");
+                mac.update(data);
+
+                mac.verify_truncated_left(expected).is_ok()
+            },
+            Self::HmacSha512 => {
+                let mut mac = Hmac::<Sha512>::new_from_slice(secret).expect("hmac takes keys of any size");
+                mac.update(data);
+
+                mac.verify_truncated_left(expected).is_ok()
+            }
+        }
+    }
+}
+
+// a secret shared with another server or a client, it's known to both by its name.
+#[derive(Clone)]
+pub struct Key {
+    pub name: String,
+    pub algorithm: Algorithm,
+    secret: Vec<u8>
+}
+
+impl Key {
+    pub fn new(name: &str, algorithm: &str, secret: &str) -> Result<Self> {
+        let algorithm = match Algorithm::from(algorithm) {
+            Some(algorithm) => algorithm,
+            None => bail!("key {} uses the unsupported algorithm {}", name, algorithm)
+        };
+
+        let secret = STANDARD.decode(secret.trim()).map_err(|e| {
+            anyhow!("the secret of key {} isn't valid base64: {}", name, e)
+        })?;
+
+        let name = name.trim_end_matches('.').to_lowercase();
+        write_domain(&name)?;
+
+        Ok(Self {
+            name,
+            algorithm,
+            secret
+        })
+    }
+}
+
+// the secret is left out, so keys can be logged
+impl Debug for Key {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Key {{ name: {}, algorithm: {} }}", self.name, self.algorithm.name())
+    }
+}
+
+#[derive(Clone, Default, Debug)]
+pub struct Keyring {
+    keys: HashMap<String, Arc<Key>>
+}
+
+impl Keyring {
+    pub fn from(keys: Vec<Key>) -> Result<Self> {
+        let mut res = Self::default();
+
+        for key in keys {
+            if res.keys.contains_key(&key.name) {
+                bail!("key {} is defined more than once", key.name);
+            }
+
+            res.keys.insert(key.name.clone(), Arc::new(key));
+        }
+
+        Ok(res)
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<Key>> {
+        self.keys.get(&name.trim_end_matches('.').to_lowercase()).cloned()
+    }
+}
+
+// the data of a TSIG record (RFC 8945 4.2), the owner of the record is the key's name.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Tsig {
+    pub algorithm: String,
+    // seconds since the epoch, it's a 48 bit field
+    pub time_signed: u64,
+    pub fudge: u16,
+    pub mac: Vec<u8>,
+    pub original_id: u16,
+    pub error: u16,
+    pub other: Vec<u8>
+}
+
+impl Tsig {
+    pub fn into_record(self, key: &str) -> Record {
+        Record {
+            domain: key.to_string(),
+            rtype: QueryType::TSIG,
+            rclass: QueryClass::ASTERISK,
+            ttl: 0,
+            data: RecordData::TSIG(self),
+            ..Default::default()
+        }
+    }
+}
+
+// why a signed request can't be answered normally (RFC 8945 5.2).
+#[derive(Debug)]
+pub enum TsigError {
+    // the MAC has an invalid length, the client gets a FORMERR
+    Malformed,
+    // the key is unknown or the MAC is wrong, the error is sent without a MAC
+    Unsigned {
+        error: u16,
+        key: String,
+        algorithm: String
+    },
+    // the request is genuine but it's out of the time window or its MAC is truncated,
+    // the error is signed
+    Signed {
+        error: u16,
+        time_signed: u64,
+        transaction: Box<Transaction>
+    }
+}
+
+impl TsigError {
+    // adds the TSIG record telling the client what went wrong to a NOTAUTH response.
+    pub fn sign(self, buf: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            TsigError::Malformed => Ok(buf.to_vec()),
+            TsigError::Unsigned { error, key, algorithm } => {
+                let tsig = Tsig {
+                    algorithm,
+                    time_signed: now(),
+                    fudge: FUDGE,
+                    mac: Vec::new(),
+                    original_id: message_id(buf)?,
+                    error,
+                    other: Vec::new()
+                };
+
+                append_record(buf, &tsig.into_record(&key))
+            },
+            TsigError::Signed { error, time_signed, mut transaction } => {
+                // the client learns our time from a BADTIME error
+                let other = match error {
+                    BADTIME => to_u48(now()),
+                    _ => Vec::new()
+                };
+
+                transaction.sign_with(buf, error, time_signed, other)
+            }
+        }
+    }
+}
+
+// the messages exchanged under one key, a request and its responses. the MAC of every
+// message covers the MAC of the message before it, so they can't be replayed on their own
+// (RFC 8945 4.3).
+#[derive(Clone, Debug)]
+pub struct Transaction {
+    key: Arc<Key>,
+    // the MAC of the last signed message, requests don't have one before them
+    mac: Option<Vec<u8>>,
+    // whether the next response is the first one, later ones only cover the timers
+    first: bool,
+    // the unsigned messages received since the last signed one
+    unsigned: Vec<u8>,
+    unsigned_count: usize
+}
+
+impl Transaction {
+    pub fn new(key: Arc<Key>) -> Self {
+        Self {
+            key,
+            mac: None,
+            first: true,
+            unsigned: Vec::new(),
+            unsigned_count: 0
+        }
+    }
+
+    pub fn key(&self) -> &Key {
+        &self.key
+    }
+
+    pub fn sign(&mut self, buf: &[u8]) -> Result<Vec<u8>> {
+        self.sign_with(buf, 0, now(), Vec::new())
+    }
+
+    fn sign_with(&mut self, buf: &[u8], error: u16, time_signed: u64, other: Vec<u8>) -> Result<Vec<u8>> {
+        let mut tsig = Tsig {
+            algorithm: self.key.algorithm.name().to_string(),
+            time_signed,
+            fudge: FUDGE,
+            mac: Vec::new(),
+            original_id: message_id(buf)?,
+            error,
+            other
+        };
+
+        let full = self.first || self.mac.is_none();
+        tsig.mac = self.key.algorithm.mac(&self.key.secret, &self.digest(buf, &tsig, full)?);
+
+        if self.mac.is_some() {
+            self.first = false;
+        }
+        self.mac = Some(tsig.mac.clone());
+
+        append_record(buf, &tsig.into_record(&self.key.name))
+    }
+
+    // checks the signature of a response, unsigned messages in the middle of a zone transfer
+    // are covered by the next signed one.
+    pub fn verify(&mut self, parser: &PacketParser, res: &Packet) -> Result<()> {
+        let record = match res.tsig() {
+            Some(record) => record,
+            None => {
+                if self.first {
+                    bail!("the answer isn't signed");
+                }
+
+                self.unsigned_count += 1;
+                if self.unsigned_count > MAX_UNSIGNED_MESSAGES {
+                    bail!("too many unsigned messages in a row");
+                }
+
+                self.unsigned.extend_from_slice(parser.bytes());
+
+                return Ok(());
+            }
+        };
+
+        let tsig = match &record.data {
+            RecordData::TSIG(tsig) => tsig,
+            _ => bail!("invalid TSIG record")
+        };
+
+        if tsig.error != 0 {
+            bail!("the server rejected our signature with {}", error_name(tsig.error));
+        }
+
+        if !record.domain.eq_ignore_ascii_case(&self.key.name) {
+            bail!("the answer is signed with key {} instead of {}", record.domain, self.key.name);
+        }
+
+        let unsigned = match parser.unsigned_message() {
+            Some(unsigned) => unsigned,
+            None => bail!("invalid TSIG record")
+        };
+
+        match self.check(&unsigned, tsig) {
+            Ok(_) => Ok(()),
+            Err(TsigError::Signed { error, .. }) | Err(TsigError::Unsigned { error, .. }) => {
+                bail!("the signature of the answer is invalid ({})", error_name(error))
+            },
+            Err(TsigError::Malformed) => bail!("the MAC of the answer has an invalid length")
+        }
+    }
+
+    // whether the last message was signed, a response has to end with a signed message.
+    pub fn is_complete(&self) -> bool {
+        self.unsigned_count == 0
+    }
+
+    fn check(&mut self, unsigned: &[u8], tsig: &Tsig) -> Result<(), TsigError> {
+        let algorithm = self.key.algorithm;
+
+        // a truncated MAC can't be shorter than 10 bytes or half of the full MAC (RFC 8945 5.2.2.1)
+        if tsig.mac.len() > algorithm.len() || tsig.mac.len() < (algorithm.len() / 2).max(10) {
+            return Err(TsigError::Malformed);
+        }
+
+        let full = self.first || self.mac.is_none();
+        let digest = self.digest(unsigned, tsig, full).map_err(|_| TsigError::Malformed)?;
+
+        if !algorithm.verify(&self.key.secret, &digest, &tsig.mac) {
+            return Err(TsigError::Unsigned {
+                error: BADSIG,
+                key: self.key.name.clone(),
+                algorithm: tsig.algorithm.clone()
+            });
+        }
+
+        if self.mac.is_some() {
+            self.first = false;
+        }
+        self.mac = Some(tsig.mac.clone());
+        self.unsigned.clear();
+        self.unsigned_count = 0;
+
+        let error = if now().abs_diff(tsig.time_signed) > tsig.fudge as u64 {
+            BADTIME
+        } else if tsig.mac.len() < algorithm.len() {
+            BADTRUNC
+        } else {
+            return Ok(());
+        };
+
+        Err(TsigError::Signed {
+            error,
+            time_signed: tsig.time_signed,
+            transaction: Box::new(Transaction {
+                key: self.key.clone(),
+                mac: self.mac.clone(),
+                first: self.first,
+                unsigned: Vec::new(),
+                unsigned_count: 0
+            })
+        })
+    }
+
+    // what the MAC of a message is computed over (RFC 8945 4.3). the first message of a
+    // response covers all of the TSIG variables, the later ones only the timers.
+    fn digest(&self, message: &[u8], tsig: &Tsig, full: bool) -> Result<Vec<u8>> {
+        let mut res = Vec::new();
+
+        if let Some(mac) = &self.mac {
+            res.extend_from_slice(&(mac.len() as u16).to_be_bytes());
+            res.extend_from_slice(mac);
+        }
+
+        res.extend_from_slice(&self.unsigned);
+        res.extend_from_slice(message);
+
+        if full {
+            res.extend_from_slice(&write_domain(&self.key.name)?);
+            res.extend_from_slice(&QueryClass::ASTERISK.to_num().to_be_bytes());
+            res.extend_from_slice(&0u32.to_be_bytes());
+            res.extend_from_slice(&write_domain(&tsig.algorithm.to_lowercase())?);
+        }
+
+        res.extend_from_slice(&to_u48(tsig.time_signed));
+        res.extend_from_slice(&tsig.fudge.to_be_bytes());
+
+        if full {
+            res.extend_from_slice(&tsig.error.to_be_bytes());
+            res.extend_from_slice(&(tsig.other.len() as u16).to_be_bytes());
+            res.extend_from_slice(&tsig.other);
+        }
+
+        Ok(res)
+    }
+}
+
+// checks the signature of a request, the returned transaction signs the answers to it.
+pub fn verify_request(parser: &PacketParser, req: &Packet, keys: &Keyring) -> Result<Option<Transaction>, TsigError> {
+    let record = match req.tsig() {
+        Some(record) => record,
+        None => return Ok(None)
+    };
+
+    let (tsig, unsigned) = match (&record.data, parser.unsigned_message()) {
+        (RecordData::TSIG(tsig), Some(unsigned)) => (tsig, unsigned),
+        _ => return Err(TsigError::Malformed)
+    };
+
+    let key = match keys.get(&record.domain) {
+        Some(key) if Algorithm::from(&tsig.algorithm) == Some(key.algorithm) => key,
+        _ => return Err(TsigError::Unsigned {
+            error: BADKEY,
+            key: record.domain.clone(),
+            algorithm: tsig.algorithm.clone()
+        })
+    };
+
+    let mut transaction = Transaction::new(key);
+    transaction.check(&unsigned, tsig)?;
+
+    Ok(Some(transaction))
+}
+
+pub fn error_name(error: u16) -> String {
+    match error {
+        BADSIG => "BADSIG".to_string(),
+        BADKEY => "BADKEY".to_string(),
+        BADTIME => "BADTIME".to_string(),
+        BADTRUNC => "BADTRUNC".to_string(),
+        _ => format!("error {}", error)
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or_default()
+}
+
+fn to_u48(value: u64) -> Vec<u8> {
+    value.to_be_bytes()[2..].to_vec()
+}
+
+fn message_id(buf: &[u8]) -> Result<u16> {
+    match buf.get(..2) {
+        Some(id) => Ok(u16::from_be_bytes([id[0], id[1]])),
+        None => bail!("the message is too short to be signed")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::question::Question;
+    use crate::writer::PacketWriter;
+
+    fn key(name: &str) -> Arc<Key> {
+        Arc::new(Key::new(name, "hmac-sha256", "c2VjcmV0c2VjcmV0c2VjcmV0c2VjcmV0").unwrap())
+    }
+
+    fn request() -> Vec<u8> {
+        let mut req = Packet::new();
+        req.header.id = 1234;
+        req.questions.push(Question::new("example.com".to_string(), QueryType::AXFR));
+
+        PacketWriter::from(req).write().unwrap()
+    }
+
+    #[test]
+    fn sign_and_verify() {
+        let keys = Keyring::from(vec![Key::new("xfr", "hmac-sha256", "c2VjcmV0c2VjcmV0c2VjcmV0c2VjcmV0").unwrap()]).unwrap();
+
+        let mut client = Transaction::new(key("xfr"));
+        let buf = client.sign(&request()).unwrap();
+
+        let mut parser = PacketParser::new(&buf);
+        let req = parser.parse().unwrap();
+        assert_eq!(req.header.resource_count, 1);
+        let mut server = verify_request(&parser, &req, &keys).unwrap().unwrap();
+
+        // every message of the response is chained to the one before it
+        let mut res = Packet::from(&req);
+        res.resources.clear();
+        res.header.response = true;
+        let res = PacketWriter::from(res).write().unwrap();
+
+        for _ in 0..3 {
+            let buf = server.sign(&res).unwrap();
+            let mut parser = PacketParser::new(&buf);
+            let packet = parser.parse().unwrap();
+
+            client.verify(&parser, &packet).unwrap();
+        }
+
+        // the first answer has to be signed
+        let mut unsigned = Transaction::new(key("xfr"));
+        unsigned.sign(&request()).unwrap();
+        assert!(unsigned.verify(&PacketParser::new(&res), &PacketParser::new(&res).parse().unwrap()).is_err());
+
+        // a changed message or an unknown key is rejected
+        let mut tampered = Transaction::new(key("xfr")).sign(&request()).unwrap();
+        tampered[3] ^= 0x01;
+        let mut parser = PacketParser::new(&tampered);
+        let packet = parser.parse().unwrap();
+        assert!(matches!(verify_request(&parser, &packet, &keys), Err(TsigError::Unsigned { error: BADSIG, .. })));
+
+        let buf = Transaction::new(key("other")).sign(&request()).unwrap();
+        let mut parser = PacketParser::new(&buf);
+        let packet = parser.parse().unwrap();
+        assert!(matches!(verify_request(&parser, &packet, &keys), Err(TsigError::Unsigned { error: BADKEY, .. })));
+    }
+
+    #[test]
+    fn stale_signatures() {
+        let keys = Keyring::from(vec![Key::new("xfr", "hmac-sha256", "c2VjcmV0c2VjcmV0c2VjcmV0c2VjcmV0").unwrap()]).unwrap();
+
+        let buf = Transaction::new(key("xfr")).sign_with(&request(), 0, now() - 3600, Vec::new()).unwrap();
+        let mut parser = PacketParser::new(&buf);
+        let packet = parser.parse().unwrap();
+
+        match verify_request(&parser, &packet, &keys) {
+            Err(e @ TsigError::Signed { error: BADTIME, .. }) => {
+                let res = e.sign(&request()).unwrap();
+                let packet = PacketParser::new(&res).parse().unwrap();
+
+                assert!(matches!(&packet.tsig().unwrap().data, RecordData::TSIG(tsig)
+                    if tsig.error == BADTIME && tsig.other.len() == 6 && !tsig.mac.is_empty()));
+            },
+            res => panic!("expected BADTIME, got {:?}", res)
+        }
+    }
+}
@@ -0,0 +1,331 @@
+use crate::packet::Packet;
+use crate::query_class::QueryClass;
+use crate::query_type::QueryType;
+use crate::record::{Record, RecordData};
+use crate::result_code::ResultCode;
+use crate::transfer::{serial_gt, soa_serial};
+use crate::zone::parser::Zone;
+use crate::zone::tree::{is_subdomain, AuthZone};
+
+// applies a dynamic update (RFC 2136) to a zone. the zone section of the request names
+// the zone, the answer section holds the prerequisites and the authority section the
+// changes. returns the new version of the zone, or none if nothing has changed.
+pub fn apply(zone: &AuthZone, req: &Packet) -> Result<Option<Zone>, ResultCode> {
+    let mut records: Vec<Record> = zone.records().cloned().collect();
+
+    check_prerequisites(zone, &records, &req.answers)?;
+    prescan(zone, &req.authorities)?;
+
+    let mut changed = false;
+    let mut soa_updated = false;
+
+    for update in &req.authorities {
+        let name = update.domain.to_lowercase();
+
+        match update.rclass {
+            QueryClass::ASTERISK => {
+                let before = records.len();
+
+                records.retain(|record| {
+                    if !record.domain.eq_ignore_ascii_case(&name) {
+                        return true;
+                    }
+
+                    // the SOA and NS records of the apex are never deleted as a whole
+                    if name == zone.origin && matches!(record.rtype, QueryType::SOA | QueryType::NS) {
+                        return true;
+                    }
+
+                    update.rtype != QueryType::ASTERISK && record.rtype != update.rtype
+                });
+
+                changed |= records.len() != before;
+            },
+            QueryClass::NONE => {
+                if update.rtype == QueryType::SOA {
+                    continue;
+                }
+
+                // the last NS record of the apex is kept
+                if name == zone.origin && update.rtype == QueryType::NS && rrset(&records, &name, QueryType::NS).len() == 1 {
+                    continue;
+                }
+
+                let before = records.len();
+                records.retain(|record| !is_same_record(record, update));
+
+                changed |= records.len() != before;
+            },
+            _ => {
+                if records.iter().any(|record| is_same_record(record, update) && record.ttl == update.ttl) {
+                    continue;
+                }
+
+                let types: Vec<QueryType> = rrset_types(&records, &name);
+
+                // a name with an alias can't have other records (RFC 2136 3.4.2.2)
+                if update.rtype == QueryType::CNAME && types.iter().any(|rtype| *rtype != QueryType::CNAME) {
+                    continue;
+                }
+                if update.rtype != QueryType::CNAME && types.contains(&QueryType::CNAME) {
+                    continue;
+                }
+
+                match update.rtype {
+                    QueryType::SOA => {
+                        let current = zone.serial();
+                        match soa_serial(update) {
+                            Some(serial) if name == zone.origin && serial_gt(serial, current) => {},
+                            _ => continue
+                        }
+
+                        records.retain(|record| record.rtype != QueryType::SOA);
+                        soa_updated = true;
+                    },
+                    QueryType::CNAME => {
+                        records.retain(|record| {
+                            !(record.rtype == QueryType::CNAME && record.domain.eq_ignore_ascii_case(&name))
+                        });
+                    },
+                    _ => {
+                        // the same record again only changes its ttl
+                        if let Some(record) = records.iter_mut().find(|record| is_same_record(record, update)) {
+                            if record.ttl != update.ttl {
+                                record.ttl = update.ttl;
+                                changed = true;
+                            }
+
+                            continue;
+                        }
+                    }
+                }
+
+                let mut record = update.clone();
+                record.domain = name;
+                records.push(record);
+                changed = true;
+            }
+        }
+    }
+
+    if !changed {
+        return Ok(None);
+    }
+
+    if !soa_updated {
+        for record in records.iter_mut() {
+            if let RecordData::SOA { serial, .. } = &mut record.data {
+                *serial = serial.wrapping_add(1);
+            }
+        }
+    }
+
+    Ok(Some(Zone {
+        origin: zone.origin.clone(),
+        ttl: None,
+        records,
+        file: zone.file.clone(),
+        includes: vec![]
+    }))
+}
+
+// every prerequisite has to hold for the update to go ahead (RFC 2136 3.2).
+fn check_prerequisites(zone: &AuthZone, records: &[Record], prerequisites: &[Record]) -> Result<(), ResultCode> {
+    // prerequisites on the values of RRsets, grouped by owner name and type
+    let mut values: Vec<(String, QueryType, Vec<&Record>)> = Vec::new();
+
+    for prerequisite in prerequisites {
+        let name = prerequisite.domain.to_lowercase();
+
+        if prerequisite.ttl != 0 {
+            return Err(ResultCode::FORMERR);
+        }
+
+        if !is_subdomain(&name, &zone.origin) {
+            return Err(ResultCode::NOTZONE);
+        }
+
+        match prerequisite.rclass {
+            QueryClass::ASTERISK | QueryClass::NONE if prerequisite.len != 0 => {
+                return Err(ResultCode::FORMERR);
+            },
+            QueryClass::ASTERISK => {
+                let exists = match prerequisite.rtype {
+                    QueryType::ASTERISK => !rrset_types(records, &name).is_empty(),
+                    rtype => !rrset(records, &name, rtype).is_empty()
+                };
+
+                if !exists {
+                    return Err(match prerequisite.rtype {
+                        QueryType::ASTERISK => ResultCode::NXDOMAIN,
+                        _ => ResultCode::NXRRSET
+                    });
+                }
+            },
+            QueryClass::NONE => {
+                let exists = match prerequisite.rtype {
+                    QueryType::ASTERISK => !rrset_types(records, &name).is_empty(),
+                    rtype => !rrset(records, &name, rtype).is_empty()
+                };
+
+                if exists {
+                    return Err(match prerequisite.rtype {
+                        QueryType::ASTERISK => ResultCode::YXDOMAIN,
+                        _ => ResultCode::YXRRSET
+                    });
+                }
+            },
+            QueryClass::IN => {
+                match values.iter_mut().find(|(other, rtype, _)| *other == name && *rtype == prerequisite.rtype) {
+                    Some((_, _, expected)) => expected.push(prerequisite),
+                    None => values.push((name, prerequisite.rtype, vec![prerequisite]))
+                }
+            },
+            _ => return Err(ResultCode::FORMERR)
+        }
+    }
+
+    // the RRsets have to match exactly, ttls aside
+    for (name, rtype, expected) in values {
+        let actual = rrset(records, &name, rtype);
+
+        let matches = actual.iter().all(|record| expected.iter().any(|other| other.data == record.data))
+            && expected.iter().all(|other| actual.iter().any(|record| other.data == record.data));
+
+        if !matches {
+            return Err(ResultCode::NXRRSET);
+        }
+    }
+
+    Ok(())
+}
+
+// checks the whole update before any of it is applied (RFC 2136 3.4.1.3).
+fn prescan(zone: &AuthZone, updates: &[Record]) -> Result<(), ResultCode> {
+    for update in updates {
+        if !is_subdomain(&update.domain.to_lowercase(), &zone.origin) {
+            return Err(ResultCode::NOTZONE);
+        }
+
+        let valid = match update.rclass {
+            QueryClass::IN => !is_meta(update.rtype) && update.len != 0,
+            QueryClass::ASTERISK => {
+                update.ttl == 0 && update.len == 0 && (update.rtype == QueryType::ASTERISK || !is_meta(update.rtype))
+            },
+            QueryClass::NONE => update.ttl == 0 && !is_meta(update.rtype),
+            _ => false
+        };
+
+        if !valid {
+            return Err(ResultCode::FORMERR);
+        }
+    }
+
+    Ok(())
+}
+
+fn is_meta(rtype: QueryType) -> bool {
+    matches!(rtype, QueryType::OPT | QueryType::IXFR | QueryType::AXFR | QueryType::MAILB | QueryType::MAILA | QueryType::ASTERISK)
+}
+
+fn is_same_record(record: &Record, other: &Record) -> bool {
+    record.rtype == other.rtype && record.data == other.data && record.domain.eq_ignore_ascii_case(&other.domain)
+}
+
+fn rrset<'a>(records: &'a [Record], name: &str, rtype: QueryType) -> Vec<&'a Record> {
+    records.iter().filter(|record| record.rtype == rtype && record.domain.eq_ignore_ascii_case(name)).collect()
+}
+
+fn rrset_types(records: &[Record], name: &str) -> Vec<QueryType> {
+    records.iter().filter(|record| record.domain.eq_ignore_ascii_case(name)).map(|record| record.rtype).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use crate::question::Question;
+    use crate::zone::parser::parse;
+
+    fn zone() -> AuthZone {
+        let src = "$ORIGIN example.com.
+$TTL 3600
+@ IN SOA ns1.example.com. admin.example.com. ( 1 7200 3600 1209600 300 )
+@ IN NS ns1
+ns1 IN A 10.0.0.1
+www IN A 10.0.0.2
+alias IN CNAME www
+";
+
+        AuthZone::from(parse(src.as_bytes().to_vec()).unwrap()).unwrap()
+    }
+
+    fn record(name: &str, rclass: QueryClass, rtype: QueryType, ttl: u32, data: Option<RecordData>) -> Record {
+        Record {
+            domain: name.to_string(),
+            rtype,
+            rclass,
+            ttl,
+            len: if data.is_some() { 4 } else { 0 },
+            data: data.unwrap_or(RecordData::UNKNOWN(vec![]))
+        }
+    }
+
+    fn a(addr: [u8; 4]) -> Option<RecordData> {
+        Some(RecordData::A(Ipv4Addr::from(addr)))
+    }
+
+    fn update(prerequisites: Vec<Record>, updates: Vec<Record>) -> Packet {
+        let mut req = Packet::new();
+        req.questions.push(Question::new("example.com".to_string(), QueryType::SOA));
+        req.answers = prerequisites;
+        req.authorities = updates;
+
+        req
+    }
+
+    #[test]
+    fn prerequisites() {
+        let zone = zone();
+        let check = |prerequisite: Record| apply(&zone, &update(vec![prerequisite], vec![])).map(|_| ());
+
+        assert_eq!(check(record("www.example.com", QueryClass::ASTERISK, QueryType::ASTERISK, 0, None)), Ok(()));
+        assert_eq!(check(record("ftp.example.com", QueryClass::ASTERISK, QueryType::ASTERISK, 0, None)), Err(ResultCode::NXDOMAIN));
+        assert_eq!(check(record("www.example.com", QueryClass::ASTERISK, QueryType::AAAA, 0, None)), Err(ResultCode::NXRRSET));
+        assert_eq!(check(record("www.example.com", QueryClass::NONE, QueryType::ASTERISK, 0, None)), Err(ResultCode::YXDOMAIN));
+        assert_eq!(check(record("www.example.com", QueryClass::NONE, QueryType::A, 0, None)), Err(ResultCode::YXRRSET));
+        assert_eq!(check(record("www.example.com", QueryClass::IN, QueryType::A, 0, a([10, 0, 0, 2]))), Ok(()));
+        assert_eq!(check(record("www.example.com", QueryClass::IN, QueryType::A, 0, a([10, 0, 0, 3]))), Err(ResultCode::NXRRSET));
+        assert_eq!(check(record("www.example.org", QueryClass::ASTERISK, QueryType::ASTERISK, 0, None)), Err(ResultCode::NOTZONE));
+        assert_eq!(check(record("www.example.com", QueryClass::ASTERISK, QueryType::ASTERISK, 300, None)), Err(ResultCode::FORMERR));
+    }
+
+    #[test]
+    fn updates() {
+        let zone = zone();
+
+        let updated = apply(&zone, &update(vec![], vec![
+            record("host.example.com", QueryClass::IN, QueryType::A, 300, a([10, 0, 0, 5])),
+            record("www.example.com", QueryClass::NONE, QueryType::A, 0, a([10, 0, 0, 2])),
+            record("alias.example.com", QueryClass::IN, QueryType::A, 300, a([10, 0, 0, 6])),
+            record("example.com", QueryClass::ASTERISK, QueryType::ASTERISK, 0, None),
+        ])).unwrap().unwrap();
+        let updated = AuthZone::from(updated).unwrap();
+
+        assert_eq!(updated.serial(), 2);
+        assert!(updated.get("host.example.com", QueryType::A).is_some());
+        assert!(updated.get("www.example.com", QueryType::A).is_none());
+        // records next to an alias are ignored, the apex SOA and NS are kept
+        assert!(updated.get("alias.example.com", QueryType::A).is_none());
+        assert!(updated.get("example.com", QueryType::NS).is_some());
+
+        // the last NS record of the apex can't be deleted, so nothing changes
+        let ns = record("example.com", QueryClass::NONE, QueryType::NS, 0, Some(RecordData::NS("ns1.example.com".to_string())));
+        assert!(apply(&updated, &update(vec![], vec![ns])).unwrap().is_none());
+
+        assert_eq!(
+            apply(&zone, &update(vec![], vec![record("www.example.com", QueryClass::IN, QueryType::ASTERISK, 0, None)])).err(),
+            Some(ResultCode::FORMERR)
+        );
+    }
+}
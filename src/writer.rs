@@ -1,25 +1,23 @@
-use std::cell::RefCell;
 use std::collections::HashMap;
 use anyhow::{bail, Result};
-use crate::bytes_util::BytesUtil;
 use crate::header::Header;
+use crate::name::{escape_label, labels};
 use crate::packet::Packet;
-use crate::pair::BytesPair;
+use crate::parser::MAX_MESSAGE_SIZE;
 use crate::record::{Record, RecordData};
+use crate::tsig::Transaction;
 
 #[derive(Default)]
 pub struct PacketWriter {
     pub packet: Packet,
     buf: Vec<u8>,
-    offset: u16,
-    domains_buf: RefCell<HashMap<String, u16>>
+    // offsets of the names (and their suffixes) written so far, used for message compression
+    domains_buf: HashMap<String, u16>
 }
 
 impl PacketWriter {
     pub fn new() -> PacketWriter {
         PacketWriter {
-            buf: vec![0],
-            offset: 0,
             ..Default::default()
         }
     }
@@ -27,104 +25,125 @@ impl PacketWriter {
     pub fn from(packet: Packet) -> PacketWriter {
         PacketWriter {
             packet,
-            buf: Vec::from([0u8; 512]),
-            offset: 0,
-            domains_buf: RefCell::new(HashMap::new())
+            buf: Vec::with_capacity(512),
+            domains_buf: HashMap::new()
         }
     }
 
     pub fn write(&mut self) -> Result<Vec<u8>> {
         // reset the buffer before any writes to the buf
         self.buf.clear();
+        self.domains_buf.clear();
 
-        self.write_header()?;
-        self.write_questions()?;
-        self.write_bytes(self.write_records(&self.packet.answers)?)?;
-        self.write_bytes(self.write_records(&self.packet.authorities)?)?;
-        self.write_bytes(self.write_records(&self.packet.resources)?)?;
+        let packet = std::mem::take(&mut self.packet);
+        let res = self.write_packet(&packet);
+        self.packet = packet;
+
+        res?;
 
         Ok(self.buf.clone())
     }
 
-    fn write_header(&mut self) -> Result<()> {
-        self.seek(0)?;
+    // writes the packet and signs it, the TSIG record is added at the end of it.
+    pub fn sign(&mut self, transaction: &mut Transaction) -> Result<Vec<u8>> {
+        let buf = self.write()?;
+
+        transaction.sign(&buf)
+    }
+
+    fn write_packet(&mut self, packet: &Packet) -> Result<()> {
+        self.write_header(packet)?;
+        self.write_questions(packet)?;
+        self.write_records(&packet.answers)?;
+        self.write_records(&packet.authorities)?;
+        self.write_records(&packet.resources)?;
 
-        self.write_u16(self.packet.header.id)?;
+        Ok(())
+    }
 
-        let flags = Self::write_header_flags(&self.packet.header);
+    // section counts are taken from the packet itself, so they always match what is written
+    fn write_header(&mut self, packet: &Packet) -> Result<()> {
+        self.write_u16(packet.header.id)?;
+
+        let flags = Self::write_header_flags(&packet.header);
         self.write_byte(flags.0)?;
         self.write_byte(flags.1)?;
 
-        self.write_u16(self.packet.header.question_count)?;
-        self.write_u16(self.packet.header.answer_count)?;
-        self.write_u16(self.packet.header.authority_count)?;
-        self.write_u16(self.packet.header.resource_count)?;
+        self.write_u16(packet.questions.len() as u16)?;
+        self.write_u16(packet.answers.len() as u16)?;
+        self.write_u16(packet.authorities.len() as u16)?;
+        self.write_u16(packet.resources.len() as u16)?;
 
         Ok(())
     }
 
-    fn write_questions(&mut self) -> Result<()> {
-        if self.offset > 12 {
-            self.seek(12)?;
+    fn write_questions(&mut self, packet: &Packet) -> Result<()> {
+        for question in &packet.questions {
+            self.write_domain(&question.domain)?;
+            self.write_u16(question.qtype.to_num())?;
+            self.write_u16(question.qclass.to_num())?;
         }
 
-        let mut buf: Vec<u8> = Vec::new();
-        for question in &self.packet.questions {
-            let domain = self.write_domain(&question.domain)?;
-            for byte in domain {
-                buf.push(byte);
-            }
-
-            let pair = BytesPair::from(question.qtype.to_num());
-            buf.append(&mut pair.bytes());
+        Ok(())
+    }
 
-            let pair = BytesPair::from(question.qclass.to_num());
-            buf.append(&mut pair.bytes());
+    fn write_records(&mut self, records: &Vec<Record>) -> Result<()> {
+        for record in records {
+            self.write_record(record)?;
         }
 
-        self.write_bytes(buf)?;
-
         Ok(())
     }
 
-    fn write_records(&self, records: &Vec<Record>) -> Result<Vec<u8>> {
-        let mut res = Vec::new();
+    fn write_record(&mut self, record: &Record) -> Result<()> {
+        self.write_domain(&record.domain)?;
 
-        for record in records {
-            let mut bytes = self.write_record(&record)?;
-            res.append(&mut bytes);
+        self.write_u16(record.rtype.to_num())?;
+        match &record.data {
+            RecordData::OPT(edns) => {
+                self.write_u16(edns.udp_payload_size)?;
+                self.write_u32(edns.ttl())?;
+            },
+            _ => {
+                self.write_u16(record.rclass.to_num())?;
+                self.write_u32(record.ttl)?;
+            }
         }
 
-        Ok(res)
-    }
-    
-    fn write_record(&self, record: &Record) -> Result<Vec<u8>> {
-        let mut res = Vec::new();
+        // reserve the rdlength field, it's filled in once the data is written
+        let len_offset = self.buf.len();
+        self.write_u16(0)?;
 
-        let mut bytes = self.write_domain(&record.domain)?;
-        res.append(&mut bytes);
+        self.write_record_data(&record.data)?;
 
-        res.append(&mut BytesPair::from(record.rtype.to_num()).bytes());
-        res.append(&mut BytesPair::from(record.rclass.to_num()).bytes());
+        let len = self.buf.len() - len_offset - 2;
+        if len > u16::MAX as usize {
+            bail!("record data of {} is too large", record.domain);
+        }
 
-        res.append(&mut BytesUtil::from_u32(record.ttl).to_vec());
+        self.buf[len_offset] = (len >> 8) as u8;
+        self.buf[len_offset + 1] = (len & 0xFF) as u8;
 
-        let mut data = self.write_record_data(&record.data)?;
-        res.append(&mut BytesPair::from(data.len() as u16).bytes());
-        res.append(&mut data);
-        
-        Ok(res)
+        Ok(())
     }
-    
-    fn write_record_data(&self, data: &RecordData) -> Result<Vec<u8>> {
+
+    fn write_record_data(&mut self, data: &RecordData) -> Result<()> {
         match data {
             RecordData::A(addr) => {
-                Ok(addr.octets().to_vec())
+                self.write_bytes(&addr.octets())
             },
-            RecordData::NS(host) | RecordData::CNAME(host) |
-            RecordData::PTR(host) | RecordData::TXT(host) => {
+            RecordData::NS(host) | RecordData::CNAME(host) | RecordData::PTR(host) => {
                 self.write_domain(host)
             },
+            // a TXT record without strings is written without data, the same as the parser reads
+            // records with no data
+            RecordData::TXT(strings) => {
+                for s in strings {
+                    self.write_character_string(s)?;
+                }
+
+                Ok(())
+            },
             RecordData::SOA {
                 mname,
                 rname,
@@ -134,30 +153,24 @@ impl PacketWriter {
                 expire,
                 minimum
             } => {
-                let mut res: Vec<u8> = self.write_domain(mname)?;
-                res.append(&mut self.write_domain(rname)?);
-                res.append(&mut BytesUtil::from_u32(*serial).to_vec());
-                res.append(&mut BytesUtil::from_u32(*refresh).to_vec());
-                res.append(&mut BytesUtil::from_u32(*retry).to_vec());
-                res.append(&mut BytesUtil::from_u32(*expire).to_vec());
-                res.append(&mut BytesUtil::from_u32(*minimum).to_vec());
-                
-                Ok(res)
+                self.write_domain(mname)?;
+                self.write_domain(rname)?;
+                self.write_u32(*serial)?;
+                self.write_u32(*refresh)?;
+                self.write_u32(*retry)?;
+                self.write_u32(*expire)?;
+                self.write_u32(*minimum)
             },
             RecordData::HINFO { ref cpu, ref os} => {
-                let mut res: Vec<u8> = cpu.bytes().collect();
-                res.append(&mut os.bytes().collect());
-
-                Ok(res)
+                self.write_character_string(cpu)?;
+                self.write_character_string(os)
             },
             RecordData::MX { preference, exchange } => {
-                let mut res: Vec<u8> = BytesPair::from(*preference).bytes();
-                res.append(&mut self.write_domain(exchange)?);
-
-                Ok(res)
+                self.write_u16(*preference)?;
+                self.write_domain(exchange)
             },
             RecordData::AAAA(addr) => {
-                Ok(addr.octets().to_vec())
+                self.write_bytes(&addr.octets())
             },
             RecordData::SRV {
                 priority,
@@ -165,34 +178,70 @@ impl PacketWriter {
                 port,
                 host
             } => {
-                let mut res = BytesPair::from(*priority).bytes();
-                res.append(&mut BytesPair::from(*weight).bytes());
-                res.append(&mut BytesPair::from(*port).bytes());
-                res.append(&mut self.write_domain(host)?);
+                self.write_u16(*priority)?;
+                self.write_u16(*weight)?;
+                self.write_u16(*port)?;
 
-                Ok(res)
+                // the target of a SRV record must not be compressed (RFC 2782)
+                self.write_bytes(&write_domain(host)?)
+            },
+            RecordData::OPT(edns) => {
+                for option in &edns.options {
+                    self.write_u16(option.code)?;
+                    self.write_u16(option.data.len() as u16)?;
+                    self.write_bytes(&option.data)?;
+                }
+
+                Ok(())
+            },
+            RecordData::TSIG(tsig) => {
+                // the algorithm name is never compressed (RFC 8945 4.2)
+                self.write_bytes(&write_domain(&tsig.algorithm)?)?;
+                self.write_u16((tsig.time_signed >> 32) as u16)?;
+                self.write_u32(tsig.time_signed as u32)?;
+                self.write_u16(tsig.fudge)?;
+                self.write_u16(tsig.mac.len() as u16)?;
+                self.write_bytes(&tsig.mac)?;
+                self.write_u16(tsig.original_id)?;
+                self.write_u16(tsig.error)?;
+                self.write_u16(tsig.other.len() as u16)?;
+                self.write_bytes(&tsig.other)
             },
-            RecordData::UNKNOWN(n) => {
-                Ok(vec![0; *n as usize])
+            RecordData::UNKNOWN(data) => {
+                self.write_bytes(data)
             }
         }
     }
 
-    pub fn write_domain(&self, domain: &String) -> Result<Vec<u8>> {
-        let mut res = Vec::new();
+    fn write_character_string(&mut self, s: &[u8]) -> Result<()> {
+        if s.len() > 255 {
+            bail!("character strings can't be longer than 255 bytes");
+        }
+
+        self.write_byte(s.len() as u8)?;
+        self.write_bytes(s)
+    }
 
-        if let Some(offset) = self.domains_buf.borrow().get(domain) {
-            if (offset >> 8) as u8 & 0xC0 < 0x40 {
-                res.push((offset >> 8) as u8 | 0xC0);
-                res.push((offset & 0xFF) as u8);
-                
-                return Ok(res)
+    pub fn write_domain(&mut self, domain: &str) -> Result<()> {
+        let labels = labels(domain)?;
+
+        for i in 0..labels.len() {
+            let suffix = labels[i..].iter().map(|label| escape_label(&label.to_ascii_lowercase())).collect::<Vec<String>>().join(".");
+
+            if let Some(offset) = self.domains_buf.get(&suffix) {
+                return self.write_u16(0xC000 | *offset);
+            }
+
+            // pointers only have 14 bits to address the message
+            if self.buf.len() < 0x4000 {
+                self.domains_buf.insert(suffix, self.buf.len() as u16);
             }
-        }
 
-        self.domains_buf.borrow_mut().insert(domain.clone(), self.offset);
+            self.write_byte(labels[i].len() as u8)?;
+            self.write_bytes(&labels[i])?;
+        }
 
-        write_domain(domain)
+        self.write_byte(0x00)
     }
 
     fn write_header_flags(header: &Header) -> (u8, u8) {
@@ -211,34 +260,22 @@ impl PacketWriter {
         res
     }
 
-    fn seek(&mut self, n: u16) -> Result<()> {
-        if n >= 512 {
-            bail!("End Of Stream");
-        }
-
-        self.offset = n;
-
-        Ok(())
-    }
-
     fn write_byte(&mut self, value: u8) -> Result<()> {
-        if self.offset >= 512 {
+        if self.buf.len() >= MAX_MESSAGE_SIZE {
             bail!("End Of Buffer");
         }
 
-        self.buf.insert(self.offset as usize, value);
-        self.offset += 1;
+        self.buf.push(value);
 
         Ok(())
     }
 
-    fn write_bytes(&mut self, mut values: Vec<u8>) -> Result<()> {
-        if self.offset + values.len() as u16 > 512 {
+    fn write_bytes(&mut self, values: &[u8]) -> Result<()> {
+        if self.buf.len() + values.len() > MAX_MESSAGE_SIZE {
             bail!("End Of Buffer");
         }
 
-        self.offset += values.len() as u16;
-        self.buf.append(values.as_mut());
+        self.buf.extend_from_slice(values);
 
         Ok(())
     }
@@ -260,21 +297,178 @@ impl PacketWriter {
     }
 }
 
-pub fn write_domain(domain: &String) -> Result<Vec<u8>> {
-    let mut res = Vec::new();
+// adds a record to the end of a message that has already been written, it's how a message
+// gets its TSIG record once its MAC is known.
+pub fn append_record(buf: &[u8], record: &Record) -> Result<Vec<u8>> {
+    if buf.len() < 12 {
+        bail!("the message is too short to add records to");
+    }
 
-    for label in domain.split('.') {
-        if label.len() > 63 {
-            bail!("labels exceeds 63 character limit");
-        }
+    let mut writer = PacketWriter::new();
+    writer.buf = buf.to_vec();
+    writer.write_record(record)?;
+
+    let count = match u16::from_be_bytes([buf[10], buf[11]]).checked_add(1) {
+        Some(count) => count,
+        None => bail!("the message has too many additional records")
+    };
+    writer.buf[10..12].copy_from_slice(&count.to_be_bytes());
 
+    Ok(writer.buf)
+}
+
+pub fn write_domain(domain: &str) -> Result<Vec<u8>> {
+    let mut res = Vec::new();
+
+    for label in labels(domain)? {
         res.push(label.len() as u8);
-        for byte in label.as_bytes() {
-            res.push(*byte);
-        }
+        res.extend_from_slice(&label);
     }
 
     res.push(0x00);
 
     Ok(res)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::Ipv4Addr;
+    use super::*;
+    use crate::edns::{Edns, EdnsOption};
+    use crate::parser::PacketParser;
+    use crate::query_class::QueryClass;
+    use crate::query_type::QueryType;
+    use crate::question::Question;
+
+    fn a_record(domain: &str, addr: Ipv4Addr) -> Record {
+        Record {
+            domain: domain.to_string(),
+            rtype: QueryType::A,
+            ttl: 300,
+            data: RecordData::A(addr),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn write_and_parse_large_packet() {
+        let mut packet = Packet::new();
+        packet.header.id = 1234;
+        packet.header.response = true;
+        packet.questions.push(Question::new("example.com".to_string(), QueryType::A));
+        for i in 0..100 {
+            packet.answers.push(a_record("example.com", Ipv4Addr::new(10, 0, 0, i)));
+        }
+
+        let buf = PacketWriter::from(packet).write().unwrap();
+        assert!(buf.len() > 512);
+
+        let packet = PacketParser::new(&buf).parse().unwrap();
+        assert_eq!(packet.header.id, 1234);
+        assert!(packet.header.response);
+        assert_eq!(packet.answers.len(), 100);
+        assert_eq!(packet.answers[99].domain, "example.com");
+        assert!(matches!(packet.answers[99].data, RecordData::A(addr) if addr == Ipv4Addr::new(10, 0, 0, 99)));
+    }
+
+    #[test]
+    fn compress_domains() {
+        let mut packet = Packet::new();
+        packet.questions.push(Question::new("www.example.com".to_string(), QueryType::A));
+        packet.answers.push(a_record("www.example.com", Ipv4Addr::LOCALHOST));
+        packet.authorities.push(Record {
+            domain: "example.com".to_string(),
+            rtype: QueryType::NS,
+            data: RecordData::NS("ns1.example.com".to_string()),
+            ..Default::default()
+        });
+
+        let buf = PacketWriter::from(packet).write().unwrap();
+
+        // the question name is written once, everything else points to it
+        assert_eq!(buf.windows(7).filter(|w| w == b"example").count(), 1);
+
+        let packet = PacketParser::new(&buf).parse().unwrap();
+        assert_eq!(packet.answers[0].domain, "www.example.com");
+        assert_eq!(packet.authorities[0].domain, "example.com");
+        assert!(matches!(&packet.authorities[0].data, RecordData::NS(ns) if ns == "ns1.example.com"));
+    }
+
+    #[test]
+    fn write_and_parse_opt() {
+        let mut edns = Edns::new(4096, true);
+        edns.options.push(EdnsOption { code: 10, data: vec![1, 2, 3, 4, 5, 6, 7, 8] });
+
+        let mut packet = Packet::new();
+        packet.questions.push(Question::new("example.com".to_string(), QueryType::A));
+        packet.resources.push(edns.clone().into_record());
+
+        let buf = PacketWriter::from(packet).write().unwrap();
+        let packet = PacketParser::new(&buf).parse().unwrap();
+
+        assert_eq!(packet.edns(), Some(&edns));
+    }
+
+    #[test]
+    fn write_and_parse_unknown_records() {
+        let mut packet = Packet::new();
+        packet.answers.push(Record {
+            domain: "example.com".to_string(),
+            rtype: QueryType::UNKNOWN(257),
+            rclass: QueryClass::UNKNOWN(42),
+            data: RecordData::UNKNOWN(vec![0, 5, b'i', b's', b's', b'u', b'e']),
+            ..Default::default()
+        });
+
+        let buf = PacketWriter::from(packet).write().unwrap();
+        let packet = PacketParser::new(&buf).parse().unwrap();
+
+        assert_eq!(packet.answers[0].rtype, QueryType::UNKNOWN(257));
+        assert_eq!(packet.answers[0].rclass, QueryClass::UNKNOWN(42));
+        assert!(matches!(&packet.answers[0].data, RecordData::UNKNOWN(data) if data == b"\x00\x05issue"));
+    }
+
+    #[test]
+    fn write_and_parse_character_strings() {
+        let mut packet = Packet::new();
+        packet.answers.push(Record {
+            domain: "example.com".to_string(),
+            rtype: QueryType::TXT,
+            data: RecordData::TXT(vec![b"v=spf1 -all".to_vec(), vec![], vec![b'a'; 255]]),
+            ..Default::default()
+        });
+        packet.answers.push(Record {
+            domain: "example.com".to_string(),
+            rtype: QueryType::HINFO,
+            data: RecordData::HINFO { cpu: b"x86".to_vec(), os: b"Linux".to_vec() },
+            ..Default::default()
+        });
+
+        let buf = PacketWriter::from(packet).write().unwrap();
+        let packet = PacketParser::new(&buf).parse().unwrap();
+
+        assert!(matches!(&packet.answers[0].data, RecordData::TXT(strings)
+            if strings.len() == 3 && strings[0] == b"v=spf1 -all" && strings[1].is_empty() && strings[2].len() == 255));
+        assert!(matches!(&packet.answers[1].data, RecordData::HINFO { cpu, os }
+            if cpu == b"x86" && os == b"Linux"));
+    }
+
+    #[test]
+    fn empty_txt_records() {
+        let mut packet = Packet::new();
+        packet.answers.push(Record {
+            domain: "example.com".to_string(),
+            rtype: QueryType::TXT,
+            data: RecordData::TXT(vec![]),
+            ..Default::default()
+        });
+
+        // the record is written without data, and written back the same way once parsed
+        let buf = PacketWriter::from(packet).write().unwrap();
+        assert_eq!(&buf[buf.len() - 2..], &[0, 0]);
+
+        let packet = PacketParser::new(&buf).parse().unwrap();
+        assert!(packet.answers[0].rtype == QueryType::TXT && packet.answers[0].len == 0);
+        assert_eq!(PacketWriter::from(packet).write().unwrap(), buf);
+    }
+}
@@ -1,44 +1,125 @@
-use std::io::{Error, ErrorKind};
-use anyhow::anyhow;
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+use crate::zone::token::Token;
 
+// a problem found in a zone file, with where it is and the line it's on. the line is 0
+// when the problem is with the file as a whole.
+#[derive(Clone, Debug)]
 pub struct ParserError {
-    line: u16,
-    err: ParserErrorKind
+    pub file: Option<PathBuf>,
+    pub line: u16,
+    pub column: u16,
+    pub kind: ParserErrorKind,
+    pub message: String,
+    pub snippet: Option<String>
 }
 
 impl ParserError {
-    pub fn new(line: u16, err: ParserErrorKind) -> Self {
+    pub fn new(line: u16, column: u16, kind: ParserErrorKind, message: &str) -> Self {
         Self {
+            file: None,
             line,
-            err
+            column,
+            kind,
+            message: message.to_string(),
+            snippet: None
         }
     }
-    
-    pub fn to_string(&self) -> String {
-        format!("line {}: {}", self.line, self.err.to_string())
+
+    // an error with the given token
+    pub fn at(token: &Token, kind: ParserErrorKind, message: &str) -> Self {
+        Self::new(token.line, token.column, kind, message)
     }
 }
 
-impl Into<Error> for ParserError {
-    fn into(self) -> Error {
-        Error::new(ErrorKind::Other, self.to_string())
+impl Display for ParserError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}:", file.display())?;
+        }
+
+        if self.line > 0 {
+            write!(f, "{}:{}:", self.line, self.column)?;
+        }
+
+        write!(f, " {}: {}", self.kind.to_string(), self.message)?;
+
+        // the line with a caret under the column
+        if let Some(snippet) = &self.snippet {
+            let indent: String = snippet.chars().take(self.column.saturating_sub(1) as usize).map(|ch| {
+                if ch == '\t' { '\t' } else { ' ' }
+            }).collect();
+
+            write!(f, "\n    {}\n    {}^", snippet, indent)?;
+        }
+
+        Ok(())
     }
 }
 
-impl Into<anyhow::Error> for ParserError {
-    fn into(self) -> anyhow::Error {
-        anyhow!(self.to_string())
+impl std::error::Error for ParserError {}
+
+// every error found in one or more zone files
+#[derive(Debug)]
+pub struct ParserErrors(pub Vec<ParserError>);
+
+impl Display for ParserErrors {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let errors: Vec<String> = self.0.iter().map(|error| error.to_string()).collect();
+
+        write!(f, "{}", errors.join("\n"))
     }
 }
 
+impl std::error::Error for ParserErrors {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ParserErrorKind {
     UnexpectedEOF,
+    UnexpectedToken,
+    UnterminatedString,
+    UnbalancedParentheses,
+    InvalidEncoding,
+    InvalidEscape,
+    InvalidName,
+    InvalidNumber,
+    InvalidTtl,
+    InvalidAddress,
+    InvalidData,
+    InvalidClass,
+    UnknownType,
+    UnsupportedType,
+    UnknownDirective,
+    InvalidDirective,
+    MissingOwner,
+    MissingTtl,
+    MissingSoa,
+    Io
 }
 
 impl ParserErrorKind {
-    pub fn to_string(&self) -> String {
+    pub fn to_string(self) -> String {
         match self {
-            ParserErrorKind::UnexpectedEOF => "ParseError: unexpected end of file".to_string()
-        }
+            ParserErrorKind::UnexpectedEOF => "unexpected end of line",
+            ParserErrorKind::UnexpectedToken => "unexpected token",
+            ParserErrorKind::UnterminatedString => "unterminated string",
+            ParserErrorKind::UnbalancedParentheses => "unbalanced parentheses",
+            ParserErrorKind::InvalidEncoding => "invalid encoding",
+            ParserErrorKind::InvalidEscape => "invalid escape",
+            ParserErrorKind::InvalidName => "invalid name",
+            ParserErrorKind::InvalidNumber => "invalid number",
+            ParserErrorKind::InvalidTtl => "invalid ttl",
+            ParserErrorKind::InvalidAddress => "invalid address",
+            ParserErrorKind::InvalidData => "invalid record data",
+            ParserErrorKind::InvalidClass => "invalid class",
+            ParserErrorKind::UnknownType => "unknown type",
+            ParserErrorKind::UnsupportedType => "unsupported type",
+            ParserErrorKind::UnknownDirective => "unknown directive",
+            ParserErrorKind::InvalidDirective => "invalid directive",
+            ParserErrorKind::MissingOwner => "missing owner",
+            ParserErrorKind::MissingTtl => "missing ttl",
+            ParserErrorKind::MissingSoa => "missing soa",
+            ParserErrorKind::Io => "io error"
+        }.to_string()
     }
-}
\ No newline at end of file
+}
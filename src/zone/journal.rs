@@ -0,0 +1,62 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use crate::query_type::QueryType;
+use crate::record::Record;
+use crate::transfer::soa_serial;
+use crate::zone::tree::AuthZone;
+
+// the number of versions of a zone kept in its journal, clients with an older
+// version are sent the whole zone instead.
+const MAX_JOURNAL_LEN: usize = 100;
+
+// the changes between two versions of a zone, SOA records aside.
+#[derive(Debug)]
+pub struct Diff {
+    pub from: Record,
+    pub to: Record,
+    pub removed: Vec<Record>,
+    pub added: Vec<Record>
+}
+
+impl Diff {
+    pub fn between(old: &AuthZone, new: &AuthZone) -> Self {
+        Self {
+            from: old.soa().clone(),
+            to: new.soa().clone(),
+            removed: old.records().filter(|record| {
+                record.rtype != QueryType::SOA && !new.contains(record)
+            }).cloned().collect(),
+            added: new.records().filter(|record| {
+                record.rtype != QueryType::SOA && !old.contains(record)
+            }).cloned().collect()
+        }
+    }
+
+    pub fn starts_at(&self) -> Option<u32> {
+        soa_serial(&self.from)
+    }
+}
+
+// the differences between the consecutive versions of a zone, oldest first (RFC 1995 5).
+#[derive(Clone, Default, Debug)]
+pub struct Journal {
+    diffs: VecDeque<Arc<Diff>>
+}
+
+impl Journal {
+    pub fn push(&mut self, diff: Diff) {
+        self.diffs.push_back(Arc::new(diff));
+
+        while self.diffs.len() > MAX_JOURNAL_LEN {
+            self.diffs.pop_front();
+        }
+    }
+
+    // the changes needed to bring a zone at the given serial up to date, if the
+    // journal goes back that far.
+    pub fn since(&self, serial: u32) -> Option<Vec<Arc<Diff>>> {
+        let start = self.diffs.iter().position(|diff| diff.starts_at() == Some(serial))?;
+
+        Some(self.diffs.iter().skip(start).cloned().collect())
+    }
+}
@@ -1,5 +1,8 @@
 pub mod parser;
+pub mod tree;
+pub mod writer;
+pub mod journal;
 mod token;
 mod scanner;
-mod error;
-mod fs;
\ No newline at end of file
+pub mod error;
+pub(crate) mod fs;
\ No newline at end of file
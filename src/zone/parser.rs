@@ -1,295 +1,973 @@
+use std::collections::HashSet;
 use std::fs;
 use crate::zone::fs::read_dir;
-use std::iter::Enumerate;
 use std::net::{Ipv4Addr, Ipv6Addr};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::vec::IntoIter;
+use crate::duration;
+use crate::name::{escape_label, is_absolute, labels};
 use crate::record::{Record, RecordData};
-use anyhow::{bail, Result};
+use anyhow::Result;
 use crate::query_class::QueryClass;
 use crate::query_type::QueryType;
-use crate::zone::error::{ParserError, ParserErrorKind};
+use crate::zone::error::{ParserError, ParserErrorKind, ParserErrors};
 use crate::zone::scanner::Scanner;
 use crate::zone::token::{Keyword, Token, TokenType};
 
+// how deep $INCLUDE directives may be nested, which also stops files from including each other
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+// how many records a single $GENERATE directive may add, so a typo in a range can't exhaust memory
+const MAX_GENERATED_RECORDS: u32 = 65536;
+
+type ParseResult<T> = std::result::Result<T, ParserError>;
+
 #[derive(Default, Debug)]
 pub struct Zone {
     pub(crate) origin: String,
     pub(crate) ttl: Option<usize>,
-    pub(crate) records: Vec<Record>
+    pub(crate) records: Vec<Record>,
+    // the file the zone was read from, dynamic updates are written back to it
+    pub(crate) file: Option<PathBuf>,
+    // the files pulled in with $INCLUDE
+    pub(crate) includes: Vec<PathBuf>
 }
 
 impl Zone {
-    pub fn parse_file<P: AsRef<Path>>(p: P) -> Result<Zone> {
-        let src = fs::read(p)?;
-        
-        parse(src)
+    pub fn parse_file<P: AsRef<Path>>(p: P) -> Result<Zone, ParserErrors> {
+        match read_file(p.as_ref()) {
+            (zone, errors) if errors.is_empty() => Ok(zone),
+            (_, errors) => Err(ParserErrors(errors))
+        }
     }
-    
+
+    // reads every zone of a directory, the errors of all of its files are reported together
     pub fn parse_directory<P: AsRef<Path>>(p: P, recursive: bool) -> Result<Vec<Zone>> {
         let mut zones: Vec<Zone> = Vec::new();
+        let mut errors = Vec::new();
         let files = read_dir(p, recursive)?;
         
         for file in files {
-            zones.push(Self::parse_file(file)?);
+            let (zone, mut found) = read_file(&file);
+            for error in &mut found {
+                error.file.get_or_insert_with(|| file.clone());
+            }
+
+            errors.push((file, found));
+            zones.push(zone);
         }
+
+        // files included by other zones are parts of them, not zones of their own, even when
+        // the zone including them is broken. a file that ends up including itself is still a
+        // zone, its errors would be reported by no one otherwise.
+        let included: HashSet<PathBuf> = zones.iter()
+            .flat_map(|zone| zone.includes.iter().cloned())
+            .filter(|include| !zones.iter().any(|zone| zone.file.as_ref().is_some_and(|file| &canonical(file) == include) && zone.includes.contains(include)))
+            .collect();
+        let errors: Vec<ParserError> = errors.into_iter()
+            .filter(|(file, _)| !included.contains(&canonical(file)))
+            .flat_map(|(_, found)| found)
+            .collect();
+
+        if !errors.is_empty() {
+            return Err(ParserErrors(errors).into());
+        }
+
+        zones.retain(|zone| !zone.file.as_ref().is_some_and(|file| included.contains(&canonical(file))));
         
         Ok(zones)
     }
 }
 
-fn parse(src: Vec<u8>) -> Result<Zone> {
+// parses a zone that isn't read from a file
+#[cfg(test)]
+pub(crate) fn parse(src: Vec<u8>) -> Result<Zone> {
+    match read(src, None) {
+        (zone, errors) if errors.is_empty() => Ok(zone),
+        (_, errors) => Err(ParserErrors(errors).into())
+    }
+}
+
+// reads a zone file. when there are errors the zone only holds what could be read
+fn read_file(path: &Path) -> (Zone, Vec<ParserError>) {
+    let (mut zone, errors) = match fs::read(path) {
+        Ok(src) => read(src, Some(path)),
+        Err(e) => (Zone::default(), vec![io_error(path, e)])
+    };
+    zone.file = Some(path.to_path_buf());
+
+    (zone, errors)
+}
+
+// reads a whole zone, finding every error rather than stopping at the first one
+fn read(src: Vec<u8>, file: Option<&Path>) -> (Zone, Vec<ParserError>) {
     let mut res = Zone{
         ..Default::default()
     };
 
-    let scanner = Scanner::new(src)?;
-    let mut tokens = scanner.scan()?.into_iter().enumerate();
-    
-    while let Some((_pos, token)) = tokens.next() {
-        match token.token_type {
-            TokenType::DolorSign => {
-                let keyword = get_next_token(&mut tokens, token.line)?;
-                let value = get_next_token(&mut tokens, token.line)?;
-                
-                parse_keywords(keyword, value, &mut res)?;
-            },
-            _ => {
-                let mut record = Record::default();
-                
-                if let Some(ttl) = res.ttl {
-                    record.ttl = ttl as u32;
-                }
-                
-                let domain = token.clone();
-
-                match domain.token_type {
-                    TokenType::AtSign => {
-                        if res.origin.is_empty() {
-                            bail!("invalid use of @, origin not defined at line {}", domain.line);
-                        }
-                        
-                        record.domain = res.origin.clone()
-                    },
-                    TokenType::String => {
-                        record.domain = to_domain(domain.lexeme, &res.origin);
-                    },
-                    TokenType::WhiteSpace => {
-                        record.domain = res.records.last().unwrap().domain.clone()
-                    },
-                    _ => {
-                        bail!("invalid domain {} at line {}", domain.lexeme, domain.line)
-                    }
-                }
+    let mut errors = Vec::new();
+    parse_source(src, &mut res, file, 0, &mut errors);
 
-                let mut next_token = get_next_token(&mut tokens, token.line)?;
-                if let Ok(ttl) = next_token.lexeme.parse::<usize>() {
-                    record.ttl = ttl as u32;
-                    next_token = get_next_token(&mut tokens, token.line)?;
-                }
+    if !errors.is_empty() {
+        errors.sort_by(|a, b| (&a.file, a.line, a.column).cmp(&(&b.file, b.line, b.column)));
+    } else if !res.records.iter().any(|record| {
+        record.rtype == QueryType::SOA
+    }) {
+        let mut error = ParserError::new(0, 0, ParserErrorKind::MissingSoa, "expected one SOA record");
+        error.file = file.map(|file| file.to_path_buf());
+        errors.push(error);
+    }
 
-                match next_token.lexeme.as_str() {
-                    "IN" => {
-                        record.rclass = QueryClass::IN
-                    },
-                    _ => {
-                        bail!("unsupported query class: {}", next_token.lexeme)
+    (res, errors)
+}
+
+// reads the entries of a file into the zone. its errors are added to `errors` along with the
+// file and the line they're on, included files are looked up relative to it.
+fn parse_source(src: Vec<u8>, res: &mut Zone, file: Option<&Path>, depth: usize, errors: &mut Vec<ParserError>) {
+    let first = errors.len();
+
+    match Scanner::new(src) {
+        Ok(scanner) => {
+            let tokens = scanner.scan(errors);
+            parse_tokens(tokens, res, file.and_then(|file| file.parent()), depth, errors);
+
+            // the errors of included files already name theirs
+            for error in errors[first..].iter_mut().filter(|error| error.file.is_none()) {
+                if let Some(line) = scanner.line(error.line) {
+                    // errors at the end of an entry point past its last character
+                    if error.column == 0 {
+                        error.column = line.chars().count() as u16 + 1;
                     }
+
+                    error.snippet = Some(line.to_string());
                 }
 
-                let typ = get_next_token(&mut tokens, token.line)?;
-                match typ.lexeme.to_uppercase().as_str() {
-                    "A" => {
-                        record.rtype = QueryType::A;
-                        
-                        let addr_token = get_next_token(&mut tokens, token.line)?;
-                        
-                        match Ipv4Addr::from_str(addr_token.lexeme.as_str()) {
-                            Ok(addr) => {
-                                record.data = RecordData::A(addr);        
-                            },
-                            Err(_) => {
-                                bail!("cannot parse {} as a valid ip v4 address", addr_token.lexeme);
-                            }
-                        }
-                    },
-                    "AAAA" => {
-                        record.rtype = QueryType::AAAA;
-
-                        let addr_token = get_next_token(&mut tokens, token.line)?;
-
-                        match Ipv6Addr::from_str(addr_token.lexeme.as_str()) {
-                            Ok(addr) => {
-                                record.data = RecordData::AAAA(addr);
-                            },
-                            Err(_) => {
-                                bail!("cannot parse {} as a valid ip v4 address", addr_token.lexeme);
-                            }
-                        }
-                    },
-                    "NS" => {
-                        record.rtype = QueryType::NS;
-                        
-                        let ns = get_next_token(&mut tokens, token.line)?;
-                        
-                        record.data = RecordData::NS(to_domain(ns.lexeme, &res.origin));
-                    },
-                    "CNAME" => {
-                        record.rtype = QueryType::CNAME;
+                error.file = file.map(|file| file.to_path_buf());
+            }
+        },
+        Err(mut e) => {
+            e.file = file.map(|file| file.to_path_buf());
+            errors.push(e);
+        }
+    }
+}
 
-                        let cname = get_next_token(&mut tokens, token.line)?;
+// an entry with an error is skipped, the ones after it are still read
+fn parse_tokens(tokens: Vec<Token>, res: &mut Zone, dir: Option<&Path>, depth: usize, errors: &mut Vec<ParserError>) {
+    for entry in tokens.split(|token| token.token_type == TokenType::EOL) {
+        if entry.is_empty() {
+            continue;
+        }
 
-                        record.data = RecordData::CNAME(to_domain(cname.lexeme, &res.origin));
-                    },
-                    "SOA" => {
-                        record.rtype = QueryType::SOA;
-                        
-                        let mname = get_next_token(&mut tokens, token.line)?;
-                        let rname = get_next_token(&mut tokens, token.line)?;
-
-                        let left_paran = get_next_token(&mut tokens, token.line)?;
-                        if left_paran.token_type != TokenType::LeftParenthesis {
-                            bail!("expected ) found {}", left_paran.lexeme)
-                        }
-                        
-                        let serial = get_next_non_empty_token(&mut tokens, token.line)?;
-                        let refresh = get_next_non_empty_token(&mut tokens, token.line)?;
-                        let retry = get_next_non_empty_token(&mut tokens, token.line)?;
-                        let expire = get_next_non_empty_token(&mut tokens, token.line)?;
-                        let minimum = get_next_non_empty_token(&mut tokens, token.line)?;
-                        
-                        let right_paren = get_next_non_empty_token(&mut tokens, token.line)?;
-                        if right_paren.token_type != TokenType::RightParenthesis {
-                            bail!("expected ( found {}", right_paren.lexeme)
-                        }
-                        
-                        record.data = RecordData::SOA {
-                            mname: to_domain(mname.lexeme, &res.origin),
-                            rname: to_domain(rname.lexeme, &res.origin),
-                            serial: serial.lexeme.parse()?,
-                            refresh: refresh.lexeme.parse()?,
-                            retry: retry.lexeme.parse()?,
-                            expire: expire.lexeme.parse()?,
-                            minimum: minimum.lexeme.parse()?
-                        }
-                    },
-                    "MX" => {
-                        record.rtype = QueryType::MX;
+        if let Err(e) = parse_entry(entry.to_vec(), res, dir, depth, errors) {
+            errors.push(e);
+        }
+    }
+}
 
-                        let preference = get_next_token(&mut tokens, token.line)?;
-                        let exchange = get_next_token(&mut tokens, token.line)?;
+// an entry is either a directive or a record: <owner> [<ttl>] [<class>] <type> <data>, where
+// the ttl and the class may come in either order and are otherwise inherited (RFC 1035 5.1).
+fn parse_entry(entry: Vec<Token>, res: &mut Zone, dir: Option<&Path>, depth: usize, errors: &mut Vec<ParserError>) -> ParseResult<()> {
+    // where the entry ends, for errors about what's missing from it
+    let end = match entry.last() {
+        Some(last) => last.line,
+        None => return Ok(())
+    };
 
-                        record.data = RecordData::MX { 
-                            preference: preference.lexeme.parse()?, 
-                            exchange: to_domain(exchange.lexeme, &res.origin) 
-                        };
-                    },
-                    "PTR" => {
-                        record.rtype = QueryType::PTR;
+    let mut tokens = entry.into_iter().peekable();
+    let token = match tokens.next() {
+        Some(token) => token,
+        None => return Ok(())
+    };
 
-                        let ptr = get_next_token(&mut tokens, token.line)?;
+    if token.token_type == TokenType::DolorSign {
+        let keyword = get_next_token(&mut tokens, end, "a directive")?;
 
-                        record.data = RecordData::PTR(to_domain(ptr.lexeme, &res.origin));
-                    },
-                    "HINFO" => {
-                        record.rtype = QueryType::HINFO;
+        return parse_keywords(keyword, tokens.collect(), res, dir, depth, errors);
+    }
 
-                        let cpu = get_next_token(&mut tokens, token.line)?;
-                        let os = get_next_token(&mut tokens, token.line)?;
+    let mut record = Record::default();
+    let previous = res.records.last();
 
-                        record.data = RecordData::HINFO {
-                            cpu: cpu.lexeme,
-                            os: os.lexeme,
-                        };
-                    },
-                    "TXT" => {
-                        record.rtype = QueryType::TXT;
+    record.domain = match token.token_type {
+        TokenType::AtSign => {
+            if res.origin.is_empty() {
+                return Err(ParserError::at(&token, ParserErrorKind::InvalidName, "invalid use of @, origin not defined"));
+            }
 
-                        let txt = get_next_token(&mut tokens, token.line)?;
+            res.origin.clone()
+        },
+        // a * anywhere but as the whole leftmost label is an ordinary character (RFC 4592 2.1.1)
+        TokenType::String => parse_name(&token, &res.origin)?,
+        // a record without an owner belongs to the previous one's
+        TokenType::WhiteSpace => match previous {
+            Some(previous) => previous.domain.clone(),
+            None => return Err(ParserError::at(&token, ParserErrorKind::MissingOwner, "no owner name for the record"))
+        },
+        _ => {
+            return Err(ParserError::at(&token, ParserErrorKind::InvalidName, &format!("invalid domain {}", token.lexeme)))
+        }
+    };
 
-                        record.data = RecordData::TXT(txt.lexeme);
-                    },
-                    _ => {
-                        bail!("unsupported record type: {}", typ.lexeme)
-                    }
-                }
-                
-                res.records.push(record);
+    let mut ttl = None;
+    let mut class = None;
+    while let Some(next) = tokens.peek() {
+        if class.is_none() {
+            if let Some(value) = QueryClass::from_name(&next.lexeme) {
+                class = Some((value, next.clone()));
+                tokens.next();
+
+                continue;
+            }
+        }
+
+        if ttl.is_none() {
+            if let Some(value) = parse_ttl(next) {
+                ttl = Some(value);
+                tokens.next();
+
+                continue;
             }
         }
+
+        break;
     }
-    
-    if !res.records.iter().any(|record| {
-        record.rtype == QueryType::SOA
-    }) {
-        bail!("expected one SOA records")
+
+    let typ = get_next_token(&mut tokens, end, "a record type")?;
+    record.rtype = match QueryType::from_name(&typ.lexeme) {
+        Some(rtype) => rtype,
+        None => return Err(ParserError::at(&typ, ParserErrorKind::UnknownType, &format!("unknown record type {}", typ.lexeme)))
+    };
+
+    let class_token = class.as_ref().map(|(_, token)| token.clone()).unwrap_or_else(|| typ.clone());
+    record.rclass = match class.map(|(class, _)| class).or_else(|| previous.map(|previous| previous.rclass.clone())) {
+        Some(class) => class,
+        None => QueryClass::IN
+    };
+
+    // all of the records of a zone are of the same class
+    if let Some(first) = res.records.first() {
+        if first.rclass != record.rclass {
+            return Err(ParserError::at(&class_token, ParserErrorKind::InvalidClass,
+                &format!("class {:?} isn't the class of the zone, {:?}", record.rclass, first.rclass)));
+        }
     }
 
-    Ok(res)
+    record.data = parse_data(record.rtype, tokens.collect(), end, &res.origin)?;
+
+    // without a ttl of its own, a record takes the $TTL or the previous record's. the SOA
+    // record can fall back to its minimum as a last resort.
+    record.ttl = match ttl.or(res.ttl.map(|ttl| ttl as u32)).or(previous.map(|previous| previous.ttl)) {
+        Some(ttl) => ttl,
+        None => match record.data {
+            RecordData::SOA { minimum, .. } => minimum,
+            _ => return Err(ParserError::at(&typ, ParserErrorKind::MissingTtl, "no ttl for the record, set a default with $TTL"))
+        }
+    };
+
+    res.records.push(record);
+
+    Ok(())
 }
 
-fn parse_keywords(keyword: Token, value: Token, res: &mut Zone) -> Result<()> {
+// `end` is the line the record ends on
+fn parse_data(rtype: QueryType, data: Vec<Token>, end: u16, origin: &str) -> ParseResult<RecordData> {
+    if data.first().is_some_and(|token| token.lexeme == "\\#") {
+        return parse_generic_data(rtype, data, end);
+    }
+
+    if rtype == QueryType::TXT {
+        let mut strings = Vec::new();
+
+        for txt in &data {
+            let txt = decode_string(txt)?;
+            if txt.is_empty() {
+                strings.push(txt);
+
+                continue;
+            }
+
+            // values longer than a single <character-string> are split into several ones
+            for chunk in txt.chunks(255) {
+                strings.push(chunk.to_vec());
+            }
+        }
+
+        if strings.is_empty() {
+            return Err(ParserError::new(end, 0, ParserErrorKind::UnexpectedEOF, "expected a string"));
+        }
+
+        return Ok(RecordData::TXT(strings));
+    }
+
+    let mut fields = data.into_iter().peekable();
+
+    let data = match rtype {
+        QueryType::A => {
+            let addr_token = get_next_token(&mut fields, end, "an address")?;
+
+            match Ipv4Addr::from_str(addr_token.lexeme.as_str()) {
+                Ok(addr) => RecordData::A(addr),
+                Err(_) => return Err(ParserError::at(&addr_token, ParserErrorKind::InvalidAddress,
+                    &format!("cannot parse {} as a valid ip v4 address", addr_token.lexeme)))
+            }
+        },
+        QueryType::AAAA => {
+            let addr_token = get_next_token(&mut fields, end, "an address")?;
+
+            match Ipv6Addr::from_str(addr_token.lexeme.as_str()) {
+                Ok(addr) => RecordData::AAAA(addr),
+                Err(_) => return Err(ParserError::at(&addr_token, ParserErrorKind::InvalidAddress,
+                    &format!("cannot parse {} as a valid ip v6 address", addr_token.lexeme)))
+            }
+        },
+        QueryType::NS => RecordData::NS(parse_name(&get_next_token(&mut fields, end, "a name")?, origin)?),
+        QueryType::CNAME => RecordData::CNAME(parse_name(&get_next_token(&mut fields, end, "a name")?, origin)?),
+        QueryType::PTR => RecordData::PTR(parse_name(&get_next_token(&mut fields, end, "a name")?, origin)?),
+        QueryType::SOA => {
+            RecordData::SOA {
+                mname: parse_name(&get_next_token(&mut fields, end, "the primary name server")?, origin)?,
+                rname: parse_name(&get_next_token(&mut fields, end, "the mailbox")?, origin)?,
+                serial: parse_u32(&get_next_token(&mut fields, end, "the serial")?)?,
+                refresh: parse_time(&get_next_token(&mut fields, end, "the refresh")?)?,
+                retry: parse_time(&get_next_token(&mut fields, end, "the retry")?)?,
+                expire: parse_time(&get_next_token(&mut fields, end, "the expire")?)?,
+                minimum: parse_time(&get_next_token(&mut fields, end, "the minimum")?)?
+            }
+        },
+        QueryType::MX => {
+            RecordData::MX {
+                preference: parse_u16(&get_next_token(&mut fields, end, "the preference")?)?,
+                exchange: parse_name(&get_next_token(&mut fields, end, "the exchange")?, origin)?
+            }
+        },
+        QueryType::SRV => {
+            RecordData::SRV {
+                priority: parse_u16(&get_next_token(&mut fields, end, "the priority")?)?,
+                weight: parse_u16(&get_next_token(&mut fields, end, "the weight")?)?,
+                port: parse_u16(&get_next_token(&mut fields, end, "the port")?)?,
+                host: parse_name(&get_next_token(&mut fields, end, "the target")?, origin)?,
+            }
+        },
+        QueryType::HINFO => {
+            RecordData::HINFO {
+                cpu: parse_character_string(&get_next_token(&mut fields, end, "the cpu")?)?,
+                os: parse_character_string(&get_next_token(&mut fields, end, "the os")?)?,
+            }
+        },
+        _ => {
+            return Err(ParserError::new(end, 0, ParserErrorKind::UnsupportedType,
+                &format!("unsupported record type {}, use the \\# generic syntax", rtype.to_name())))
+        }
+    };
+
+    if let Some(token) = fields.next() {
+        return Err(ParserError::at(&token, ParserErrorKind::UnexpectedToken, &format!("unexpected {}", token.lexeme)));
+    }
+
+    Ok(data)
+}
+
+fn parse_keywords(keyword: Token, args: Vec<Token>, res: &mut Zone, dir: Option<&Path>, depth: usize, errors: &mut Vec<ParserError>) -> ParseResult<()> {
     match Keyword::from(keyword.lexeme.as_str()) {
-        Some(keyword) => {
-            match keyword {
+        Some(keyword_type) => {
+            let value = match args.first() {
+                Some(value) => value,
+                None => return Err(ParserError::new(keyword.line, 0, ParserErrorKind::UnexpectedEOF,
+                    &format!("expected a value after ${}", keyword_type.to_string().to_uppercase())))
+            };
+
+            match keyword_type {
+                Keyword::Origin | Keyword::TTL if args.len() > 1 => {
+                    return Err(ParserError::at(&args[1], ParserErrorKind::UnexpectedToken,
+                        &format!("unexpected {} after ${}", args[1].lexeme, keyword_type.to_string().to_uppercase())))
+                },
                 Keyword::Origin => {
-                    res.origin = trim_end(value.lexeme.to_owned());
+                    res.origin = parse_name(value, &res.origin)?;
                 },
                 Keyword::TTL => {
-                    match usize::from_str(value.lexeme.as_str()) {
-                        Ok(ttl) => {
-                            res.ttl = Some(ttl)
-                        },
-                        Err(_e) => {
-                            bail!("invalid input for ttl {} at line {}", value.lexeme, value.line)
-                        }
-                    }
+                    res.ttl = Some(parse_time(value)? as usize);
                 },
-                _ => {
-                    bail!("unsupported keyword {} at line {}", keyword.to_string(), value.line)
-                }
+                Keyword::Include => include(&args, res, dir, depth, errors)?,
+                Keyword::Generate => generate(&args, res, dir, depth, errors)?
             }
         },
         None => {
-            bail!("unknown keyword {} at line {}", keyword.lexeme, keyword.line)
+            return Err(ParserError::at(&keyword, ParserErrorKind::UnknownDirective, &format!("unknown keyword {}", keyword.lexeme)))
         }
     }
     
     Ok(())
 }
 
-fn to_domain(mut s: String, origin: &String) -> String {
-    if s.ends_with(".") {
-        s.remove(s.len()-1);
-        return s
+// $INCLUDE <file> [<origin>] reads the records of another file, relative to the one including
+// it. the origin given to the included file doesn't change the origin of this one (RFC 1035 5.1).
+// errors in the included file are reported with its own name and lines.
+fn include(args: &[Token], res: &mut Zone, dir: Option<&Path>, depth: usize, errors: &mut Vec<ParserError>) -> ParseResult<()> {
+    if let Some(token) = args.get(2) {
+        return Err(ParserError::at(token, ParserErrorKind::UnexpectedToken, &format!("unexpected {} after $INCLUDE", token.lexeme)));
     }
-    
-    [s, origin.clone()].join(".")
+
+    if depth >= MAX_INCLUDE_DEPTH {
+        return Err(ParserError::at(&args[0], ParserErrorKind::InvalidDirective,
+            &format!("$INCLUDE nested more than {} files deep", MAX_INCLUDE_DEPTH)));
+    }
+
+    let path = match dir {
+        Some(dir) => dir.join(&args[0].lexeme),
+        None => PathBuf::from(&args[0].lexeme)
+    };
+
+    let src = match fs::read(&path) {
+        Ok(src) => src,
+        Err(e) => return Err(ParserError::at(&args[0], ParserErrorKind::Io, &format!("can't include {}: {}", path.display(), e)))
+    };
+
+    let origin = res.origin.clone();
+    if let Some(value) = args.get(1) {
+        res.origin = parse_name(value, &origin)?;
+    }
+
+    res.includes.push(canonical(&path));
+    parse_source(src, res, Some(&path), depth + 1, errors);
+    res.origin = origin;
+
+    Ok(())
 }
 
-fn trim_end(mut s: String) -> String {
-    if s.ends_with(".") {
-        s.remove(s.len()-1);
+// $GENERATE <start>-<stop>[/<step>] <owner> [<ttl>] [<class>] <type> <data> adds a record for
+// every number in the range, with `$` in the owner and the data replaced by the number (BIND).
+// it stops at the first error, the same mistake would otherwise be reported for every number.
+fn generate(args: &[Token], res: &mut Zone, dir: Option<&Path>, depth: usize, errors: &mut Vec<ParserError>) -> ParseResult<()> {
+    let range = &args[0];
+
+    if args.len() < 4 {
+        return Err(ParserError::new(range.line, 0, ParserErrorKind::UnexpectedEOF, "expected an owner, a type and record data after the range"));
     }
-    
-    s
+
+    let (start, stop, step) = match parse_range(&range.lexeme) {
+        Some((start, stop, step)) if start <= stop && step > 0 => (start, stop, step),
+        _ => return Err(ParserError::at(range, ParserErrorKind::InvalidDirective, &format!("invalid $GENERATE range {}", range.lexeme)))
+    };
+
+    if (stop - start) / step >= MAX_GENERATED_RECORDS {
+        return Err(ParserError::at(range, ParserErrorKind::InvalidDirective, &format!("$GENERATE range {} adds more than {} records", range.lexeme, MAX_GENERATED_RECORDS)));
+    }
+
+    for n in (start..=stop).step_by(step as usize) {
+        let line = args[1..].iter().map(|token| {
+            let value = substitute(token, n)?;
+
+            Ok(match token.token_type {
+                TokenType::QuotedString => format!("\"{}\"", value),
+                _ => value
+            })
+        }).collect::<ParseResult<Vec<String>>>()?.join(" ");
+
+        // the generated records are reported at the owner of the directive
+        let mut scan_errors = Vec::new();
+        let tokens: Vec<Token> = Scanner::new(line.into_bytes())?.scan(&mut scan_errors).into_iter().map(|mut token| {
+            token.line = range.line;
+            token.column = args[1].column;
+            token
+        }).collect();
+
+        if let Some(mut e) = scan_errors.into_iter().next() {
+            e.line = range.line;
+            e.column = args[1].column;
+
+            return Err(e);
+        }
+
+        for entry in tokens.split(|token| token.token_type == TokenType::EOL) {
+            parse_entry(entry.to_vec(), res, dir, depth, errors)?;
+        }
+    }
+
+    Ok(())
 }
 
-fn get_next_token(t: &mut Enumerate<IntoIter<Token>>, line: u16) -> Result<Token> {
-    let (_, token) = t.
-        next().
-        ok_or::<anyhow::Error>(ParserError::new(line, ParserErrorKind::UnexpectedEOF).into())?;
-    
-    Ok(token)
+fn parse_range(range: &str) -> Option<(u32, u32, u32)> {
+    let (range, step) = match range.split_once('/') {
+        Some((range, step)) => (range, step.parse().ok()?),
+        None => (range, 1)
+    };
+
+    let (start, stop) = range.split_once('-')?;
+
+    Some((start.parse().ok()?, stop.parse().ok()?, step))
+}
+
+// replaces `$` with the number and `${offset[,width[,base]]}` with the number plus the offset,
+// padded to the width and written in base d, o, x, X, n or N. `$$` and `\$` are a literal $.
+fn substitute(token: &Token, n: u32) -> ParseResult<String> {
+    let template = token.lexeme.as_str();
+    let mut res = String::new();
+    let mut chars = template.chars();
+
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\\' => {
+                match chars.next() {
+                    Some('$') => res.push('$'),
+                    Some(next) => {
+                        res.push(ch);
+                        res.push(next);
+                    },
+                    None => res.push(ch)
+                }
+            },
+            '$' => {
+                let rest = chars.as_str();
+
+                if let Some(rest) = rest.strip_prefix('$') {
+                    res.push('$');
+                    chars = rest.chars();
+                } else if let Some(rest) = rest.strip_prefix('{') {
+                    let (modifier, rest) = match rest.split_once('}') {
+                        Some(modifier) => modifier,
+                        None => return Err(ParserError::at(token, ParserErrorKind::InvalidDirective, &format!("unterminated modifier in {}", template)))
+                    };
+
+                    res.push_str(&format_number(modifier, n, token)?);
+                    chars = rest.chars();
+                } else {
+                    res.push_str(&n.to_string());
+                }
+            },
+            _ => res.push(ch)
+        }
+    }
+
+    Ok(res)
+}
+
+fn format_number(modifier: &str, n: u32, token: &Token) -> ParseResult<String> {
+    let invalid = |message: String| ParserError::at(token, ParserErrorKind::InvalidDirective, &message);
+
+    let parts: Vec<&str> = modifier.split(',').collect();
+    if parts.len() > 3 {
+        return Err(invalid(format!("invalid modifier ${{{}}}", modifier)));
+    }
+
+    let offset: i64 = match parts[0].parse() {
+        Ok(offset) => offset,
+        Err(_) => return Err(invalid(format!("invalid offset in ${{{}}}", modifier)))
+    };
+
+    let width: usize = match parts.get(1).map(|width| width.parse()) {
+        Some(Ok(width)) => width,
+        Some(Err(_)) => return Err(invalid(format!("invalid width in ${{{}}}", modifier))),
+        None => 0
+    };
+
+    let value = match u64::try_from(n as i64 + offset) {
+        Ok(value) => value,
+        Err(_) => return Err(invalid(format!("${{{}}} makes {} negative", modifier, n)))
+    };
+
+    Ok(match parts.get(2).copied().unwrap_or("d") {
+        "d" => format!("{:0width$}", value),
+        "o" => format!("{:0width$o}", value),
+        "x" => format!("{:0width$x}", value),
+        "X" => format!("{:0width$X}", value),
+        // nibbles in reverse order each followed by a dot, as in ip6.arpa names
+        base @ ("n" | "N") => {
+            let digits = match base {
+                "n" => format!("{:x}", value),
+                _ => format!("{:X}", value)
+            };
+
+            let mut nibbles: Vec<char> = digits.chars().rev().collect();
+            while nibbles.len() * 2 < width {
+                nibbles.push('0');
+            }
+
+            let mut res: String = nibbles.iter().flat_map(|nibble| [*nibble, '.']).collect();
+            // the width counts the dots, an odd width leaves the last one out
+            if width % 2 == 1 && res.len() == width + 1 {
+                res.pop();
+            }
+
+            res
+        },
+        base => return Err(invalid(format!("invalid base {} in ${{{}}}", base, modifier)))
+    })
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+fn io_error(path: &Path, e: std::io::Error) -> ParserError {
+    let mut error = ParserError::new(0, 0, ParserErrorKind::Io, &e.to_string());
+    error.file = Some(path.to_path_buf());
+
+    error
+}
+
+// reads a name in presentation format, relative to the origin unless it ends with a dot
+fn parse_name(token: &Token, origin: &str) -> ParseResult<String> {
+    if token.token_type == TokenType::AtSign {
+        return Ok(origin.to_string());
+    }
+
+    if token.token_type != TokenType::String {
+        return Err(ParserError::at(token, ParserErrorKind::InvalidName, &format!("expected a name, found {}", token.lexeme)));
+    }
+
+    let parsed = match labels(&token.lexeme) {
+        Ok(parsed) => parsed,
+        Err(e) => return Err(ParserError::at(token, ParserErrorKind::InvalidName, &e.to_string()))
+    };
+
+    // escapes are written the same way whichever form they had
+    let name = parsed.iter().map(|label| escape_label(label)).collect::<Vec<String>>().join(".");
+    if is_absolute(&token.lexeme) || origin.is_empty() {
+        return Ok(name);
+    }
+
+    let name = format!("{}.{}", name, origin);
+    if let Err(e) = labels(&name) {
+        return Err(ParserError::at(token, ParserErrorKind::InvalidName, &e.to_string()));
+    }
+
+    Ok(name)
+}
+
+// a ttl in seconds, either a number or a duration with BIND's units such as 1h30m
+fn parse_ttl(token: &Token) -> Option<u32> {
+    if token.token_type != TokenType::String || !token.lexeme.starts_with(|ch: char| ch.is_ascii_digit()) {
+        return None;
+    }
+
+    if let Ok(ttl) = token.lexeme.parse::<u32>() {
+        return Some(ttl);
+    }
+
+    let ttl = duration::parse(&token.lexeme).ok()?;
+    if ttl.subsec_nanos() != 0 {
+        return None;
+    }
+
+    u32::try_from(ttl.as_secs()).ok()
+}
+
+fn parse_time(token: &Token) -> ParseResult<u32> {
+    match parse_ttl(token) {
+        Some(value) => Ok(value),
+        None => Err(ParserError::at(token, ParserErrorKind::InvalidTtl,
+            &format!("expected a number of seconds or a duration such as 1h30m, found {}", token.lexeme)))
+    }
+}
+
+fn parse_u16(token: &Token) -> ParseResult<u16> {
+    match token.lexeme.parse::<u16>() {
+        Ok(value) => Ok(value),
+        Err(_) => Err(ParserError::at(token, ParserErrorKind::InvalidNumber,
+            &format!("expected a number between 0 and 65535, found {}", token.lexeme)))
+    }
+}
+
+fn parse_u32(token: &Token) -> ParseResult<u32> {
+    match token.lexeme.parse::<u32>() {
+        Ok(value) => Ok(value),
+        Err(_) => Err(ParserError::at(token, ParserErrorKind::InvalidNumber,
+            &format!("expected a number between 0 and 4294967295, found {}", token.lexeme)))
+    }
+}
+
+fn parse_character_string(token: &Token) -> ParseResult<Vec<u8>> {
+    let res = decode_string(token)?;
+    if res.len() > 255 {
+        return Err(ParserError::at(token, ParserErrorKind::InvalidData, "the string is longer than 255 characters"));
+    }
+
+    Ok(res)
+}
+
+// decodes the \X and \DDD escapes of a (possibly quoted) string (RFC 1035 5.1).
+fn decode_string(token: &Token) -> ParseResult<Vec<u8>> {
+    if token.token_type != TokenType::String && token.token_type != TokenType::QuotedString {
+        return Err(ParserError::at(token, ParserErrorKind::UnexpectedToken, &format!("expected a string, found {}", token.lexeme)));
+    }
+
+    let invalid = || ParserError::at(token, ParserErrorKind::InvalidEscape, &format!("invalid escape sequence in {}", token.lexeme));
+
+    let mut res = Vec::new();
+    let mut bytes = token.lexeme.bytes();
+
+    while let Some(byte) = bytes.next() {
+        if byte != b'\\' {
+            res.push(byte);
+
+            continue;
+        }
+
+        match bytes.next() {
+            Some(digit) if digit.is_ascii_digit() => {
+                let mut value = (digit - b'0') as u16;
+                for _ in 0..2 {
+                    match bytes.next() {
+                        Some(digit) if digit.is_ascii_digit() => {
+                            value = value * 10 + (digit - b'0') as u16;
+                        },
+                        _ => return Err(invalid())
+                    }
+                }
+
+                if value > 255 {
+                    return Err(invalid());
+                }
+
+                res.push(value as u8);
+            },
+            Some(byte) => res.push(byte),
+            None => return Err(invalid())
+        }
+    }
+
+    Ok(res)
 }
 
-fn get_next_non_empty_token(t: &mut Enumerate<IntoIter<Token>>, line: u16) -> Result<Token> {
-    while let Some((_, token)) = t.next() {
-        if token.token_type != TokenType::WhiteSpace {
-            return Ok(token)
+// parses the RFC 3597 generic representation of record data: \# <length> <hex data>
+fn parse_generic_data(rtype: QueryType, tokens: Vec<Token>, end: u16) -> ParseResult<RecordData> {
+    let mut tokens = tokens.into_iter().skip(1).peekable();
+
+    let len = get_next_token(&mut tokens, end, "the length of the record data")?;
+    let len = match len.lexeme.parse::<usize>() {
+        Ok(value) => value,
+        Err(_) => return Err(ParserError::at(&len, ParserErrorKind::InvalidNumber, &format!("invalid record data length {}", len.lexeme)))
+    };
+
+    let mut data = Vec::new();
+    for token in tokens {
+        let hex = &token.lexeme;
+        if hex.len() % 2 != 0 || !hex.chars().all(|ch| ch.is_ascii_hexdigit()) {
+            return Err(ParserError::at(&token, ParserErrorKind::InvalidData, &format!("invalid hex record data {}", hex)));
+        }
+
+        for i in (0..hex.len()).step_by(2) {
+            // only hex digits are left
+            data.push(u8::from_str_radix(&hex[i..i + 2], 16).unwrap_or_default());
         }
     }
 
-    Err(ParserError::new(line, ParserErrorKind::UnexpectedEOF).into())
+    if data.len() != len {
+        return Err(ParserError::new(end, 0, ParserErrorKind::InvalidData, &format!("expected {} bytes of record data, found {}", len, data.len())));
+    }
+
+    match RecordData::from_wire(rtype, &data) {
+        Ok(data) => Ok(data),
+        Err(e) => Err(ParserError::new(end, 0, ParserErrorKind::InvalidData, &format!("invalid record data: {}", e)))
+    }
+}
+
+// `what` is missing when there are no more tokens on the line
+fn get_next_token<I: Iterator<Item = Token>>(t: &mut I, end: u16, what: &str) -> ParseResult<Token> {
+    match t.next() {
+        Some(token) => Ok(token),
+        None => Err(ParserError::new(end, 0, ParserErrorKind::UnexpectedEOF, &format!("expected {}", what)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SOA: &str = "$ORIGIN example.com.
+$TTL 3600
+@ IN SOA ns1.example.com. admin.example.com. (
+    1
+    7200
+    3600
+    1209600
+    300 )
+";
+
+    fn parse_zone(records: &str) -> Result<Zone> {
+        parse([SOA, records].concat().into_bytes())
+    }
+
+    #[test]
+    fn generic_record_data() {
+        let zone = parse_zone("www IN TYPE65 \\# 4 0001 0000
+www IN A \\# 4 0A000001
+www IN TYPE257 \\# 0
+").unwrap();
+
+        assert_eq!(zone.records[1].rtype, QueryType::UNKNOWN(65));
+        assert!(matches!(&zone.records[1].data, RecordData::UNKNOWN(data) if data == &vec![0, 1, 0, 0]));
+        assert!(matches!(zone.records[2].data, RecordData::A(addr) if addr == Ipv4Addr::new(10, 0, 0, 1)));
+        assert!(matches!(&zone.records[3].data, RecordData::UNKNOWN(data) if data.is_empty()));
+
+        assert!(parse_zone("www IN TYPE65 \\# 3 0001\n").is_err());
+        assert!(parse_zone("www IN A \\# 3 0A0000\n").is_err());
+        assert!(parse_zone("www IN TYPE65 0001\n").is_err());
+    }
+
+    #[test]
+    fn character_strings() {
+        let long = "a".repeat(300);
+        let zone = parse_zone(&format!(r#"@ IN TXT "v=spf1 include:_spf.example.com ~all"
+@ IN TXT "first; string" second "with \"quotes\" and \059" ""
+@ IN TXT "{}"
+@ IN HINFO "Intel Xeon" Linux
+"#, long)).unwrap();
+
+        assert!(matches!(&zone.records[1].data, RecordData::TXT(strings)
+            if strings == &vec![b"v=spf1 include:_spf.example.com ~all".to_vec()]));
+        assert!(matches!(&zone.records[2].data, RecordData::TXT(strings)
+            if strings == &vec![b"first; string".to_vec(), b"second".to_vec(), b"with \"quotes\" and ;".to_vec(), vec![]]));
+        assert!(matches!(&zone.records[3].data, RecordData::TXT(strings)
+            if strings.len() == 2 && strings[0].len() == 255 && strings[1].len() == 45));
+        assert!(matches!(&zone.records[4].data, RecordData::HINFO { cpu, os }
+            if cpu == b"Intel Xeon" && os == b"Linux"));
+
+        assert!(parse_zone("@ IN TXT \"unterminated\n").is_err());
+        assert!(parse_zone(&format!("@ IN HINFO {} Linux\n", long)).is_err());
+    }
+
+    #[test]
+    fn srv_records() {
+        let zone = parse_zone("_sip._tcp IN SRV 10 60 5060 sip
+_ldap._tcp.example.com. IN SRV 0 0 389 ldap.example.net.
+_http._tcp IN SRV 0 0 0 .
+").unwrap();
+
+        assert!(matches!(&zone.records[1].data, RecordData::SRV { priority: 10, weight: 60, port: 5060, host }
+            if host == "sip.example.com"));
+        assert_eq!(zone.records[1].domain, "_sip._tcp.example.com");
+        assert!(matches!(&zone.records[2].data, RecordData::SRV { port: 389, host, .. } if host == "ldap.example.net"));
+        assert!(matches!(&zone.records[3].data, RecordData::SRV { host, .. } if host.is_empty()));
+
+        assert!(parse_zone("_sip._tcp IN SRV 10 60 70000 sip\n").is_err());
+    }
+
+    #[test]
+    fn grammar() {
+        let zone = parse("$ORIGIN example.com.
+$TTL 1h
+@ SOA ns1 admin\\.hostmaster ( 2024010101 ; serial
+        2h 30m 2w 5m )
+  IN 300 NS ns1 ; ttl and class in either order
+ns1 A 10.0.0.1
+    1d IN A 10.0.0.2
+mail 600 MX ( 10
+    mx.example.net. )
+a\\.b.c\\065 TXT ( \"one\"
+    \"two ; not a comment\" )
+".as_bytes().to_vec()).unwrap();
+
+        let records: Vec<(&str, u32, &RecordData)> = zone.records.iter().map(|record| (record.domain.as_str(), record.ttl, &record.data)).collect();
+        assert!(matches!(records[0], ("example.com", 3600, RecordData::SOA { rname, serial: 2024010101, refresh: 7200, retry: 1800, expire: 1209600, minimum: 300, .. })
+            if rname == "admin\\.hostmaster.example.com"));
+        assert!(matches!(records[1], ("example.com", 300, RecordData::NS(ns)) if ns == "ns1.example.com"));
+        assert_eq!((records[2].0, records[2].1), ("ns1.example.com", 3600));
+        assert_eq!((records[3].0, records[3].1), ("ns1.example.com", 86400));
+        assert!(matches!(records[4], ("mail.example.com", 600, RecordData::MX { preference: 10, exchange }) if exchange == "mx.example.net"));
+        assert!(matches!(records[5], ("a\\.b.cA.example.com", 3600, RecordData::TXT(strings))
+            if strings == &vec![b"one".to_vec(), b"two ; not a comment".to_vec()]));
+        assert!(zone.records.iter().all(|record| record.rclass == QueryClass::IN));
+
+        // without $TTL a record takes the ttl of the one before it
+        let zone = parse("$ORIGIN example.com.
+@ SOA ns1 admin 1 2 3 4 5
+www 120 A 10.0.0.1
+ftp A 10.0.0.2
+".as_bytes().to_vec()).unwrap();
+        assert_eq!(zone.records.iter().map(|record| record.ttl).collect::<Vec<u32>>(), vec![5, 120, 120]);
+
+        assert!(parse_zone("www IN A ( 10.0.0.1\n").is_err());
+        assert!(parse_zone("www IN A 10.0.0.1 )\n").is_err());
+        assert!(parse_zone("www IN A 10.0.0.1 10.0.0.2\n").is_err());
+        assert!(parse_zone("www IN 1x A 10.0.0.1\n").is_err());
+        let err = parse_zone("@ CH 60 TXT test\n").unwrap_err().to_string();
+        assert!(err.starts_with("9:3: invalid class: class CH"), "{}", err);
+    }
+
+    #[test]
+    fn includes() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("parts")).unwrap();
+        fs::write(dir.path().join("parts/hosts"), "www IN A 10.0.0.1\n$INCLUDE mail\n").unwrap();
+        fs::write(dir.path().join("parts/mail"), "@ IN MX 10 mx\n").unwrap();
+        fs::write(dir.path().join("loop"), "$INCLUDE loop\n").unwrap();
+        fs::write(dir.path().join("example.zone"), [SOA, "$INCLUDE parts/hosts\n$INCLUDE parts/hosts sub\nftp IN A 10.0.0.2\n"].concat()).unwrap();
+
+        let zone = Zone::parse_file(dir.path().join("example.zone")).unwrap();
+        let names: Vec<&str> = zone.records.iter().map(|record| record.domain.as_str()).collect();
+        assert_eq!(names, vec!["example.com", "www.example.com", "example.com", "www.sub.example.com", "sub.example.com", "ftp.example.com"]);
+        assert!(matches!(&zone.records[4].data, RecordData::MX { exchange, .. } if exchange == "mx.sub.example.com"));
+        assert_eq!(zone.includes.len(), 4);
+
+        // the included files aren't zones of their own, the file including itself is
+        fs::remove_file(dir.path().join("loop")).unwrap();
+        fs::rename(dir.path().join("parts/mail"), dir.path().join("mail")).unwrap();
+        fs::write(dir.path().join("parts/hosts"), "www IN A 10.0.0.1\n$INCLUDE ../mail\n").unwrap();
+        assert_eq!(Zone::parse_directory(dir.path(), true).unwrap().len(), 1);
+
+        fs::write(dir.path().join("loop"), "$INCLUDE loop\n").unwrap();
+        let err = Zone::parse_directory(dir.path(), true).unwrap_err().to_string();
+        assert!(err.contains("nested more than 8 files deep"), "{}", err);
+    }
+
+    #[test]
+    fn generate() {
+        let zone = parse_zone("$GENERATE 1-3 host-$ IN A 10.0.0.$
+$GENERATE 10-30/10 $.2 IN PTR host-${-9,3}.example.net.
+$GENERATE 254-255 ${0,4,x} IN TXT \"$$ ${0,0,X} \\$\"
+$GENERATE 18-18 ${0,7,n}ip6 IN CNAME ${0,3,n}ip6
+").unwrap();
+
+        let records: Vec<(&str, &RecordData)> = zone.records[1..].iter().map(|record| (record.domain.as_str(), &record.data)).collect();
+        assert_eq!(records.len(), 9);
+        assert!(matches!(records[2], ("host-3.example.com", RecordData::A(addr)) if *addr == Ipv4Addr::new(10, 0, 0, 3)));
+        assert!(matches!(records[4], ("20.2.example.com", RecordData::PTR(ptr)) if ptr == "host-011.example.net"));
+        assert!(matches!(records[7], ("00ff.example.com", RecordData::TXT(strings)) if strings == &vec![b"$ FF $".to_vec()]));
+        assert!(matches!(records[8], ("2.1.0.0ip6.example.com", RecordData::CNAME(name)) if name == "2.1ip6.example.com"));
+
+        assert!(parse_zone("$GENERATE 3-1 host-$ IN A 10.0.0.$\n").is_err());
+        assert!(parse_zone("$GENERATE 1-3 host-${0,2,q} IN A 10.0.0.$\n").is_err());
+        assert!(parse_zone("$GENERATE 1-3 host-${-2} IN A 10.0.0.$\n").is_err());
+
+        let err = parse_zone("$GENERATE 0-4294967295 host-$ A 10.0.0.1\n").unwrap_err().to_string();
+        assert!(err.contains("adds more than 65536 records"), "{}", err);
+    }
+
+    #[test]
+    fn diagnostics() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("hosts"), "www IN A 10.0.0.1\nftp IN AAAA 10.0.0.2\n").unwrap();
+        fs::write(dir.path().join("example.zone"), [SOA, "$INCLUDE hosts
+mail IN MX ten mx
+txt IN TXT \"unterminated
+  IN A 10.0.0.3 )
+ns IN NS ns1
+bad IN BOGUS x
+"].concat()).unwrap();
+
+        // every error of the file and of the files it includes is found in one pass
+        let errors = Zone::parse_file(dir.path().join("example.zone")).unwrap_err().0;
+        let found: Vec<(String, u16, u16, ParserErrorKind)> = errors.iter().map(|error| {
+            (error.file.as_ref().unwrap().file_name().unwrap().to_string_lossy().to_string(), error.line, error.column, error.kind)
+        }).collect();
+
+        assert_eq!(found, vec![
+            ("example.zone".to_string(), 10, 12, ParserErrorKind::InvalidNumber),
+            ("example.zone".to_string(), 11, 12, ParserErrorKind::UnterminatedString),
+            ("example.zone".to_string(), 12, 17, ParserErrorKind::UnbalancedParentheses),
+            ("example.zone".to_string(), 14, 8, ParserErrorKind::UnknownType),
+            ("hosts".to_string(), 2, 13, ParserErrorKind::InvalidAddress)
+        ]);
+
+        let message = errors[0].to_string();
+        assert!(message.ends_with("example.zone:10:12: invalid number: expected a number between 0 and 65535, found ten
+    mail IN MX ten mx
+               ^"), "{}", message);
+
+        // a missing field points past the end of the line
+        let err = parse_zone("www IN MX 10\n").unwrap_err().to_string();
+        assert!(err.starts_with("9:13: unexpected end of line: expected the exchange"), "{}", err);
+    }
 }
@@ -1,5 +1,5 @@
+use crate::zone::error::{ParserError, ParserErrorKind};
 use crate::zone::token::{Token, TokenType};
-use anyhow::Result;
 
 #[derive(Default)]
 pub struct Scanner {
@@ -7,90 +7,198 @@ pub struct Scanner {
 }
 
 impl Scanner {
-    pub fn new(buf: Vec<u8>) -> Result<Self> {
-        let source = String::from_utf8(buf)?;
+    pub fn new(buf: Vec<u8>) -> Result<Self, ParserError> {
+        let source = match String::from_utf8(buf) {
+            Ok(source) => source,
+            Err(e) => {
+                // point at the first byte that isn't utf-8
+                let valid = &e.as_bytes()[..e.utf8_error().valid_up_to()];
+                let line = valid.iter().filter(|byte| **byte == b'\n').count() + 1;
+                let column = valid.iter().rev().take_while(|byte| **byte != b'\n').count() + 1;
+
+                return Err(ParserError::new(line as u16, column as u16, ParserErrorKind::InvalidEncoding, "the file isn't utf-8"));
+            }
+        };
 
         Ok(Self {
             lines: source.lines().map(|val| val.to_string()).collect::<Vec<String>>(),
         })
     }
-    
-    pub fn scan(&self) -> Result<Vec<Token>> {
+
+    // splits the source into entries, each one ended by an EOL token. an entry is a single
+    // line unless parentheses carry it over the following ones (RFC 1035 5.1). an entry with
+    // an error is left out and the error added to `errors`, so the rest can still be read.
+    pub fn scan(&self, errors: &mut Vec<ParserError>) -> Vec<Token> {
         let mut res = Vec::new();
-        
+        let mut entry: Vec<Token> = Vec::new();
+        // the parenthesis that's still open
+        let mut open: Option<Token> = None;
+        let mut broken = false;
+
         for line in 0..self.lines.len()  {
-            res.append(&mut Self::scan_line(self.lines[line].as_str(), line as u16));
+            let line_num = line as u16 + 1;
+
+            let tokens = match Self::scan_line(self.lines[line].as_str(), line_num) {
+                Ok(tokens) => tokens,
+                Err(e) => {
+                    errors.push(e);
+                    broken = true;
+
+                    vec![]
+                }
+            };
+
+            for token in tokens {
+                match token.token_type {
+                    TokenType::LeftParenthesis => {
+                        if let Some(open) = &open {
+                            errors.push(ParserError::at(&token, ParserErrorKind::UnbalancedParentheses,
+                                &format!("nested parentheses, the one at line {} isn't closed", open.line)));
+                            broken = true;
+
+                            continue;
+                        }
+
+                        open = Some(token);
+                    },
+                    TokenType::RightParenthesis => {
+                        if open.take().is_none() {
+                            errors.push(ParserError::at(&token, ParserErrorKind::UnbalancedParentheses, "unexpected )"));
+                            broken = true;
+                        }
+                    },
+                    // the leading blank of a line that continues an entry means nothing
+                    TokenType::WhiteSpace if open.is_some() => {},
+                    _ => entry.push(token)
+                }
+            }
+
+            if open.is_some() {
+                continue;
+            }
+
+            // lines that are blank or only hold a comment aren't entries
+            if !broken && entry.iter().any(|token| token.token_type != TokenType::WhiteSpace) {
+                res.append(&mut entry);
+                res.push(Token::new("", TokenType::EOL, line_num, 0));
+            }
+
+            entry.clear();
+            broken = false;
         }
-        
-        Ok(res)
+
+        if let Some(open) = open {
+            errors.push(ParserError::at(&open, ParserErrorKind::UnbalancedParentheses, "the parenthesis is never closed"));
+        }
+
+        res
+    }
+
+    // the source line with the given number, starting at 1
+    pub fn line(&self, line: u16) -> Option<&str> {
+        self.lines.get((line as usize).checked_sub(1)?).map(|line| line.as_str())
     }
-    
-    fn scan_line(line: &str, line_num: u16) -> Vec<Token> {
+
+    fn scan_line(line: &str, line_num: u16) -> Result<Vec<Token>, ParserError> {
         let mut res = Vec::new();
         let chars = line.chars();
 
         let mut temp_str = String::new();
+        // where the string being read starts
+        let mut start = 0;
         let mut chars = chars.collect::<Vec<char>>().into_iter().enumerate();
-        
+
         while let Some((pos, ch)) = chars.next() {
             if ch.is_whitespace() {
                 if pos == 0 && temp_str.is_empty() {
-                    res.push(Token::new(temp_str.as_str(), TokenType::WhiteSpace, line_num));
+                    res.push(Token::new(temp_str.as_str(), TokenType::WhiteSpace, line_num, 1));
                 }
 
-                if !temp_str.is_empty() {
-                    res.push(Token::new(temp_str.as_str(), TokenType::String, line_num));
-                    temp_str.clear();
-                }
-                
+                Self::push_string(&mut res, &mut temp_str, line_num, start);
+
                 continue;
             }
 
+            if temp_str.is_empty() {
+                start = pos;
+            }
+
             match ch {
-                '$' => {
-                    if !temp_str.is_empty() {
-                        res.push(Token::new(temp_str.as_str(), TokenType::String, line_num));
-                        temp_str.clear();
+                // escapes are kept as they are, the parser decodes them depending on where they appear
+                '\\' => {
+                    temp_str.push(ch);
+                    if let Some((_, next)) = chars.next() {
+                        temp_str.push(next);
                     }
-                    res.push(Token::new(&ch.to_string(), TokenType::DolorSign, line_num));
-                },
-                ';' => {
-                    return res
                 },
-                '@' => {
-                    if !temp_str.is_empty() {
-                        res.push(Token::new(temp_str.as_str(), TokenType::String, line_num));
-                        temp_str.clear();
+                '"' => {
+                    Self::push_string(&mut res, &mut temp_str, line_num, start);
+
+                    let mut closed = false;
+                    while let Some((_, ch)) = chars.next() {
+                        match ch {
+                            '"' => {
+                                closed = true;
+                                break;
+                            },
+                            '\\' => {
+                                temp_str.push(ch);
+                                if let Some((_, next)) = chars.next() {
+                                    temp_str.push(next);
+                                }
+                            },
+                            _ => temp_str.push(ch)
+                        }
+                    }
+
+                    if !closed {
+                        return Err(ParserError::new(line_num, pos as u16 + 1, ParserErrorKind::UnterminatedString, "the quoted string is never closed"));
                     }
-                    res.push(Token::new(&ch.to_string(), TokenType::AtSign, line_num));
+
+                    res.push(Token::new(temp_str.as_str(), TokenType::QuotedString, line_num, pos as u16 + 1));
+                    temp_str.clear();
+                },
+                // directives start at the beginning of a line, a $ anywhere else is part of a string
+                '$' if pos == 0 => {
+                    res.push(Token::new(&ch.to_string(), TokenType::DolorSign, line_num, 1));
+                },
+                ';' => {
+                    break;
                 },
                 '(' => {
-                    if !temp_str.is_empty() {
-                        res.push(Token::new(temp_str.as_str(), TokenType::String, line_num));
-                        temp_str.clear();
-                    }
-                    res.push(Token::new(&ch.to_string(), TokenType::LeftParenthesis, line_num));
+                    Self::push_string(&mut res, &mut temp_str, line_num, start);
+                    res.push(Token::new(&ch.to_string(), TokenType::LeftParenthesis, line_num, pos as u16 + 1));
                 },
                 ')' => {
-                    if !temp_str.is_empty() {
-                        res.push(Token::new(temp_str.as_str(), TokenType::String, line_num));
-                        temp_str.clear();
-                    }
-                    res.push(Token::new(&ch.to_string(), TokenType::RightParenthesis, line_num));
+                    Self::push_string(&mut res, &mut temp_str, line_num, start);
+                    res.push(Token::new(&ch.to_string(), TokenType::RightParenthesis, line_num, pos as u16 + 1));
                 },
                 _ => {
                     temp_str.push(ch);
                 }
             }
         }
-        
-        if !temp_str.is_empty() {
-            res.push(Token::new(temp_str.as_str(), TokenType::String, line_num));
+
+        Self::push_string(&mut res, &mut temp_str, line_num, start);
+
+        Ok(res)
+    }
+
+    // a lone @ stands for the origin, anywhere else it's part of a name
+    fn push_string(res: &mut Vec<Token>, temp_str: &mut String, line_num: u16, start: usize) {
+        if temp_str.is_empty() {
+            return;
         }
-        
-        res
+
+        let token_type = match temp_str.as_str() {
+            "@" => TokenType::AtSign,
+            _ => TokenType::String
+        };
+
+        res.push(Token::new(temp_str.as_str(), token_type, line_num, start as u16 + 1));
+        temp_str.clear();
     }
-    
+
     pub(crate) fn replace(&mut self, s: &String) {
         self.lines = s.lines().map(|val| val.to_string()).collect::<Vec<String>>();
     }
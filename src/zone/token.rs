@@ -36,6 +36,7 @@ pub enum TokenType {
     RightParenthesis,
     AtSign,
     String,
+    QuotedString,
     WhiteSpace,
     EOL // End Of Line
 }
@@ -45,14 +46,17 @@ pub struct Token {
     pub token_type: TokenType,
     pub lexeme: String,
     pub line: u16,
+    // where the token starts on its line, 0 for the end of an entry
+    pub column: u16
 }
 
 impl Token {
-    pub fn new(lexeme: &str, token_type: TokenType, line: u16) -> Self {
+    pub fn new(lexeme: &str, token_type: TokenType, line: u16, column: u16) -> Self {
         Self {
             token_type,
             lexeme: lexeme.to_string(),
-            line
+            line,
+            column
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,537 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use anyhow::{bail, Result};
+use crate::query_type::QueryType;
+use crate::record::{Record, RecordData};
+use crate::transfer::{serial_gt, soa_serial};
+use crate::zone::journal::{Diff, Journal};
+use crate::zone::parser::Zone;
+
+// the records of a single owner name, grouped by type. empty non-terminals are
+// nodes without any records.
+#[derive(Default, Debug)]
+pub struct ZoneNode {
+    pub rrsets: HashMap<QueryType, Vec<Record>>
+}
+
+#[derive(Debug)]
+pub enum Lookup {
+    Answer(Vec<Record>),
+    // the name is an alias, the answer has to be looked up at its target
+    CName(Record),
+    // the name is below a zone cut, the client has to ask the child zone's servers
+    Referral {
+        ns: Vec<Record>,
+        glue: Vec<Record>
+    },
+    // the name exists but there are no records of the requested type
+    NoData,
+    NxDomain,
+}
+
+#[derive(Debug)]
+pub struct AuthZone {
+    pub origin: String,
+    // where the zone is kept, if it's one of our own
+    pub file: Option<PathBuf>,
+    // other files the zone's records were read from
+    pub includes: Vec<PathBuf>,
+    nodes: BTreeMap<String, ZoneNode>,
+    journal: Journal
+}
+
+impl AuthZone {
+    pub fn from(zone: Zone) -> Result<Self> {
+        let origin = zone.origin.to_lowercase();
+
+        let mut res = Self {
+            origin: origin.clone(),
+            file: zone.file,
+            includes: zone.includes,
+            nodes: BTreeMap::new(),
+            journal: Journal::default()
+        };
+        res.nodes.insert(origin.clone(), ZoneNode::default());
+
+        for record in zone.records {
+            let name = record.domain.to_lowercase();
+            if !is_subdomain(&name, &origin) {
+                bail!("{} is out of zone {}", record.domain, zone.origin);
+            }
+
+            res.insert(name, record);
+        }
+
+        match res.get(&origin, QueryType::SOA) {
+            Some(soa) if soa.len() == 1 => {},
+            Some(_) => bail!("zone {} has more than one SOA record", zone.origin),
+            None => bail!("zone {} has no SOA record at its apex", zone.origin)
+        }
+
+        Ok(res)
+    }
+
+    fn insert(&mut self, name: String, record: Record) {
+        // create the empty non-terminals between the apex and the new name
+        let mut parent = name.as_str();
+        while parent != self.origin {
+            parent = match parent.split_once('.') {
+                Some((_, parent)) => parent,
+                None => ""
+            };
+
+            self.nodes.entry(parent.to_string()).or_default();
+        }
+
+        self.nodes.entry(name).or_default().rrsets.entry(record.rtype).or_default().push(record);
+    }
+
+    pub fn get(&self, name: &str, rtype: QueryType) -> Option<&Vec<Record>> {
+        self.nodes.get(name)?.rrsets.get(&rtype)
+    }
+
+    // whether the zone has a record with the same owner, type, ttl and data
+    pub fn contains(&self, record: &Record) -> bool {
+        match self.get(&record.domain.to_lowercase(), record.rtype) {
+            Some(records) => records.iter().any(|other| {
+                other.ttl == record.ttl && other.data == record.data
+            }),
+            None => false
+        }
+    }
+
+    pub fn serial(&self) -> u32 {
+        soa_serial(self.soa()).expect("zones always have an SOA record")
+    }
+
+    pub fn journal(&self) -> &Journal {
+        &self.journal
+    }
+
+    // takes over the journal of the previous version of the zone and adds the changes
+    // made since. a serial that went backwards makes the old changes meaningless.
+    pub fn follow(&mut self, old: &AuthZone) {
+        if serial_gt(self.serial(), old.serial()) {
+            self.journal = old.journal.clone();
+            self.journal.push(Diff::between(old, self));
+        } else if self.serial() == old.serial() {
+            self.journal = old.journal.clone();
+        }
+    }
+
+    // every record of the zone, ordered by owner name and type
+    pub fn records(&self) -> impl Iterator<Item = &Record> {
+        self.nodes.values().flat_map(|node| {
+            let mut rrsets: Vec<&Vec<Record>> = node.rrsets.values().collect();
+            rrsets.sort_by_key(|rrset| rrset[0].rtype.to_num());
+
+            rrsets.into_iter().flatten()
+        })
+    }
+
+    pub fn soa(&self) -> &Record {
+        &self.get(&self.origin, QueryType::SOA).expect("zones always have an SOA record")[0]
+    }
+
+    // the SOA record to put in the authority section of negative answers, its ttl is
+    // the smaller of its own ttl and the minimum field (RFC 2308 3).
+    pub fn negative_soa(&self) -> Record {
+        let mut soa = self.soa().clone();
+        if let RecordData::SOA { minimum, .. } = soa.data {
+            soa.ttl = soa.ttl.min(minimum);
+        }
+
+        soa
+    }
+
+    pub fn lookup(&self, name: &str, qtype: QueryType) -> Lookup {
+        let name = name.to_lowercase();
+
+        // walk down from the apex looking for a zone cut above (or at) the name
+        for node_name in self.path_to(&name) {
+            if node_name == self.origin {
+                continue;
+            }
+
+            let node = match self.nodes.get(&node_name) {
+                Some(node) => node,
+                None => break
+            };
+
+            if let Some(ns) = node.rrsets.get(&QueryType::NS) {
+                return Lookup::Referral {
+                    ns: ns.clone(),
+                    glue: self.glue(ns)
+                };
+            }
+        }
+
+        match self.nodes.get(&name) {
+            Some(node) => Self::lookup_node(node, qtype, None),
+            None => self.lookup_wildcard(&name, qtype)
+        }
+    }
+
+    fn lookup_node(node: &ZoneNode, qtype: QueryType, owner: Option<&str>) -> Lookup {
+        // records synthesized from a wildcard are owned by the name that was asked for
+        let synthesize = |records: &Vec<Record>| -> Vec<Record> {
+            records.iter().map(|record| {
+                let mut record = record.clone();
+                if let Some(owner) = owner {
+                    record.domain = owner.to_string();
+                }
+
+                record
+            }).collect()
+        };
+
+        if qtype == QueryType::ASTERISK {
+            let records: Vec<Record> = node.rrsets.values().flat_map(synthesize).collect();
+            if records.is_empty() {
+                return Lookup::NoData;
+            }
+
+            return Lookup::Answer(records);
+        }
+
+        if let Some(records) = node.rrsets.get(&qtype) {
+            return Lookup::Answer(synthesize(records));
+        }
+
+        if let Some(cname) = node.rrsets.get(&QueryType::CNAME) {
+            return Lookup::CName(synthesize(cname).remove(0));
+        }
+
+        Lookup::NoData
+    }
+
+    // a name that doesn't exist is answered from the wildcard right below its closest
+    // encloser, the deepest existing ancestor of the name (RFC 4592 3.3.1). names that exist,
+    // empty non-terminals included, are never synthesized.
+    fn lookup_wildcard(&self, name: &str, qtype: QueryType) -> Lookup {
+        let closest_encloser = self.path_to(name).into_iter().rev().find(|ancestor| {
+            self.nodes.contains_key(ancestor)
+        });
+
+        let source = match closest_encloser {
+            Some(closest_encloser) => to_wildcard(&closest_encloser),
+            None => return Lookup::NxDomain
+        };
+
+        match self.nodes.get(&source) {
+            Some(node) => Self::lookup_node(node, qtype, Some(name)),
+            None => Lookup::NxDomain
+        }
+    }
+
+    // the names between the apex and the given name, starting with the apex.
+    fn path_to(&self, name: &str) -> Vec<String> {
+        let mut res = vec![name.to_string()];
+
+        let mut parent = name;
+        while parent != self.origin {
+            parent = match parent.split_once('.') {
+                Some((_, parent)) => parent,
+                None => break
+            };
+
+            res.push(parent.to_string());
+        }
+
+        res.reverse();
+
+        res
+    }
+
+    // addresses of the name servers of a delegation that live inside this zone.
+    fn glue(&self, ns: &[Record]) -> Vec<Record> {
+        let mut res = Vec::new();
+
+        for record in ns {
+            if let RecordData::NS(host) = &record.data {
+                let host = host.to_lowercase();
+                if !is_subdomain(&host, &self.origin) {
+                    continue;
+                }
+
+                for rtype in [QueryType::A, QueryType::AAAA] {
+                    if let Some(addrs) = self.get(&host, rtype) {
+                        res.extend(addrs.iter().cloned());
+                    }
+                }
+            }
+        }
+
+        res
+    }
+}
+
+// all of the zones served by an authoritative server, keyed by their origin. zones are
+// shared between copies of the tree, so changing one zone doesn't copy the others.
+#[derive(Clone, Default, Debug)]
+pub struct ZoneTree {
+    zones: HashMap<String, Arc<AuthZone>>
+}
+
+impl ZoneTree {
+    pub fn from(zones: Vec<Zone>) -> Result<Self> {
+        let mut res = Self::default();
+
+        for zone in zones {
+            let zone = AuthZone::from(zone)?;
+            if res.zones.contains_key(&zone.origin) {
+                bail!("zone {} is defined more than once", zone.origin);
+            }
+
+            res.insert(zone);
+        }
+
+        Ok(res)
+    }
+
+    // adds a zone or replaces the one with the same origin, keeping track of the
+    // changes between the two versions
+    pub fn insert(&mut self, mut zone: AuthZone) {
+        if let Some(old) = self.zones.get(&zone.origin) {
+            zone.follow(old);
+        }
+
+        self.zones.insert(zone.origin.clone(), Arc::new(zone));
+    }
+
+    // replaces all of the zones with the ones of another tree, zones that were already
+    // served keep their journals. the zones in `keep` stay as they are.
+    pub fn replace(&mut self, mut tree: ZoneTree, keep: &[String]) {
+        for origin in keep {
+            match self.zones.get(origin) {
+                Some(zone) => tree.zones.insert(origin.clone(), zone.clone()),
+                None => tree.zones.remove(origin)
+            };
+        }
+
+        for (origin, zone) in tree.zones.iter_mut() {
+            if let (Some(old), Some(zone)) = (self.zones.get(origin), Arc::get_mut(zone)) {
+                zone.follow(old);
+            }
+        }
+
+        *self = tree;
+    }
+
+    pub fn remove(&mut self, origin: &str) -> Option<Arc<AuthZone>> {
+        self.zones.remove(&origin.to_lowercase())
+    }
+
+    pub fn contains(&self, origin: &str) -> bool {
+        self.zones.contains_key(&origin.to_lowercase())
+    }
+
+    pub fn origins(&self) -> Vec<String> {
+        self.zones.keys().cloned().collect()
+    }
+
+    pub fn get(&self, origin: &str) -> Option<&AuthZone> {
+        self.zones.get(&origin.to_lowercase()).map(|zone| zone.as_ref())
+    }
+
+    // finds the zone closest to the given name, a name is served by the deepest
+    // zone whose origin is a suffix of it.
+    pub fn find(&self, name: &str) -> Option<&AuthZone> {
+        let name = name.to_lowercase();
+
+        let mut current = name.as_str();
+        loop {
+            if let Some(zone) = self.zones.get(current) {
+                return Some(zone.as_ref());
+            }
+
+            if current.is_empty() {
+                return None;
+            }
+
+            current = match current.split_once('.') {
+                Some((_, parent)) => parent,
+                None => ""
+            };
+        }
+    }
+}
+
+// the zone tree being served. it's replaced as a whole whenever zones change, so queries
+// that are being answered keep a consistent view of the zones.
+#[derive(Default, Debug)]
+pub struct SharedTree {
+    tree: RwLock<Arc<ZoneTree>>,
+    // told the origin of every zone that's added or gets a new serial
+    subscribers: Mutex<Vec<UnboundedSender<String>>>
+}
+
+impl SharedTree {
+    pub fn load(&self) -> Arc<ZoneTree> {
+        self.tree.read().unwrap().clone()
+    }
+
+    // changes a copy of the tree and swaps it in if the change succeeds, updates
+    // are serialized so none of them is lost.
+    pub fn update<F: FnOnce(&mut ZoneTree) -> Result<()>>(&self, f: F) -> Result<()> {
+        let mut tree = self.tree.write().unwrap();
+
+        let mut updated = ZoneTree::clone(&tree);
+        f(&mut updated)?;
+
+        let changed: Vec<String> = updated.zones.iter().filter(|(origin, zone)| {
+            match tree.zones.get(*origin) {
+                Some(old) => !Arc::ptr_eq(old, zone) && old.serial() != zone.serial(),
+                None => true
+            }
+        }).map(|(origin, _)| origin.clone()).collect();
+
+        *tree = Arc::new(updated);
+        drop(tree);
+
+        if !changed.is_empty() {
+            self.subscribers.lock().unwrap().retain(|subscriber| {
+                changed.iter().all(|origin| subscriber.send(origin.clone()).is_ok())
+            });
+        }
+
+        Ok(())
+    }
+
+    pub fn subscribe(&self) -> UnboundedReceiver<String> {
+        let (tx, rx) = unbounded_channel();
+        self.subscribers.lock().unwrap().push(tx);
+
+        rx
+    }
+}
+
+fn to_wildcard(name: &str) -> String {
+    match name {
+        "" => "*".to_string(),
+        _ => format!("*.{}", name)
+    }
+}
+
+pub fn is_subdomain(name: &str, parent: &str) -> bool {
+    parent.is_empty() || name == parent || name.ends_with(&format!(".{}", parent))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::zone::parser::parse;
+
+    fn zone() -> AuthZone {
+        let src = "$ORIGIN example.com.
+$TTL 3600
+@ IN SOA ns1.example.com. admin.example.com. (
+    1
+    7200
+    3600
+    1209600
+    300 )
+@ IN NS ns1
+ns1 IN A 10.0.0.1
+www IN A 10.0.0.2
+www IN A 10.0.0.3
+mail.internal IN A 10.0.0.4
+alias IN CNAME www
+sub IN NS ns1.sub
+sub IN NS ns.example.net.
+ns1.sub IN A 10.0.1.1
+";
+
+        AuthZone::from(parse(src.as_bytes().to_vec()).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn lookups() {
+        let zone = zone();
+
+        assert!(matches!(zone.lookup("WWW.example.com", QueryType::A), Lookup::Answer(records) if records.len() == 2));
+        assert!(matches!(zone.lookup("www.example.com", QueryType::MX), Lookup::NoData));
+        assert!(matches!(zone.lookup("ftp.example.com", QueryType::A), Lookup::NxDomain));
+        assert!(matches!(zone.lookup("alias.example.com", QueryType::A), Lookup::CName(_)));
+        assert!(matches!(zone.lookup("alias.example.com", QueryType::CNAME), Lookup::Answer(_)));
+        assert!(matches!(zone.lookup("example.com", QueryType::NS), Lookup::Answer(_)));
+
+        // empty non-terminal
+        assert!(matches!(zone.lookup("internal.example.com", QueryType::A), Lookup::NoData));
+        assert!(matches!(zone.lookup("x.internal.example.com", QueryType::A), Lookup::NxDomain));
+    }
+
+    #[test]
+    fn referrals() {
+        let zone = zone();
+
+        for name in ["sub.example.com", "www.sub.example.com", "ns1.sub.example.com"] {
+            match zone.lookup(name, QueryType::A) {
+                Lookup::Referral { ns, glue } => {
+                    assert_eq!(ns.len(), 2);
+                    assert_eq!(glue.len(), 1);
+                    assert_eq!(glue[0].domain, "ns1.sub.example.com");
+                },
+                res => panic!("expected a referral for {}, got {:?}", name, res)
+            }
+        }
+    }
+
+    #[test]
+    fn wildcards() {
+        let src = "$ORIGIN example.com.
+@ 3600 IN SOA ns1.example.com. admin.example.com. (
+    1
+    7200
+    3600
+    1209600
+    300 )
+* 3600 IN TXT root-wildcard
+*.apps 3600 IN A 10.0.0.1
+*.apps 3600 IN MX 10 mail
+*.cname 3600 IN CNAME target
+existing.apps 3600 IN TXT exists
+deep.empty.apps 3600 IN A 10.0.0.2
+foo* 3600 IN TXT literal
+a.*.apps 3600 IN A 10.0.0.3
+";
+        let zone = AuthZone::from(parse(src.as_bytes().to_vec()).unwrap()).unwrap();
+
+        match zone.lookup("tenant.apps.example.com", QueryType::A) {
+            Lookup::Answer(records) => {
+                assert_eq!(records.len(), 1);
+                assert_eq!(records[0].domain, "tenant.apps.example.com");
+            },
+            res => panic!("expected a synthesized answer, got {:?}", res)
+        }
+
+        assert!(matches!(zone.lookup("a.b.apps.example.com", QueryType::A), Lookup::Answer(_)));
+        assert!(matches!(zone.lookup("tenant.apps.example.com", QueryType::AAAA), Lookup::NoData));
+        assert!(matches!(zone.lookup("x.cname.example.com", QueryType::A), Lookup::CName(record)
+            if record.domain == "x.cname.example.com"));
+        assert!(matches!(zone.lookup("foo.example.com", QueryType::TXT), Lookup::Answer(_)));
+
+        // existing names and empty non-terminals block the synthesis
+        assert!(matches!(zone.lookup("existing.apps.example.com", QueryType::A), Lookup::NoData));
+        assert!(matches!(zone.lookup("empty.apps.example.com", QueryType::A), Lookup::NoData));
+        // the closest encloser of this name is empty.apps, which has no wildcard
+        assert!(matches!(zone.lookup("other.empty.apps.example.com", QueryType::A), Lookup::NxDomain));
+
+        // a * that isn't the whole leftmost label is part of a literal name
+        assert!(matches!(zone.lookup("foo*.example.com", QueryType::TXT), Lookup::Answer(records) if records[0].domain == "foo*.example.com"));
+        assert!(matches!(zone.lookup("a.*.apps.example.com", QueryType::A), Lookup::Answer(records) if records[0].domain == "a.*.apps.example.com"));
+        assert!(matches!(zone.lookup("b.*.apps.example.com", QueryType::A), Lookup::NxDomain));
+    }
+
+    #[test]
+    fn find_zones() {
+        let tree = ZoneTree { zones: HashMap::from([("example.com".to_string(), Arc::new(zone()))]) };
+
+        assert!(tree.find("example.com").is_some());
+        assert!(tree.find("a.b.Example.com").is_some());
+        assert!(tree.find("example.net").is_none());
+        assert!(tree.find("badexample.com").is_none());
+    }
+}
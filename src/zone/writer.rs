@@ -0,0 +1,136 @@
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+use anyhow::{bail, Result};
+use crate::query_type::QueryType;
+use crate::record::{Record, RecordData};
+use crate::zone::tree::AuthZone;
+
+// writes a zone in the master file format read by the parser, it's used to keep a copy of
+// the zones we don't load from our own files. names are always written fully qualified.
+pub fn write_zone(zone: &AuthZone) -> Result<String> {
+    let mut res = String::new();
+
+    writeln!(res, "$ORIGIN {}", to_fqdn(&zone.origin))?;
+    writeln!(res, "{}", write_record(zone.soa())?)?;
+
+    for record in zone.records().filter(|record| record.rtype != QueryType::SOA) {
+        writeln!(res, "{}", write_record(record)?)?;
+    }
+
+    Ok(res)
+}
+
+// writes the zone to a temporary file first, so a crash never leaves a partial copy behind.
+pub fn save_zone(zone: &AuthZone, path: &Path) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let mut tmp = path.to_path_buf().into_os_string();
+    tmp.push(".tmp");
+
+    fs::write(&tmp, write_zone(zone)?)?;
+    fs::rename(&tmp, path)?;
+
+    Ok(())
+}
+
+pub fn write_record(record: &Record) -> Result<String> {
+    let data = match &record.data {
+        RecordData::A(addr) => addr.to_string(),
+        RecordData::AAAA(addr) => addr.to_string(),
+        RecordData::NS(host) | RecordData::CNAME(host) | RecordData::PTR(host) => to_fqdn(host),
+        RecordData::SOA { mname, rname, serial, refresh, retry, expire, minimum } => {
+            format!(
+                "{} {} ( {} {} {} {} {} )",
+                to_fqdn(mname),
+                to_fqdn(rname),
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum
+            )
+        },
+        RecordData::MX { preference, exchange } => format!("{} {}", preference, to_fqdn(exchange)),
+        RecordData::SRV { priority, weight, port, host } => {
+            format!("{} {} {} {}", priority, weight, port, to_fqdn(host))
+        },
+        RecordData::HINFO { cpu, os } => {
+            format!("{} {}", write_character_string(cpu), write_character_string(os))
+        },
+        RecordData::TXT(strings) => {
+            strings.iter().map(|string| write_character_string(string)).collect::<Vec<String>>().join(" ")
+        },
+        RecordData::UNKNOWN(data) => {
+            let hex: String = data.iter().map(|byte| format!("{:02x}", byte)).collect();
+
+            format!("\\# {} {}", data.len(), hex).trim_end().to_string()
+        },
+        RecordData::OPT(_) => bail!("OPT records can't be written to zone files"),
+        RecordData::TSIG(_) => bail!("TSIG records can't be written to zone files")
+    };
+
+    Ok(format!("{} {} IN {} {}", to_fqdn(&record.domain), record.ttl, record.rtype.to_name(), data))
+}
+
+fn to_fqdn(name: &str) -> String {
+    format!("{}.", name)
+}
+
+// quotes a <character-string>, anything that isn't printable ascii is written as a \DDD escape.
+fn write_character_string(string: &[u8]) -> String {
+    let mut res = String::from("\"");
+
+    for byte in string {
+        match byte {
+            b'"' | b'\\' => {
+                res.push('\\');
+                res.push(*byte as char);
+            },
+            0x20..=0x7E => res.push(*byte as char),
+            _ => res.push_str(&format!("\\{:03}", byte))
+        }
+    }
+
+    res.push('"');
+
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::zone::parser::parse;
+
+    #[test]
+    fn write_and_parse_zones() {
+        let src = r#"$ORIGIN example.com.
+$TTL 3600
+@ IN SOA ns1.example.com. admin.example.com. ( 1 7200 3600 1209600 300 )
+@ IN NS ns1
+@ IN MX 10 mail
+@ IN TXT "v=spf1 -all" "with \"quotes\", \\ and \009"
+ns1 IN A 10.0.0.1
+ns1 IN AAAA ::1
+www 300 IN CNAME ns1
+_sip._tcp IN SRV 0 5 5060 .
+host IN HINFO "Intel Xeon" Linux
+host IN TYPE65 \# 4 00010000
+host IN TYPE66 \# 0
+"#;
+        let zone = AuthZone::from(parse(src.as_bytes().to_vec()).unwrap()).unwrap();
+        let written = write_zone(&zone).unwrap();
+        let reparsed = AuthZone::from(parse(written.clone().into_bytes()).unwrap()).unwrap();
+
+        assert_eq!(write_zone(&reparsed).unwrap(), written);
+        assert_eq!(reparsed.records().count(), 11);
+
+        assert!(matches!(&reparsed.get("example.com", QueryType::TXT).unwrap()[0].data, RecordData::TXT(strings)
+            if strings == &vec![b"v=spf1 -all".to_vec(), b"with \"quotes\", \\ and \t".to_vec()]));
+        assert!(matches!(&reparsed.get("_sip._tcp.example.com", QueryType::SRV).unwrap()[0].data, RecordData::SRV { host, .. }
+            if host.is_empty()));
+        assert_eq!(reparsed.get("www.example.com", QueryType::CNAME).unwrap()[0].ttl, 300);
+    }
+}